@@ -0,0 +1,48 @@
+//! An audio plugin that only measures how much audio the game produces, built as a dynamic
+//! library:
+//!
+//! ```text
+//! cargo build --example audio_plugin
+//! ```
+
+use mupen64plus::plugin::audio::{AudioInfo, AudioPlugin, SystemType};
+use mupen64plus::plugin::{PluginInfo, Version};
+
+#[derive(Default)]
+struct Meter {
+    info: Option<AudioInfo>,
+    system: Option<SystemType>,
+    samples: usize,
+}
+
+impl PluginInfo for Meter {
+    const NAME: &'static str = "Audio Meter";
+    const VERSION: Version = Version::new(0, 1, 0);
+}
+
+impl AudioPlugin for Meter {
+    fn initiate_audio(&mut self, info: AudioInfo) -> bool {
+        self.info = Some(info);
+        true
+    }
+
+    fn ai_dacrate_changed(&mut self, system: SystemType) {
+        self.system = Some(system);
+        if let Some(info) = &self.info {
+            log::info!("sample rate is now {} Hz", info.frequency(system));
+        }
+    }
+
+    fn ai_len_changed(&mut self) {
+        if let Some(info) = &self.info {
+            self.samples += info.samples().len() / 2;
+        }
+    }
+
+    fn rom_closed(&mut self) {
+        log::info!("played {} samples", self.samples);
+        self.samples = 0;
+    }
+}
+
+mupen64plus::export_audio_plugin!(Meter);
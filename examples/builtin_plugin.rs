@@ -0,0 +1,60 @@
+//! A frontend with its input plugin built in, so it doesn't need `mupen64plus-input-sdl`. The
+//! executable has to export the plugin functions for the core to find them:
+//!
+//! ```text
+//! RUSTFLAGS="-C link-arg=-rdynamic" cargo run --example builtin_plugin
+//! ```
+
+use std::env::consts::DLL_EXTENSION;
+
+use mupen64plus::plugin::input::{Buttons, ControllerConfig, ControllerState, InputPlugin};
+use mupen64plus::plugin::{PluginInfo, Version};
+use mupen64plus::{Core, Plugin};
+
+mupen64plus::export_builtin_plugins!();
+
+/// Presses start every other second.
+#[derive(Default)]
+struct StartPresser {
+    polls: u32,
+}
+
+impl PluginInfo for StartPresser {
+    const NAME: &'static str = "Start Presser";
+    const VERSION: Version = Version::new(0, 1, 0);
+}
+
+impl InputPlugin for StartPresser {
+    fn initiate_controllers(&mut self) -> [ControllerConfig; 4] {
+        [ControllerConfig::PRESENT, Default::default(), Default::default(), Default::default()]
+    }
+
+    fn get_keys(&mut self, _controller: usize) -> ControllerState {
+        self.polls += 1;
+        let buttons = if self.polls / 60 % 2 == 1 { Buttons::START } else { Buttons::empty() };
+        ControllerState { buttons, ..Default::default() }
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    pretty_env_logger::init();
+
+    let path = format!("{}/libs", env!("CARGO_MANIFEST_DIR"));
+
+    let core = Core::load_from_directory(&path)
+        .or_else(|_| Core::load_from_system())?;
+    let mut mupen = core.start(Some(&path), Some(&path))?;
+
+    mupen.open_rom_path(format!("{}/examples/m64p_test_rom.v64", env!("CARGO_MANIFEST_DIR")))?;
+
+    for name in &["video-glide64mk2", "audio-sdl"] {
+        let p = format!("{}/mupen64plus-{}.{}", &path, name, DLL_EXTENSION);
+        mupen.attach_plugin(Plugin::load_from_path(p)?)?;
+    }
+    mupen.attach_plugin(Plugin::builtin_input(StartPresser::default())?)?;
+    mupen.attach_plugin(Plugin::load_from_path(format!("{}/mupen64plus-rsp-hle.{}", &path, DLL_EXTENSION))?)?;
+
+    mupen.execute()?;
+
+    Ok(())
+}
@@ -1,6 +1,4 @@
 use std::env::consts::DLL_EXTENSION;
-use std::io::prelude::*;
-use std::fs::File;
 
 use mupen64plus::{Core, Plugin};
 use mupen64plus::core::debug::Breakpoint;
@@ -18,7 +16,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut mupen = core.start(Some(&path), Some(&path))?;
 
     // Load the test ROM and give it to mupen64plus.
-    mupen.open_rom(&mut load_rom()?)?;
+    mupen.open_rom_path(format!("{}/examples/m64p_test_rom.v64", env!("CARGO_MANIFEST_DIR")))?;
 
     // Load the plugins - the order is important.
     for name in &["video-glide64mk2", "audio-sdl", "input-sdl", "rsp-hle"] {
@@ -61,11 +59,3 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
-
-// Load the test ROM file into memory.
-fn load_rom() -> Result<Vec<u8>, std::io::Error> {
-    let mut file = File::open(format!("{}/examples/m64p_test_rom.v64", env!("CARGO_MANIFEST_DIR")))?;
-    let mut buf = Vec::with_capacity(file.metadata()?.len() as usize);
-    file.read_to_end(&mut buf)?;
-    Ok(buf)
-}
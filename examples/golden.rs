@@ -0,0 +1,52 @@
+//! Runs the test ROM headless for 300 frames, pressing start now and then, and compares the
+//! result with the golden files in `examples/golden`. Set `BLESS=1` to update them. The
+//! executable has to export the built-in plugin functions for the core to find them:
+//!
+//! ```text
+//! RUSTFLAGS="-C link-arg=-rdynamic" cargo run --example golden
+//! ```
+
+use std::env::consts::DLL_EXTENSION;
+
+use mupen64plus::core::golden::GoldenTest;
+use mupen64plus::plugin::input::scripted::ScriptedInput;
+use mupen64plus::plugin::input::{Buttons, ControllerState};
+use mupen64plus::plugin::null::{NullAudio, NullVideo};
+use mupen64plus::{Core, Plugin};
+
+mupen64plus::export_builtin_plugins!();
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    pretty_env_logger::init();
+
+    let path = format!("{}/libs", env!("CARGO_MANIFEST_DIR"));
+
+    let core = Core::load_from_directory(&path)
+        .or_else(|_| Core::load_from_system())?;
+    let mut mupen = core.start(Some(&path), Some(&path))?;
+
+    mupen.open_rom_path(format!("{}/examples/m64p_test_rom.v64", env!("CARGO_MANIFEST_DIR")))?;
+
+    let input = ScriptedInput::new(1);
+    input.set_script(0, |poll| ControllerState {
+        buttons: if poll % 60 < 5 { Buttons::START } else { Buttons::empty() },
+        ..Default::default()
+    });
+
+    mupen.attach_plugin(Plugin::builtin_video(NullVideo)?)?;
+    mupen.attach_plugin(Plugin::builtin_audio(NullAudio)?)?;
+    mupen.attach_plugin(Plugin::builtin_input(input)?)?;
+    mupen.attach_plugin(Plugin::load_from_path(format!("{}/mupen64plus-rsp-hle.{}", &path, DLL_EXTENSION))?)?;
+
+    let test = GoldenTest::new(format!("{}/examples/golden", env!("CARGO_MANIFEST_DIR")), "test_rom", 300)
+        .bless(std::env::var_os("BLESS").is_some());
+    let report = mupen.run_golden_test(&test)?;
+
+    if report.passed() {
+        println!("passed{}", if report.blessed { " (golden files written)" } else { "" });
+        Ok(())
+    } else {
+        eprintln!("{:#?}", report);
+        std::process::exit(1);
+    }
+}
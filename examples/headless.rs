@@ -0,0 +1,35 @@
+//! Runs the test ROM with no window or sound, as on a CI machine without a GPU.
+//! Only the core and `mupen64plus-rsp-hle`, next to it, are needed. The executable has to
+//! export the built-in plugin functions for the core to find them:
+//!
+//! ```text
+//! RUSTFLAGS="-C link-arg=-rdynamic" cargo run --example headless
+//! ```
+
+use mupen64plus::Core;
+
+mupen64plus::export_builtin_plugins!();
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    pretty_env_logger::init();
+
+    let path = format!("{}/libs", env!("CARGO_MANIFEST_DIR"));
+
+    let core = Core::load_from_directory(&path)
+        .or_else(|_| Core::load_from_system())?;
+    let mut mupen = core.start(Some(&path), Some(&path))?;
+
+    mupen.open_rom_path(format!("{}/examples/m64p_test_rom.v64", env!("CARGO_MANIFEST_DIR")))?;
+
+    mupen.attach_headless_plugins()?;
+
+    let _frames = mupen.on_frame(Box::new(|frame, _lag| {
+        if frame % 600 == 0 {
+            println!("frame {}", frame);
+        }
+    }));
+
+    mupen.execute()?;
+
+    Ok(())
+}
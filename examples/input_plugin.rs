@@ -0,0 +1,46 @@
+//! An input plugin that walks forwards and presses A every second, built as a dynamic library:
+//!
+//! ```text
+//! cargo build --example input_plugin
+//! ```
+//!
+//! Load it with `Plugin::load_from_path("target/debug/examples/libinput_plugin.so")`.
+
+use mupen64plus::plugin::input::{Buttons, ControllerConfig, ControllerState, InputPlugin};
+use mupen64plus::plugin::{PluginInfo, Version};
+
+#[derive(Default)]
+struct WalkForwards {
+    polls: u32,
+}
+
+impl PluginInfo for WalkForwards {
+    const NAME: &'static str = "Walk Forwards";
+    const VERSION: Version = Version::new(0, 1, 0);
+}
+
+impl InputPlugin for WalkForwards {
+    fn initiate_controllers(&mut self) -> [ControllerConfig; 4] {
+        log::info!("controller 1 connected");
+        [ControllerConfig::PRESENT, Default::default(), Default::default(), Default::default()]
+    }
+
+    fn get_keys(&mut self, controller: usize) -> ControllerState {
+        if controller != 0 {
+            return ControllerState::default();
+        }
+
+        self.polls = self.polls.wrapping_add(1);
+        ControllerState {
+            buttons: if self.polls % 60 < 5 { Buttons::A } else { Buttons::empty() },
+            x: 0,
+            y: 80,
+        }
+    }
+
+    fn rom_closed(&mut self) {
+        self.polls = 0;
+    }
+}
+
+mupen64plus::export_input_plugin!(WalkForwards);
@@ -0,0 +1,44 @@
+//! An RSP plugin that passes every graphics and audio task on to the video and audio plugins,
+//! and logs the others. Built as a dynamic library:
+//!
+//! ```text
+//! cargo build --example rsp_plugin
+//! ```
+
+use mupen64plus::plugin::rsp::{RspInfo, RspPlugin, TaskType};
+use mupen64plus::plugin::{PluginInfo, Version};
+
+#[derive(Default)]
+struct PassThrough {
+    info: Option<RspInfo>,
+}
+
+impl PluginInfo for PassThrough {
+    const NAME: &'static str = "RSP Pass-Through";
+    const VERSION: Version = Version::new(0, 1, 0);
+}
+
+impl RspPlugin for PassThrough {
+    fn initiate_rsp(&mut self, info: RspInfo) {
+        self.info = Some(info);
+    }
+
+    fn do_rsp_cycles(&mut self, cycles: u32) -> u32 {
+        let info = match &self.info {
+            Some(info) => info,
+            None => return cycles,
+        };
+
+        let task_type = info.task_type();
+        match task_type {
+            TaskType::Graphics => info.process_dlist_list(),
+            TaskType::Audio => info.process_alist_list(),
+            TaskType::Other(other) => log::warn!("unhandled RSP task type {}", other),
+        }
+        info.task_done(task_type);
+
+        cycles
+    }
+}
+
+mupen64plus::export_rsp_plugin!(PassThrough);
@@ -0,0 +1,75 @@
+//! A video plugin that doesn't render display lists, but keeps a copy of the frame buffer the VI
+//! is showing so that screenshots work. Built as a dynamic library:
+//!
+//! ```text
+//! cargo build --example video_plugin
+//! ```
+
+use mupen64plus::plugin::video::{GfxInfo, VideoPlugin};
+use mupen64plus::plugin::{PluginInfo, Version};
+
+#[derive(Default)]
+struct FrameBufferViewer {
+    info: Option<GfxInfo>,
+    width: u32,
+    height: u32,
+    /// The last frame as RGB, top row first.
+    image: Vec<u8>,
+}
+
+impl PluginInfo for FrameBufferViewer {
+    const NAME: &'static str = "Frame Buffer Viewer";
+    const VERSION: Version = Version::new(0, 1, 0);
+}
+
+impl FrameBufferViewer {
+    /// Converts a 16-bit RGBA5551 frame buffer, the format most games use.
+    fn capture(&mut self, info: &GfxInfo) {
+        let origin = (info.vi_origin.get() & 0x00FF_FFFF) as usize;
+        let width = (info.vi_width.get() & 0xFFF) as usize;
+        let height = width * 3 / 4;
+
+        self.image.clear();
+        for i in 0..width * height {
+            // Pixels are halfwords in native-endian words, so flip the address on little-endian
+            let addr = if cfg!(target_endian = "little") { (origin + i * 2) ^ 2 } else { origin + i * 2 };
+            let pixel = match info.rdram().get(addr..addr + 2) {
+                Some(bytes) => u16::from_ne_bytes([bytes[0], bytes[1]]),
+                None => return,
+            };
+            for shift in [11, 6, 1].iter() {
+                self.image.push((((pixel >> shift) & 0x1F) << 3) as u8);
+            }
+        }
+        self.width = width as u32;
+        self.height = height as u32;
+    }
+}
+
+impl VideoPlugin for FrameBufferViewer {
+    fn initiate_gfx(&mut self, info: GfxInfo) -> bool {
+        self.info = Some(info);
+        true
+    }
+
+    fn update_screen(&mut self) -> bool {
+        if let Some(info) = self.info.take() {
+            self.capture(&info);
+            self.info = Some(info);
+        }
+        true
+    }
+
+    fn screen_size(&mut self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn read_screen(&mut self, _front: bool, dest: &mut [u8]) {
+        let row = self.width as usize * 3;
+        for (dest, src) in dest.chunks_exact_mut(row).zip(self.image.chunks_exact(row).rev()) {
+            dest.copy_from_slice(src);
+        }
+    }
+}
+
+mupen64plus::export_video_plugin!(FrameBufferViewer);
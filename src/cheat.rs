@@ -0,0 +1,300 @@
+//! Offline reading and writing of the cheat database, `mupencheat.txt`.
+//!
+//! Cheats from here can be applied to a running ROM with [`Mupen::add_cheat`](crate::core::Mupen::add_cheat).
+
+use std::fmt;
+use std::path::Path;
+use thiserror::Error;
+
+pub mod gameshark;
+pub mod pj64;
+
+#[derive(Error, Debug)]
+pub enum CheatDbError {
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("line {line}: {message}")]
+    Parse { line: usize, message: String },
+}
+
+/// One line of a cheat, writing `value` to `address`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct CheatCode {
+    pub address: u32,
+    /// `None` for a `????` placeholder, which is replaced by the value of the chosen option.
+    pub value: Option<u16>,
+}
+
+/// A selectable value for a cheat's `????` placeholders.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CheatOption {
+    pub value: u16,
+    pub label: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Cheat {
+    /// The name, with `\` separating folders (e.g. `Play As\Player 1`).
+    pub name: String,
+    pub description: Option<String>,
+    pub codes: Vec<CheatCode>,
+    pub options: Vec<CheatOption>,
+}
+
+/// The cheats for one game, identified like `crc 80F41131-384645F6-C:4A`.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct CheatGame {
+    pub crc1: u32,
+    pub crc2: u32,
+    /// Country code byte from the ROM header.
+    pub country: u8,
+    pub name: String,
+    pub cheats: Vec<Cheat>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct CheatDb {
+    pub games: Vec<CheatGame>,
+}
+
+impl Cheat {
+    /// Returns true if the cheat has `????` placeholders and needs an option to be chosen.
+    pub fn has_options(&self) -> bool {
+        self.codes.iter().any(|c| c.value.is_none())
+    }
+
+    /// Resolves the codes, replacing placeholders with the value of `options[option]`.
+    ///
+    /// Returns `None` if the cheat has placeholders and `option` doesn't select one of its options.
+    pub fn resolve(&self, option: Option<usize>) -> Option<Vec<(u32, u16)>> {
+        let option = option.and_then(|i| self.options.get(i)).map(|o| o.value);
+
+        self.codes
+            .iter()
+            .map(|code| Some((code.address, code.value.or(option)?)))
+            .collect()
+    }
+}
+
+impl CheatGame {
+    /// The identifier used after `crc` in `mupencheat.txt`.
+    pub fn id(&self) -> String {
+        format!("{:08X}-{:08X}-C:{:02X}", self.crc1, self.crc2, self.country)
+    }
+
+    pub fn cheat(&self, name: &str) -> Option<&Cheat> {
+        self.cheats.iter().find(|c| c.name == name)
+    }
+}
+
+impl CheatDb {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, CheatDbError> {
+        let text = std::fs::read(path)?;
+        Self::parse(&String::from_utf8_lossy(&text))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), CheatDbError> {
+        std::fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, CheatDbError> {
+        let mut db = CheatDb::default();
+
+        for (idx, line) in text.lines().enumerate() {
+            let err = |message: String| CheatDbError::Parse { line: idx + 1, message };
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+
+            let (tag, rest) = line.split_once(' ').unwrap_or((line, ""));
+            let rest = rest.trim();
+
+            match tag {
+                "crc" => {
+                    let (crc1, crc2, country) =
+                        parse_game_id(rest).ok_or_else(|| err(format!("invalid game id {:?}", rest)))?;
+                    db.games.push(CheatGame {
+                        crc1,
+                        crc2,
+                        country,
+                        ..Default::default()
+                    });
+                }
+                "gn" => {
+                    let game = db.games.last_mut().ok_or_else(|| err("`gn` before `crc`".into()))?;
+                    game.name = rest.to_owned();
+                }
+                "cn" => {
+                    let game = db.games.last_mut().ok_or_else(|| err("`cn` before `crc`".into()))?;
+                    game.cheats.push(Cheat {
+                        name: rest.to_owned(),
+                        ..Default::default()
+                    });
+                }
+                "cd" => {
+                    let cheat = db
+                        .games
+                        .last_mut()
+                        .and_then(|g| g.cheats.last_mut())
+                        .ok_or_else(|| err("`cd` before `cn`".into()))?;
+                    cheat.description = Some(rest.to_owned());
+                }
+                _ => {
+                    let cheat = db
+                        .games
+                        .last_mut()
+                        .and_then(|g| g.cheats.last_mut())
+                        .ok_or_else(|| err("code before `cn`".into()))?;
+                    let address =
+                        u32::from_str_radix(tag, 16).map_err(|_| err(format!("invalid address {:?}", tag)))?;
+                    let (value, options) = rest.split_once(' ').unwrap_or((rest, ""));
+
+                    let value = if value == "????" {
+                        None
+                    } else {
+                        let value =
+                            u16::from_str_radix(value, 16).map_err(|_| err(format!("invalid value {:?}", value)))?;
+                        Some(value)
+                    };
+                    cheat.codes.push(CheatCode { address, value });
+
+                    let options = options.trim();
+                    if !options.is_empty() {
+                        cheat.options = parse_options(options).map_err(err)?;
+                    }
+                }
+            }
+        }
+
+        Ok(db)
+    }
+
+    /// Finds the cheats for a game by the CRCs and country code in its ROM header.
+    pub fn get(&self, crc1: u32, crc2: u32, country: u8) -> Option<&CheatGame> {
+        self.games
+            .iter()
+            .find(|g| g.crc1 == crc1 && g.crc2 == crc2 && g.country == country)
+    }
+}
+
+/// Parses `80F41131-384645F6-C:4A`.
+fn parse_game_id(s: &str) -> Option<(u32, u32, u8)> {
+    let mut parts = s.split('-');
+    let crc1 = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let crc2 = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let country = u8::from_str_radix(parts.next()?.strip_prefix("C:")?, 16).ok()?;
+    Some((crc1, crc2, country))
+}
+
+/// Parses `0001:"1 Lap",0002:"2 Laps"`. Labels may contain commas and `\"` escapes.
+fn parse_options(s: &str) -> Result<Vec<CheatOption>, String> {
+    let mut options = Vec::new();
+    let mut rest = s;
+
+    while !rest.is_empty() {
+        let bad = || format!("invalid option list at {:?}", rest);
+
+        let (value, after) = rest.split_once(":\"").ok_or_else(bad)?;
+        let value = u16::from_str_radix(value.trim(), 16).map_err(|_| bad())?;
+
+        let mut label = String::new();
+        let mut chars = after.char_indices();
+        let end = loop {
+            match chars.next().ok_or_else(bad)? {
+                (_, '\\') => label.extend(chars.next().map(|(_, c)| c)),
+                (i, '"') => break i,
+                (_, c) => label.push(c),
+            }
+        };
+
+        options.push(CheatOption { value, label });
+        let after = after[end + 1..].trim_start();
+        rest = after.strip_prefix(',').unwrap_or(after).trim_start();
+    }
+
+    Ok(options)
+}
+
+impl fmt::Display for Cheat {
+    /// Formats the cheat as its `cn` block in `mupencheat.txt`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, " cn {}", self.name)?;
+        if let Some(description) = &self.description {
+            writeln!(f, "  cd {}", description)?;
+        }
+
+        let mut options_written = false;
+        for code in &self.codes {
+            match code.value {
+                Some(value) => writeln!(f, "  {:08X} {:04X}", code.address, value)?,
+                None => {
+                    write!(f, "  {:08X} ????", code.address)?;
+                    if !options_written && !self.options.is_empty() {
+                        let options: Vec<String> = self
+                            .options
+                            .iter()
+                            .map(|o| {
+                                let label = o.label.replace('\\', "\\\\").replace('"', "\\\"");
+                                format!("{:04X}:\"{}\"", o.value, label)
+                            })
+                            .collect();
+                        write!(f, " {}", options.join(","))?;
+                        options_written = true;
+                    }
+                    writeln!(f)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for CheatGame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "crc {}", self.id())?;
+        writeln!(f, "gn {}", self.name)?;
+        for cheat in &self.cheats {
+            write!(f, "{}", cheat)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for CheatDb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, game) in self.games.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", game)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shipped_database() {
+        let db = CheatDb::load(concat!(env!("CARGO_MANIFEST_DIR"), "/libs/mupencheat.txt")).unwrap();
+        assert!(db.games.len() > 500);
+
+        let game = db.get(0x80F41131, 0x384645F6, 0x4A).unwrap();
+        assert_eq!(game.name, "AeroGauge (J) (V1.1)");
+
+        let laps = game.cheat("Laps Of Race").unwrap();
+        assert!(laps.has_options());
+        assert_eq!(laps.options.len(), 6);
+        assert_eq!(laps.options[5].label, "Never Ending Laps");
+        assert_eq!(laps.resolve(None), None);
+        assert_eq!(laps.resolve(Some(1)), Some(vec![(0x8013CD0E, 0x0002)]));
+
+        // Writing and re-reading gives the same database
+        assert_eq!(CheatDb::parse(&db.to_string()).unwrap(), db);
+    }
+}
@@ -0,0 +1,350 @@
+//! GameShark / Action Replay codes, as understood by `CoreAddCheat`.
+//!
+//! Each code is a 32-bit word, whose top byte is the code type and low 24 bits are an RDRAM
+//! offset, followed by a 16-bit value: `8013CD0E 0001`.
+
+use mupen64plus_sys::*;
+use std::fmt;
+use thiserror::Error;
+
+/// Size of RDRAM with the Expansion Pak; codes can't address past it.
+pub const RDRAM_SIZE: u32 = 0x80_0000;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum CodeType {
+    /// `80`: write an 8-bit value every frame.
+    Write8,
+    /// `81`: write a 16-bit value every frame.
+    Write16,
+    /// `A0`: write an 8-bit value through the uncached segment.
+    UncachedWrite8,
+    /// `A1`: write a 16-bit value through the uncached segment.
+    UncachedWrite16,
+    /// `D0`: only run the next code if the 8-bit value matches.
+    IfEqual8,
+    /// `D1`: only run the next code if the 16-bit value matches.
+    IfEqual16,
+    /// `D2`: only run the next code if the 8-bit value differs.
+    IfNotEqual8,
+    /// `D3`: only run the next code if the 16-bit value differs.
+    IfNotEqual16,
+    /// `88`: write an 8-bit value when the GameShark button is pressed.
+    ButtonWrite8,
+    /// `89`: write a 16-bit value when the GameShark button is pressed.
+    ButtonWrite16,
+    /// `F0`: write an 8-bit value once, at boot.
+    BootWrite8,
+    /// `F1`: write a 16-bit value once, at boot.
+    BootWrite16,
+    /// `50`: repeat the next write code (`5000NNSS VVVV`: `NN` times, stepping the address by
+    /// `SS` and the value by `VVVV`).
+    Repeat,
+    /// `2A`: an enabler for the GameShark hardware. Accepted but has no effect in the core.
+    Enabler,
+    /// `DE`: sets the game's entry point for the GameShark hardware.
+    EntryPoint,
+    /// `EE`: disables the Expansion Pak.
+    DisableExpansionPak,
+    /// `FF`: sets the GameShark's active location.
+    ActiveLocation,
+}
+
+impl CodeType {
+    pub fn from_byte(b: u8) -> Option<CodeType> {
+        Some(match b {
+            0x80 => CodeType::Write8,
+            0x81 => CodeType::Write16,
+            0xA0 => CodeType::UncachedWrite8,
+            0xA1 => CodeType::UncachedWrite16,
+            0xD0 => CodeType::IfEqual8,
+            0xD1 => CodeType::IfEqual16,
+            0xD2 => CodeType::IfNotEqual8,
+            0xD3 => CodeType::IfNotEqual16,
+            0x88 => CodeType::ButtonWrite8,
+            0x89 => CodeType::ButtonWrite16,
+            0xF0 => CodeType::BootWrite8,
+            0xF1 => CodeType::BootWrite16,
+            0x50 => CodeType::Repeat,
+            0x2A => CodeType::Enabler,
+            0xDE => CodeType::EntryPoint,
+            0xEE => CodeType::DisableExpansionPak,
+            0xFF => CodeType::ActiveLocation,
+            _ => return None,
+        })
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            CodeType::Write8 => 0x80,
+            CodeType::Write16 => 0x81,
+            CodeType::UncachedWrite8 => 0xA0,
+            CodeType::UncachedWrite16 => 0xA1,
+            CodeType::IfEqual8 => 0xD0,
+            CodeType::IfEqual16 => 0xD1,
+            CodeType::IfNotEqual8 => 0xD2,
+            CodeType::IfNotEqual16 => 0xD3,
+            CodeType::ButtonWrite8 => 0x88,
+            CodeType::ButtonWrite16 => 0x89,
+            CodeType::BootWrite8 => 0xF0,
+            CodeType::BootWrite16 => 0xF1,
+            CodeType::Repeat => 0x50,
+            CodeType::Enabler => 0x2A,
+            CodeType::EntryPoint => 0xDE,
+            CodeType::DisableExpansionPak => 0xEE,
+            CodeType::ActiveLocation => 0xFF,
+        }
+    }
+
+    /// Returns true for codes that operate on a single byte of memory.
+    pub fn is_8bit(self) -> bool {
+        matches!(
+            self,
+            CodeType::Write8
+                | CodeType::UncachedWrite8
+                | CodeType::IfEqual8
+                | CodeType::IfNotEqual8
+                | CodeType::ButtonWrite8
+                | CodeType::BootWrite8
+        )
+    }
+
+    /// Returns true for codes that operate on a halfword of memory.
+    pub fn is_16bit(self) -> bool {
+        matches!(
+            self,
+            CodeType::Write16
+                | CodeType::UncachedWrite16
+                | CodeType::IfEqual16
+                | CodeType::IfNotEqual16
+                | CodeType::ButtonWrite16
+                | CodeType::BootWrite16
+        )
+    }
+
+    /// Returns true for codes that apply to the code after them.
+    pub fn is_prefix(self) -> bool {
+        matches!(
+            self,
+            CodeType::IfEqual8
+                | CodeType::IfEqual16
+                | CodeType::IfNotEqual8
+                | CodeType::IfNotEqual16
+                | CodeType::Repeat
+        )
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq, Clone)]
+pub enum CodeErrorKind {
+    #[error("expected `XXXXXXXX YYYY`")]
+    Syntax,
+    #[error("unknown code type {0:02X}")]
+    UnknownType(u8),
+    #[error("address {0:06X} is outside of RDRAM")]
+    OutOfRange(u32),
+    #[error("16-bit code on odd address {0:06X}")]
+    Unaligned(u32),
+    #[error("8-bit code with value {0:04X} wider than a byte")]
+    ValueTooWide(u16),
+    #[error("{0:?} code must be followed by another code")]
+    MissingNext(CodeType),
+    #[error("repeat code must be followed by an 80 or 81 write")]
+    BadRepeatTarget,
+}
+
+/// An invalid code. `line` is the 1-based index of the offending code.
+#[derive(Error, Debug, PartialEq, Eq, Clone)]
+#[error("line {line}: {kind}")]
+pub struct CodeError {
+    pub line: usize,
+    pub kind: CodeErrorKind,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct GsCode {
+    pub code_type: CodeType,
+    /// Offset into RDRAM (the low 24 bits of the code's address).
+    pub offset: u32,
+    pub value: u16,
+}
+
+impl GsCode {
+    /// Decodes and checks a single code, given as its 32-bit address word and value.
+    pub fn new(address: u32, value: u16) -> Result<GsCode, CodeErrorKind> {
+        let type_byte = (address >> 24) as u8;
+        let code_type = CodeType::from_byte(type_byte).ok_or(CodeErrorKind::UnknownType(type_byte))?;
+        let offset = address & 0x00FF_FFFF;
+
+        if code_type.is_8bit() || code_type.is_16bit() {
+            if offset >= RDRAM_SIZE {
+                return Err(CodeErrorKind::OutOfRange(offset));
+            }
+            if code_type.is_16bit() && offset & 1 != 0 {
+                return Err(CodeErrorKind::Unaligned(offset));
+            }
+            if code_type.is_8bit() && value > 0xFF {
+                return Err(CodeErrorKind::ValueTooWide(value));
+            }
+        }
+
+        Ok(GsCode {
+            code_type,
+            offset,
+            value,
+        })
+    }
+
+    /// Like `new`, but only rejects what the core does: an unknown code type. The value of an
+    /// 8-bit code is truncated to its low byte, and a write may be to any address, odd or
+    /// outside of RDRAM. Meant for curated sources such as `mupencheat.txt`, which have all of
+    /// these.
+    pub fn new_lenient(address: u32, value: u16) -> Result<GsCode, CodeErrorKind> {
+        let type_byte = (address >> 24) as u8;
+        let code_type = CodeType::from_byte(type_byte).ok_or(CodeErrorKind::UnknownType(type_byte))?;
+        let value = if code_type.is_8bit() { value & 0xFF } else { value };
+
+        Ok(GsCode {
+            code_type,
+            offset: address & 0x00FF_FFFF,
+            value,
+        })
+    }
+
+    /// The full 32-bit address word, type byte included.
+    pub fn address(&self) -> u32 {
+        ((self.code_type.to_byte() as u32) << 24) | self.offset
+    }
+}
+
+impl std::str::FromStr for GsCode {
+    type Err = CodeErrorKind;
+
+    /// Parses `8013CD0E 0001`. The separator may be omitted or be a `:`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits: String = s.chars().filter(|c| !c.is_whitespace() && *c != ':').collect();
+        if digits.len() != 12 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(CodeErrorKind::Syntax);
+        }
+
+        let address = u32::from_str_radix(&digits[..8], 16).unwrap();
+        let value = u16::from_str_radix(&digits[8..], 16).unwrap();
+        GsCode::new(address, value)
+    }
+}
+
+impl fmt::Display for GsCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08X} {:04X}", self.address(), self.value)
+    }
+}
+
+impl From<GsCode> for m64p_cheat_code {
+    fn from(code: GsCode) -> Self {
+        m64p_cheat_code {
+            address: code.address(),
+            value: code.value as i32,
+        }
+    }
+}
+
+/// Checks rules that span several codes, such as a conditional needing a code to apply to.
+pub fn validate(codes: &[GsCode]) -> Result<(), CodeError> {
+    for (i, code) in codes.iter().enumerate() {
+        let err = |kind| CodeError { line: i + 1, kind };
+
+        if code.code_type.is_prefix() {
+            let next = codes.get(i + 1).ok_or_else(|| err(CodeErrorKind::MissingNext(code.code_type)))?;
+
+            if code.code_type == CodeType::Repeat
+                && !matches!(next.code_type, CodeType::Write8 | CodeType::Write16)
+            {
+                return Err(err(CodeErrorKind::BadRepeatTarget));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parses one code per line, ignoring blank lines. Every bad line is reported, not just the
+/// first.
+pub fn parse(text: &str) -> Result<Vec<GsCode>, Vec<CodeError>> {
+    let mut codes = Vec::new();
+    let mut lines = Vec::new();
+    let mut errors = Vec::new();
+
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        match line.parse() {
+            Ok(code) => {
+                codes.push(code);
+                lines.push(idx + 1);
+            }
+            Err(kind) => errors.push(CodeError { line: idx + 1, kind }),
+        }
+    }
+
+    if errors.is_empty() {
+        // Report sequence errors against the text's line numbers
+        validate(&codes).map_err(|e| {
+            vec![CodeError {
+                line: lines[e.line - 1],
+                kind: e.kind,
+            }]
+        })?;
+        Ok(codes)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Formats codes back to text, one per line.
+pub fn format(codes: &[GsCode]) -> String {
+    codes.iter().map(|c| format!("{}\n", c)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_format() {
+        let codes = parse("50000402 0000\n8113d058 0000\n\nD0123456:0001\n80123457 00FF\n").unwrap();
+        assert_eq!(codes.len(), 4);
+        assert_eq!(codes[0].code_type, CodeType::Repeat);
+        assert_eq!(codes[2].code_type, CodeType::IfEqual8);
+        assert_eq!(codes[3].offset, 0x123457);
+        assert_eq!(
+            format(&codes),
+            "50000402 0000\n8113D058 0000\nD0123456 0001\n80123457 00FF\n"
+        );
+    }
+
+    #[test]
+    fn errors_per_line() {
+        let errors = parse("80123456 0100\nXX\n81123457 0000\n12000000 0000\n80900000 0000").unwrap_err();
+        let kinds: Vec<_> = errors.iter().map(|e| (e.line, e.kind.clone())).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (1, CodeErrorKind::ValueTooWide(0x100)),
+                (2, CodeErrorKind::Syntax),
+                (3, CodeErrorKind::Unaligned(0x123457)),
+                (4, CodeErrorKind::UnknownType(0x12)),
+                (5, CodeErrorKind::OutOfRange(0x900000)),
+            ]
+        );
+
+        let errors = parse("80000000 0000\n\nD1000000 0000").unwrap_err();
+        assert_eq!(
+            errors,
+            vec![CodeError {
+                line: 3,
+                kind: CodeErrorKind::MissingNext(CodeType::IfEqual16),
+            }]
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Importing Project64 cheat files (`Project64.cht`).
+//!
+//! ```text
+//! [80F41131-384645F6-C:4A]
+//! Name=AeroGauge (J) (V1.1)
+//! Cheat0="No Damage\Player 1",50000402 0000,8113D058 0000
+//! Cheat0_N=Stops the other racers from hurting you
+//! Cheat1="Laps Of Race",8013CD0E ????
+//! Cheat1_O=$0001 1 Lap,$0002 2 Laps
+//! ```
+//!
+//! The result is a [`CheatDb`], so imported cheats can be added to a running ROM with
+//! [`Mupen::add_cheat`](crate::core::Mupen::add_cheat) or saved in `mupencheat.txt` format.
+
+use super::{Cheat, CheatCode, CheatDb, CheatDbError, CheatGame, CheatOption};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<CheatDb, CheatDbError> {
+    let text = std::fs::read(path)?;
+    parse(&String::from_utf8_lossy(&text))
+}
+
+pub fn parse(text: &str) -> Result<CheatDb, CheatDbError> {
+    let mut db = CheatDb::default();
+    // Keys may come in any order, so collect each game's cheats by index first
+    let mut cheats: BTreeMap<usize, Cheat> = BTreeMap::new();
+    let mut placeholders: BTreeMap<usize, Vec<Placeholder>> = BTreeMap::new();
+
+    for (idx, line) in text.lines().enumerate() {
+        let err = |message: String| CheatDbError::Parse { line: idx + 1, message };
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+
+        if let Some(rest) = line.strip_prefix('[') {
+            let id = rest.strip_suffix(']').ok_or_else(|| err("malformed game key".into()))?;
+            let (crc1, crc2, country) =
+                super::parse_game_id(id).ok_or_else(|| err(format!("invalid game key {:?}", id)))?;
+
+            finish_game(&mut db, &mut cheats, &mut placeholders);
+            db.games.push(CheatGame {
+                crc1,
+                crc2,
+                country,
+                ..Default::default()
+            });
+            continue;
+        }
+
+        let game = db.games.last_mut().ok_or_else(|| err("key before game key".into()))?;
+        let (key, value) = line.split_once('=').ok_or_else(|| err("expected `key=value`".into()))?;
+
+        if key == "Name" {
+            game.name = value.to_owned();
+            continue;
+        }
+
+        let rest = match key.strip_prefix("Cheat") {
+            Some(rest) => rest,
+            None => {
+                log::debug!("pj64: ignoring unknown key {:?} on line {}", key, idx + 1);
+                continue;
+            }
+        };
+        let (index, suffix) = rest.split_once('_').unwrap_or((rest, ""));
+        let index: usize = index.parse().map_err(|_| err(format!("invalid key {:?}", key)))?;
+        let cheat = cheats.entry(index).or_default();
+
+        match suffix {
+            "" => parse_cheat(cheat, placeholders.entry(index).or_default(), value).map_err(err)?,
+            "N" => cheat.description = Some(value.to_owned()),
+            "O" => cheat.options = parse_options(value).map_err(err)?,
+            // Value ranges (`_R`, `_RN`) have no equivalent in mupencheat.txt
+            _ => log::warn!("pj64: ignoring unsupported key {:?} on line {}", key, idx + 1),
+        }
+    }
+
+    finish_game(&mut db, &mut cheats, &mut placeholders);
+    Ok(db)
+}
+
+/// The placeholder digits of a code's value, such as `80??`, as the mask of the bits the option
+/// replaces and the value of the other bits.
+type Placeholder = (u16, u16);
+
+fn parse_placeholder(value: &str) -> Option<Placeholder> {
+    if value.is_empty() || value.len() > 4 {
+        return None;
+    }
+    value.chars().try_fold((0, 0), |(mask, fixed), c| {
+        let (m, f) = if c == '?' { (0xF, 0) } else { (0, c.to_digit(16)? as u16) };
+        Some((mask << 4 | m, fixed << 4 | f))
+    })
+}
+
+fn finish_game(db: &mut CheatDb, cheats: &mut BTreeMap<usize, Cheat>, placeholders: &mut BTreeMap<usize, Vec<Placeholder>>) {
+    let mut placeholders = std::mem::take(placeholders);
+    let cheats = std::mem::take(cheats).into_iter().filter_map(|(index, mut cheat)| {
+        let placeholders = placeholders.remove(&index).unwrap_or_default();
+        match placeholders.split_first() {
+            // mupencheat.txt only has whole `????` placeholders, so the fixed digits go into the
+            // option values instead
+            Some((&(mask, fixed), rest)) if rest.iter().all(|&p| p == (mask, fixed)) => {
+                for option in cheat.options.iter_mut() {
+                    option.value = fixed | (option.value & mask);
+                }
+            }
+            Some(_) => {
+                log::warn!("pj64: skipping cheat {:?}, whose placeholders have different fixed digits", cheat.name);
+                return None;
+            }
+            None => {}
+        }
+        Some(cheat)
+    });
+
+    if let Some(game) = db.games.last_mut() {
+        game.cheats.extend(cheats.filter(|c| !c.name.is_empty()));
+    }
+}
+
+/// Parses `"Name",80123456 0001,81123456 ????`, adding the code's placeholders to `placeholders`.
+fn parse_cheat(cheat: &mut Cheat, placeholders: &mut Vec<Placeholder>, s: &str) -> Result<(), String> {
+    let rest = s.strip_prefix('"').ok_or_else(|| format!("expected quoted name in {:?}", s))?;
+    let (name, codes) = rest.split_once('"').ok_or_else(|| format!("unterminated name in {:?}", s))?;
+    cheat.name = name.to_owned();
+
+    for code in codes.split(',').map(str::trim).filter(|c| !c.is_empty()) {
+        let bad = || format!("invalid code {:?}", code);
+        let (address, value) = code.split_once(' ').ok_or_else(bad)?;
+        let address = u32::from_str_radix(address, 16).map_err(|_| bad())?;
+
+        // PJ64 also allows partial placeholders such as `80??`
+        let value = value.trim();
+        let value = if value.contains('?') {
+            placeholders.push(parse_placeholder(value).ok_or_else(bad)?);
+            None
+        } else {
+            Some(u16::from_str_radix(value, 16).map_err(|_| bad())?)
+        };
+
+        cheat.codes.push(CheatCode { address, value });
+    }
+
+    Ok(())
+}
+
+/// Parses `$0001 1 Lap,$0002 2 Laps`.
+fn parse_options(s: &str) -> Result<Vec<CheatOption>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|o| !o.is_empty())
+        .map(|option| {
+            let bad = || format!("invalid option {:?}", option);
+            let rest = option.strip_prefix('$').ok_or_else(bad)?;
+            let (value, label) = rest.split_once(' ').unwrap_or((rest, ""));
+
+            Ok(CheatOption {
+                value: u16::from_str_radix(value, 16).map_err(|_| bad())?,
+                label: label.trim().to_owned(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn import() {
+        let db = parse(
+            "[80F41131-384645F6-C:4A]\n\
+             Name=AeroGauge (J) (V1.1)\n\
+             Cheat1=\"Laps Of Race\",8013CD0E ????\n\
+             Cheat1_O=$0001 1 Lap,$0002 2 Laps\n\
+             Cheat0=\"No Damage\\Player 1\",50000402 0000,8113D058 0000\n\
+             Cheat0_N=Stops the other racers from hurting you\n\
+             Cheat2=\"Boost\",8013CD10 80??\n\
+             Cheat2_O=$01 Low,$7F High\n\
+             Cheat3=\"Mixed\",8013CD10 80??,8013CD12 00??\n",
+        )
+        .unwrap();
+
+        let game = db.get(0x80F41131, 0x384645F6, 0x4A).unwrap();
+        assert_eq!(game.name, "AeroGauge (J) (V1.1)");
+        assert_eq!(game.cheats.len(), 3);
+        assert_eq!(game.cheats[0].name, "No Damage\\Player 1");
+        assert_eq!(game.cheats[0].codes.len(), 2);
+        assert!(game.cheats[0].description.is_some());
+        assert_eq!(game.cheats[1].resolve(Some(1)), Some(vec![(0x8013CD0E, 2)]));
+        assert_eq!(game.cheats[2].resolve(Some(1)), Some(vec![(0x8013CD10, 0x807F)]));
+
+        assert_eq!(
+            db.to_string(),
+            "crc 80F41131-384645F6-C:4A\n\
+             gn AeroGauge (J) (V1.1)\n \
+             cn No Damage\\Player 1\n  \
+             cd Stops the other racers from hurting you\n  \
+             50000402 0000\n  \
+             8113D058 0000\n \
+             cn Laps Of Race\n  \
+             8013CD0E ???? 0001:\"1 Lap\",0002:\"2 Laps\"\n \
+             cn Boost\n  \
+             8013CD10 ???? 8001:\"Low\",807F:\"High\"\n"
+        );
+    }
+}
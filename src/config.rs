@@ -0,0 +1,444 @@
+//! Offline reading and writing of `mupen64plus.cfg`.
+//!
+//! This does not need `libmupen64plus` to be loaded. Comments, blank lines, section order and
+//! parameter help lines are kept, so a file can be parsed, patched and written back with only
+//! the changed parameters differing.
+
+use std::fmt;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("parse: {0}")]
+    Parse(#[from] ParseError),
+}
+
+#[derive(Error, Debug, PartialEq, Eq, Clone)]
+#[error("line {line}: {kind}")]
+pub struct ParseError {
+    pub line: usize,
+    pub kind: ParseErrorKind,
+}
+
+#[derive(Error, Debug, PartialEq, Eq, Clone)]
+pub enum ParseErrorKind {
+    #[error("parameter outside of a section")]
+    NoSection,
+    #[error("malformed section header")]
+    BadSection,
+    #[error("expected `name = value`")]
+    BadParameter,
+    #[error("unterminated string")]
+    UnterminatedString,
+    #[error("invalid value {0:?}")]
+    BadValue(String),
+}
+
+/// A parameter value. The variants mirror `m64p_type`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Int(i32),
+    Float(f32),
+    Bool(bool),
+    String(String),
+}
+
+impl Value {
+    /// Parses a value as written by the core (`"string"`, `True`/`False`, `1.000000`, `1`).
+    pub fn parse(s: &str) -> Result<Value, ParseErrorKind> {
+        let s = s.trim();
+
+        if let Some(rest) = s.strip_prefix('"') {
+            return match rest.rfind('"') {
+                Some(end) => Ok(Value::String(rest[..end].to_owned())),
+                None => Err(ParseErrorKind::UnterminatedString),
+            };
+        }
+
+        if s.eq_ignore_ascii_case("true") {
+            Ok(Value::Bool(true))
+        } else if s.eq_ignore_ascii_case("false") {
+            Ok(Value::Bool(false))
+        } else if let Ok(i) = s.parse() {
+            Ok(Value::Int(i))
+        } else if let Ok(f) = s.parse() {
+            Ok(Value::Float(f))
+        } else {
+            Err(ParseErrorKind::BadValue(s.to_owned()))
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i32> {
+        match *self {
+            Value::Int(i) => Some(i),
+            Value::Float(f) => Some(f as i32),
+            Value::Bool(b) => Some(b as i32),
+            Value::String(_) => None,
+        }
+    }
+
+    pub fn as_float(&self) -> Option<f32> {
+        match *self {
+            Value::Int(i) => Some(i as f32),
+            Value::Float(f) => Some(f),
+            Value::Bool(_) | Value::String(_) => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            Value::Int(i) => Some(i != 0),
+            Value::Bool(b) => Some(b),
+            Value::Float(_) | Value::String(_) => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    /// Formats the value the same way the core's `ConfigSaveFile` does.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(v) => write!(f, "{:.6}", v),
+            Value::Bool(true) => write!(f, "True"),
+            Value::Bool(false) => write!(f, "False"),
+            Value::String(s) => write!(f, "\"{}\"", s),
+        }
+    }
+}
+
+impl From<i32> for Value {
+    fn from(i: i32) -> Self {
+        Value::Int(i)
+    }
+}
+
+impl From<f32> for Value {
+    fn from(f: f32) -> Self {
+        Value::Float(f)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parameter {
+    pub name: String,
+    pub value: Value,
+    /// The comment line directly above the parameter, without the leading `#`.
+    pub help: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
+    Blank,
+    /// A comment that isn't the help text of a parameter, without the leading `#`.
+    Comment(String),
+    Parameter(Parameter),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub lines: Vec<Line>,
+}
+
+/// The contents of a `mupen64plus.cfg` file.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ConfigFile {
+    /// Lines before the first section, usually the file banner.
+    pub header: Vec<Line>,
+    pub sections: Vec<Section>,
+}
+
+impl Section {
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Section {
+            name: name.into(),
+            lines: Vec::new(),
+        }
+    }
+
+    pub fn parameters(&self) -> impl Iterator<Item = &Parameter> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Parameter(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    /// Looks up a parameter. Like the core, names are case-insensitive.
+    pub fn parameter(&self, name: &str) -> Option<&Parameter> {
+        self.parameters().find(|p| p.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn parameter_mut(&mut self, name: &str) -> Option<&mut Parameter> {
+        self.lines.iter_mut().find_map(|line| match line {
+            Line::Parameter(p) if p.name.eq_ignore_ascii_case(name) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.parameter(name).map(|p| &p.value)
+    }
+
+    /// Sets the value of a parameter, appending it to the section if it doesn't exist yet.
+    pub fn set<V: Into<Value>>(&mut self, name: &str, value: V) {
+        let value = value.into();
+
+        if let Some(p) = self.parameter_mut(name) {
+            p.value = value;
+        } else {
+            self.lines.push(Line::Parameter(Parameter {
+                name: name.to_owned(),
+                value,
+                help: None,
+            }));
+        }
+    }
+
+    /// Sets the help text of an existing parameter. Returns false if there is no such parameter.
+    pub fn set_help<S: Into<String>>(&mut self, name: &str, help: Option<S>) -> bool {
+        if let Some(p) = self.parameter_mut(name) {
+            p.help = help.map(Into::into);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Parameter> {
+        let idx = self.lines.iter().position(|line| {
+            matches!(line, Line::Parameter(p) if p.name.eq_ignore_ascii_case(name))
+        })?;
+
+        match self.lines.remove(idx) {
+            Line::Parameter(p) => Some(p),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl ConfigFile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(Self::parse(&text)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ConfigError> {
+        std::fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, ParseError> {
+        let mut config = ConfigFile::new();
+        let mut pending_comment: Option<String> = None;
+
+        for (idx, raw) in text.lines().enumerate() {
+            let line = raw.trim();
+            let err = |kind| ParseError { line: idx + 1, kind };
+
+            if let Some(comment) = line.strip_prefix('#') {
+                // A comment may be the help text of the following parameter, so hold on to it.
+                if let Some(prev) = pending_comment.replace(comment.trim_start().to_owned()) {
+                    config.last_lines_mut().push(Line::Comment(prev));
+                }
+            } else if line.is_empty() {
+                let lines = config.last_lines_mut();
+                if let Some(prev) = pending_comment.take() {
+                    lines.push(Line::Comment(prev));
+                }
+                lines.push(Line::Blank);
+            } else if let Some(rest) = line.strip_prefix('[') {
+                let name = rest.strip_suffix(']').ok_or_else(|| err(ParseErrorKind::BadSection))?;
+                if let Some(prev) = pending_comment.take() {
+                    config.last_lines_mut().push(Line::Comment(prev));
+                }
+                config.sections.push(Section::new(name.trim()));
+            } else {
+                let section = config.sections.last_mut().ok_or_else(|| err(ParseErrorKind::NoSection))?;
+
+                let eq = line.find('=').ok_or_else(|| err(ParseErrorKind::BadParameter))?;
+                let name = line[..eq].trim();
+                if name.is_empty() {
+                    return Err(err(ParseErrorKind::BadParameter));
+                }
+                let value = Value::parse(&line[eq + 1..]).map_err(err)?;
+
+                section.lines.push(Line::Parameter(Parameter {
+                    name: name.to_owned(),
+                    value,
+                    help: pending_comment.take(),
+                }));
+            }
+        }
+
+        if let Some(prev) = pending_comment {
+            config.last_lines_mut().push(Line::Comment(prev));
+        }
+
+        Ok(config)
+    }
+
+    /// The lines of the last section, or the header if there are no sections yet.
+    fn last_lines_mut(&mut self) -> &mut Vec<Line> {
+        match self.sections.last_mut() {
+            Some(section) => &mut section.lines,
+            None => &mut self.header,
+        }
+    }
+
+    /// Looks up a section. Like the core, names are case-insensitive.
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn section_mut(&mut self, name: &str) -> Option<&mut Section> {
+        self.sections.iter_mut().find(|s| s.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Gets a section, appending an empty one to the file if it doesn't exist yet.
+    pub fn section_or_insert(&mut self, name: &str) -> &mut Section {
+        match self.sections.iter().position(|s| s.name.eq_ignore_ascii_case(name)) {
+            Some(idx) => &mut self.sections[idx],
+            None => {
+                let mut section = Section::new(name);
+                section.lines.push(Line::Blank);
+                self.sections.push(section);
+                self.sections.last_mut().unwrap()
+            }
+        }
+    }
+
+    pub fn remove_section(&mut self, name: &str) -> Option<Section> {
+        let idx = self.sections.iter().position(|s| s.name.eq_ignore_ascii_case(name))?;
+        Some(self.sections.remove(idx))
+    }
+
+    pub fn get(&self, section: &str, name: &str) -> Option<&Value> {
+        self.section(section).and_then(|s| s.get(name))
+    }
+
+    pub fn set<V: Into<Value>>(&mut self, section: &str, name: &str, value: V) {
+        self.section_or_insert(section).set(name, value)
+    }
+}
+
+fn write_lines(f: &mut fmt::Formatter<'_>, lines: &[Line]) -> fmt::Result {
+    for line in lines {
+        match line {
+            Line::Blank => writeln!(f)?,
+            Line::Comment(c) => writeln!(f, "# {}", c)?,
+            Line::Parameter(p) => {
+                if let Some(help) = &p.help {
+                    writeln!(f, "# {}", help)?;
+                }
+                writeln!(f, "{} = {}", p.name, p.value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl fmt::Display for ConfigFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_lines(f, &self.header)?;
+        for section in &self.sections {
+            writeln!(f, "[{}]", section.name)?;
+            write_lines(f, &section.lines)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "\
+# Mupen64Plus Configuration File
+# This file is automatically read and written by the Mupen64Plus Core library
+
+[Core]
+
+# Mupen64Plus Core config parameter set version number.  Please don't change this version number.
+Version = 1.010000
+# Draw on-screen display if True, otherwise don't draw OSD
+OnScreenDisplay = True
+ScreenshotPath = \"\"
+R4300Emulator = 2
+
+[Video-General]
+
+Fullscreen = False
+";
+
+    #[test]
+    fn round_trip() {
+        let config = ConfigFile::parse(SAMPLE).unwrap();
+        assert_eq!(config.to_string(), SAMPLE);
+        assert_eq!(config.header.len(), 3);
+
+        let core = config.section("core").unwrap();
+        assert_eq!(core.get("Version"), Some(&Value::Float(1.01)));
+        assert_eq!(core.get("onscreendisplay"), Some(&Value::Bool(true)));
+        assert_eq!(core.get("ScreenshotPath"), Some(&Value::String(String::new())));
+        assert_eq!(
+            core.parameter("OnScreenDisplay").unwrap().help.as_deref(),
+            Some("Draw on-screen display if True, otherwise don't draw OSD")
+        );
+    }
+
+    #[test]
+    fn patch() {
+        let mut config = ConfigFile::parse(SAMPLE).unwrap();
+        config.set("Core", "R4300Emulator", 0);
+        config.set("Video-General", "ScreenWidth", 640);
+        config.set("Input-SDL-Control1", "plugged", true);
+
+        let text = config.to_string();
+        assert!(text.contains("R4300Emulator = 0\n"));
+        assert!(text.contains("Fullscreen = False\nScreenWidth = 640\n"));
+        assert!(text.ends_with("[Input-SDL-Control1]\n\nplugged = True\n"));
+    }
+
+    #[test]
+    fn errors() {
+        let err = ConfigFile::parse("Foo = 1").unwrap_err();
+        assert_eq!(err, ParseError { line: 1, kind: ParseErrorKind::NoSection });
+
+        let err = ConfigFile::parse("[Core]\nFoo = \"bar").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert_eq!(err.kind, ParseErrorKind::UnterminatedString);
+    }
+}
@@ -1,17 +1,32 @@
 use crate::plugin::*;
+use crate::rom::Rom;
 use crate::Error;
 use libloading::Library;
 use mupen64plus_sys::*;
 use std::ffi::CStr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
+mod cheat;
 pub mod debug;
+pub mod dump;
+mod frame;
+pub mod golden;
+pub mod movie;
+mod save;
+pub mod screen;
+mod state;
+mod subscribers;
+pub mod vidext;
+
+pub use frame::{FrameCallback, FrameSubscription};
 
 /// The emulator core, also known as `libmupen64plus`.
 #[allow(dead_code)]
 pub struct Core {
     lib: m64p_dynlib_handle,
+    /// The directory the library was loaded from, if loaded by path.
+    dir: Option<PathBuf>,
 
     // API
     plugin_get_version: ptr_PluginGetVersion,
@@ -80,7 +95,9 @@ impl Core {
     where
         P: AsRef<Path>,
     {
-        Self::load_from_library(unsafe { Library::new(dylib_path.as_ref().as_os_str())? })
+        let mut core = Self::load_from_library(unsafe { Library::new(dylib_path.as_ref().as_os_str())? })?;
+        core.dir = dylib_path.as_ref().parent().map(Path::to_path_buf);
+        Ok(core)
     }
 
     pub fn load_from_library<L>(lib: L) -> Result<Self, LoadError>
@@ -165,6 +182,7 @@ impl Core {
                 let lib: Library = lib.into();
                 lib.into_raw()
             },
+            dir: None,
         };
 
         let version = plugin.get_version()?;
@@ -233,7 +251,7 @@ impl Core {
                 std::ptr::null_mut(), // debug callback context
                 Some(debug_callback),
                 std::ptr::null_mut(), // state callback data
-                None,                 // state callback fn
+                Some(state::callback_state),
             );
             if r != m64p_error_M64ERR_SUCCESS {
                 return Err(r.into());
@@ -244,11 +262,14 @@ impl Core {
         drop(config_dir);
         drop(data_dir);
 
-        Ok(Mupen {
+        let mupen = Mupen {
             core: Rc::new(self),
             plugins: Vec::with_capacity(4),
             is_rom_open: false,
-        })
+        };
+        mupen.set_frame_callback()?;
+
+        Ok(mupen)
     }
 }
 
@@ -302,7 +323,10 @@ impl Mupen {
 
         let version = plugin.get_version()?;
 
-        if let Some(f) = plugin.plugin_startup {
+        if let Some(builtin) = &plugin.builtin {
+            log::trace!("starting built-in plugin {:?}", version.plugin_name);
+            builtin.start(self.core.lib);
+        } else if let Some(f) = plugin.plugin_startup {
             log::trace!("plugin {:?} PluginStartup()", version.plugin_name);
             unsafe {
                 f(self.core.lib, std::ptr::null_mut(), Some(debug_callback));
@@ -313,10 +337,21 @@ impl Mupen {
 
         log::trace!("plugin {:?} CoreAttachPlugin()", version.plugin_name);
 
-        let ret = unsafe {
+        let attach = || unsafe {
             self.core.core_attach_plugin.unwrap()(version.plugin_type.into(), plugin.lib)
         };
+        let ret = if plugin.builtin.is_some() {
+            crate::plugin::builtin::attaching(version.plugin_type, attach)
+        } else {
+            attach()
+        };
         if ret != m64p_error_M64ERR_SUCCESS {
+            // Don't leave it running, or a built-in plugin in the shared functions' dispatch
+            if let Some(f) = plugin.plugin_shutdown {
+                unsafe {
+                    let _ = f();
+                }
+            }
             return Err(ret.into());
         }
 
@@ -327,6 +362,37 @@ impl Mupen {
         Ok(())
     }
 
+    /// Attach the [`null`](crate::plugin::null) video, audio and input plugins as built-in
+    /// plugins, then `mupen64plus-rsp-hle`, loaded from the directory the core was loaded from
+    /// (or found by the system's library search if the core was loaded without a path). Games
+    /// run without a display, GPU or sound card; use `ScriptedInput` or `AudioCapture` instead
+    /// of the null plugins to control or record them.
+    ///
+    /// This only works if the executable calls
+    /// [`export_builtin_plugins!`](crate::export_builtin_plugins) and is linked with
+    /// `-rdynamic`. Otherwise it compiles fine but fails at runtime, as the core can't find the
+    /// built-in plugins' functions.
+    pub fn attach_headless_plugins(&mut self) -> Result<(), Error> {
+        let name = format!("mupen64plus-rsp-hle.{}", std::env::consts::DLL_EXTENSION);
+        let rsp = match &self.core.dir {
+            Some(dir) => Plugin::load_from_path(dir.join(name))?,
+            None => Plugin::load_from_path(name)?,
+        };
+        self.attach_headless_plugins_with_rsp(rsp)
+    }
+
+    /// Like [`attach_headless_plugins`](Self::attach_headless_plugins), with the given RSP
+    /// plugin instead of `mupen64plus-rsp-hle`.
+    pub fn attach_headless_plugins_with_rsp(&mut self, rsp: Plugin) -> Result<(), Error> {
+        use crate::plugin::null::{NullAudio, NullInput, NullVideo};
+
+        self.attach_plugin(Plugin::builtin_video(NullVideo)?)?;
+        self.attach_plugin(Plugin::builtin_audio(NullAudio)?)?;
+        self.attach_plugin(Plugin::builtin_input(NullInput)?)?;
+        self.attach_plugin(rsp)?;
+        Ok(())
+    }
+
     // TODO: detach_plugin (by type?)
 
     pub fn is_rom_open(&self) -> bool {
@@ -355,6 +421,13 @@ impl Mupen {
         }
     }
 
+    /// Load a ROM file into the core. `.z64`, `.v64` and `.n64` images are read as-is, and `.zip`
+    /// and `.gz` archives are unpacked (see `Rom::load`).
+    pub fn open_rom_path<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let rom = Rom::load(path)?;
+        self.open_rom(&mut rom.into_bytes())
+    }
+
     /// Execute the ROM. Blocking until the ROM is closed.
     pub fn execute(&self) -> Result<(), Error> {
         let ret = unsafe {
@@ -416,5 +489,8 @@ impl Drop for Mupen {
         }
 
         debug::clear_subscribers();
+        frame::clear_subscribers();
+        state::clear_subscribers();
+        vidext::clear_video_extension();
     }
 }
@@ -0,0 +1,112 @@
+use crate::cheat::gameshark::{self, CodeError, GsCode};
+use crate::cheat::{Cheat, CheatDb, CheatGame};
+use crate::Error;
+use super::Mupen;
+use mupen64plus_sys::*;
+use std::ffi::CString;
+
+/// The codes of a database cheat, checked only as far as the core checks them (see
+/// `GsCode::new_lenient`). `None` if the cheat needs an option and `option` isn't one.
+fn database_codes(cheat: &Cheat, option: Option<usize>) -> Option<Result<Vec<GsCode>, CodeError>> {
+    let codes = cheat.resolve(option)?;
+    Some(
+        codes
+            .into_iter()
+            .enumerate()
+            .map(|(i, (address, value))| {
+                GsCode::new_lenient(address, value).map_err(|kind| CodeError { line: i + 1, kind })
+            })
+            .collect(),
+    )
+}
+
+impl Mupen {
+    /// Add a cheat to the running ROM and enable it. `option` is an index into `cheat.options`,
+    /// and is required if the cheat has `????` placeholders.
+    ///
+    /// The codes are only checked as far as the core checks them, since the cheat database has
+    /// codes that `add_cheat_codes` would reject, such as 16-bit writes to odd addresses and
+    /// conditionals at the end of a cheat.
+    pub fn add_cheat(&self, cheat: &Cheat, option: Option<usize>) -> Result<(), Error> {
+        let codes = database_codes(cheat, option).ok_or(Error::InputInvalid)??;
+        self.core_add_cheat(&cheat.name, &codes)
+    }
+
+    /// Add a cheat made of GameShark codes (e.g. from `gameshark::parse`) and enable it.
+    pub fn add_cheat_codes(&self, name: &str, codes: &[GsCode]) -> Result<(), Error> {
+        gameshark::validate(codes)?;
+        self.core_add_cheat(name, codes)
+    }
+
+    fn core_add_cheat(&self, name: &str, codes: &[GsCode]) -> Result<(), Error> {
+        let name = CString::new(name).map_err(|_| Error::InputInvalid)?;
+        let mut codes: Vec<m64p_cheat_code> = codes.iter().map(|&c| c.into()).collect();
+
+        let ret = unsafe {
+            self.core.core_add_cheat.unwrap()(name.as_ptr(), codes.as_mut_ptr(), codes.len() as i32)
+        };
+        if ret != m64p_error_M64ERR_SUCCESS {
+            Err(ret.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Enable or disable a cheat previously added with `add_cheat`.
+    pub fn set_cheat_enabled(&self, name: &str, enabled: bool) -> Result<(), Error> {
+        let name = CString::new(name).map_err(|_| Error::InputInvalid)?;
+
+        let ret = unsafe {
+            self.core.core_cheat_enabled.unwrap()(name.as_ptr(), enabled as i32)
+        };
+        if ret != m64p_error_M64ERR_SUCCESS {
+            Err(ret.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Find the cheats for the open ROM, matching on the CRCs and country code in its header.
+    pub fn find_cheats<'a>(&self, db: &'a CheatDb) -> Result<Option<&'a CheatGame>, Error> {
+        if !self.is_rom_open() {
+            return Err(Error::NoRomOpen);
+        }
+
+        let header = self.rom_header()?;
+
+        // The header is a copy of the big-endian ROM bytes
+        Ok(db.get(
+            u32::from_be(header.CRC1),
+            u32::from_be(header.CRC2),
+            header.Country_code.to_ne_bytes()[0],
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shipped_database_codes() {
+        let db = CheatDb::load(concat!(env!("CARGO_MANIFEST_DIR"), "/libs/mupencheat.txt")).unwrap();
+
+        for game in &db.games {
+            for cheat in &game.cheats {
+                let options: Vec<_> = match cheat.has_options() {
+                    true => (0..cheat.options.len()).map(Some).collect(),
+                    false => vec![None],
+                };
+                for option in options {
+                    let codes = database_codes(cheat, option).unwrap();
+                    assert!(codes.is_ok(), "{} / {}: {:?}", game.name, cheat.name, codes);
+                }
+            }
+        }
+
+        // A 16-bit write to an odd address keeps its address
+        let code = GsCode::new_lenient(0x810BA9F1, 0).unwrap();
+        assert_eq!(code.address(), 0x810BA9F1);
+        assert!(GsCode::new(0x810BA9F1, 0).is_err());
+    }
+}
@@ -458,6 +458,11 @@ impl Debugger {
         }
     }
 
+    /// The framebuffer the game is displaying, decoded from RDRAM; see `Screen::read_framebuffer`.
+    pub fn read_framebuffer(&self) -> Result<crate::image::Image, Error> {
+        self.core.read_framebuffer()
+    }
+
     pub fn read_u64(&self, address: u32) -> u64 {
         unsafe {
             self.core.debug_mem_read64.unwrap()(address)
@@ -0,0 +1,215 @@
+use crate::image::Image;
+use crate::plugin::audio::capture::AudioCapture;
+use crate::Error;
+use super::screen::Screen;
+use super::{FrameSubscription, Mupen};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// Where `Mupen::dump_frames` writes frames.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DumpOutput {
+    /// Numbered PNG files (`000000.png`, `000001.png`, ...) in a directory, which is created if
+    /// needed. Each file has the size of its frame.
+    Png(PathBuf),
+    /// A YUV4MPEG2 video with 4:4:4 chroma, for piping into an encoder. Frames are scaled to the
+    /// size of the first frame. `frame_rate` is a fraction, such as `(60, 1)` for NTSC games or
+    /// `(50, 1)` for PAL.
+    Y4m { path: PathBuf, frame_rate: (u32, u32) },
+}
+
+enum Writer {
+    Png(PathBuf),
+    Y4m {
+        file: BufWriter<File>,
+        frame_rate: (u32, u32),
+        /// Set once the header is written.
+        size: Option<(u32, u32)>,
+    },
+}
+
+impl Writer {
+    fn write(&mut self, index: u32, image: &Image) -> Result<(), Error> {
+        match self {
+            Writer::Png(dir) => image.save_png(dir.join(format!("{:06}.png", index)))?,
+            Writer::Y4m { file, frame_rate, size } => {
+                let (width, height) = match *size {
+                    Some(size) => size,
+                    None => {
+                        writeln!(
+                            file,
+                            "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444",
+                            image.width, image.height, frame_rate.0, frame_rate.1
+                        )?;
+                        *size = Some((image.width, image.height));
+                        (image.width, image.height)
+                    }
+                };
+                write_y4m_frame(file, &image.scaled(width, height))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), Error> {
+        if let Writer::Y4m { mut file, .. } = self {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes a frame as full-size Y, U and V planes with BT.601 coefficients.
+fn write_y4m_frame<W: Write>(w: &mut W, image: &Image) -> io::Result<()> {
+    let pixels = image.rgb.chunks_exact(3).map(|p| (p[0] as i32, p[1] as i32, p[2] as i32));
+    let mut planes = vec![0u8; image.rgb.len()];
+    let (y, uv) = planes.split_at_mut(image.rgb.len() / 3);
+    let (u, v) = uv.split_at_mut(image.rgb.len() / 3);
+
+    for (i, (r, g, b)) in pixels.enumerate() {
+        y[i] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+        u[i] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+        v[i] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+    }
+
+    w.write_all(b"FRAME\n")?;
+    w.write_all(&planes)
+}
+
+struct DumpState {
+    screen: Screen,
+    writer: Option<Writer>,
+    audio: Option<AudioCapture>,
+    frames: u32,
+    /// The first error, after which nothing more is written.
+    error: Option<Error>,
+}
+
+impl DumpState {
+    fn frame(&mut self) {
+        let writer = match self.writer.as_mut() {
+            Some(writer) => writer,
+            None => return,
+        };
+
+        // The same buffer the core takes screenshots from
+        let frames = &mut self.frames;
+        let result = self.screen.read(false).and_then(|image| {
+            if image.is_empty() {
+                log::debug!("frame dump: video plugin has no image, skipping frame");
+                return Ok(());
+            }
+            writer.write(*frames, &image)?;
+            *frames += 1;
+            Ok(())
+        });
+
+        if let Err(e) = result {
+            log::error!("frame dump stopped: {}", e);
+            self.error = Some(e);
+            let _ = self.stop();
+        }
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        if let Some(audio) = self.audio.take() {
+            audio.stop_wav()?;
+        }
+        match self.writer.take() {
+            Some(writer) => writer.finish(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A frame dump started with `Mupen::dump_frames`. Dropping it stops the dump.
+pub struct FrameDump {
+    state: Rc<RefCell<DumpState>>,
+    /// Taken when the dump stops.
+    subscription: Option<FrameSubscription>,
+}
+
+impl FrameDump {
+    /// The number of frames written so far.
+    pub fn frames(&self) -> u32 {
+        self.state.borrow().frames
+    }
+
+    /// Stops the dump and finishes the files, returning the number of frames written or the
+    /// error that stopped the dump early.
+    pub fn finish(mut self) -> Result<u32, Error> {
+        self.subscription.take();
+        let mut state = self.state.borrow_mut();
+        state.stop()?;
+        match state.error.take() {
+            Some(e) => Err(e),
+            None => Ok(state.frames),
+        }
+    }
+}
+
+impl Drop for FrameDump {
+    fn drop(&mut self) {
+        self.subscription.take();
+        if let Err(e) = self.state.borrow_mut().stop() {
+            log::error!("failed to finish frame dump: {}", e);
+        }
+    }
+}
+
+impl Mupen {
+    /// Writes every frame the video plugin draws to `output`, until the returned `FrameDump` is
+    /// finished or dropped. The video plugin has to support reading the screen.
+    ///
+    /// With `audio`, the sound is written next to the frames: to `audio.wav` in the PNG
+    /// directory, or to the Y4M path with the extension `.wav`. `audio` must be the attached
+    /// audio plugin.
+    pub fn dump_frames(&self, output: DumpOutput, audio: Option<&AudioCapture>) -> Result<FrameDump, Error> {
+        let (writer, wav_path) = match output {
+            DumpOutput::Png(dir) => {
+                std::fs::create_dir_all(&dir)?;
+                let wav_path = dir.join("audio.wav");
+                (Writer::Png(dir), wav_path)
+            }
+            DumpOutput::Y4m { path, frame_rate } => {
+                let file = BufWriter::new(File::create(&path)?);
+                (Writer::Y4m { file, frame_rate, size: None }, path.with_extension("wav"))
+            }
+        };
+
+        if let Some(audio) = audio {
+            audio.record_wav(wav_path)?;
+        }
+
+        let state = Rc::new(RefCell::new(DumpState {
+            screen: self.screen(),
+            writer: Some(writer),
+            audio: audio.cloned(),
+            frames: 0,
+            error: None,
+        }));
+
+        let s = state.clone();
+        let subscription = self.on_frame(Box::new(move |_, _| s.borrow_mut().frame()));
+
+        Ok(FrameDump { state, subscription: Some(subscription) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn y4m_frame() {
+        let mut image = Image::new(2, 1);
+        image.set_pixel(0, 0, [255, 255, 255]);
+
+        let mut out = Vec::new();
+        write_y4m_frame(&mut out, &image).unwrap();
+        assert_eq!(out, b"FRAME\n\xEB\x10\x80\x80\x80\x80");
+    }
+}
@@ -0,0 +1,98 @@
+use crate::Error;
+use super::subscribers::{self, Subscribers};
+use super::Mupen;
+use mupen64plus_sys::*;
+use std::cell::RefCell;
+use std::os::raw::c_uint;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+/// Called with the frame number and whether it was a lag frame.
+pub type FrameCallback = Box<dyn FnMut(u32, bool)>;
+
+// thread_local is OK because the core calls back on the thread running Mupen::execute
+thread_local! {
+    static FRAME_SUBSCRIBERS: RefCell<Subscribers<dyn FnMut(u32, bool)>> = const { RefCell::new(Subscribers::new()) };
+}
+
+/// A callback registered with `Mupen::on_frame`. Dropping it removes the callback.
+#[must_use = "the callback is removed when the subscription is dropped"]
+#[derive(Debug)]
+pub struct FrameSubscription {
+    id: u64,
+}
+
+impl Drop for FrameSubscription {
+    fn drop(&mut self) {
+        // Already gone if the thread is exiting
+        let _ = FRAME_SUBSCRIBERS.try_with(|s| s.borrow_mut().unsubscribe(self.id));
+    }
+}
+
+static LAG_FRAMES: AtomicU32 = AtomicU32::new(0);
+static LAST_WAS_LAG: AtomicBool = AtomicBool::new(false);
+
+pub(super) fn clear_subscribers() {
+    FRAME_SUBSCRIBERS.with(|s| s.borrow_mut().clear());
+}
+
+extern "C" fn callback_frame(frame: c_uint) {
+    let lag = !crate::plugin::input::take_polled();
+    if lag {
+        LAG_FRAMES.fetch_add(1, Ordering::Relaxed);
+    }
+    LAST_WAS_LAG.store(lag, Ordering::Relaxed);
+
+    subscribers::notify(&FRAME_SUBSCRIBERS, |subscriber| subscriber(frame, lag));
+}
+
+impl Mupen {
+    /// Register the frame callback, which `Core::start` does.
+    pub(super) fn set_frame_callback(&self) -> Result<(), Error> {
+        LAG_FRAMES.store(0, Ordering::Relaxed);
+        LAST_WAS_LAG.store(false, Ordering::Relaxed);
+
+        let callback = callback_frame as extern "C" fn(c_uint);
+        let ret = unsafe {
+            self.core.core_do_command.unwrap()(
+                m64p_command_M64CMD_SET_FRAME_CALLBACK,
+                0,
+                callback as *mut std::os::raw::c_void,
+            )
+        };
+        if ret != m64p_error_M64ERR_SUCCESS {
+            Err(ret.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Call `callback` after each frame is drawn, with the frame number and whether it was a lag
+    /// frame (see `frame_was_lag`), until the returned subscription is dropped.
+    ///
+    /// Callbacks can call `on_frame` and drop subscriptions. A callback added from a callback is
+    /// first called on the next frame.
+    pub fn on_frame(&self, callback: FrameCallback) -> FrameSubscription {
+        FrameSubscription {
+            id: FRAME_SUBSCRIBERS.with(|s| s.borrow_mut().subscribe(callback)),
+        }
+    }
+
+    /// Whether the game didn't read the controllers during the last frame.
+    ///
+    /// Controller reads are only seen by input plugins from this crate that are built into the
+    /// frontend (see the [`builtin`](crate::plugin::builtin) module), such as `ScriptedInput`.
+    /// With any other input plugin, every frame looks like a lag frame.
+    pub fn frame_was_lag(&self) -> bool {
+        LAST_WAS_LAG.load(Ordering::Relaxed)
+    }
+
+    /// The number of lag frames (see `frame_was_lag`) since the core was started or
+    /// `reset_lag_count` was called.
+    pub fn lag_count(&self) -> u32 {
+        LAG_FRAMES.load(Ordering::Relaxed)
+    }
+
+    pub fn reset_lag_count(&self) {
+        LAG_FRAMES.store(0, Ordering::Relaxed);
+    }
+}
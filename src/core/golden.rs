@@ -0,0 +1,305 @@
+//! Regression tests that run a ROM for a number of frames and compare the result with stored
+//! golden files.
+//!
+//! Set up the `Mupen` as usual, with the ROM open and the plugins attached. The framebuffer is
+//! read from RDRAM (see `Screen::read_framebuffer`), so `Mupen::attach_headless_plugins` works,
+//! and a `ScriptedInput` can play input during the run:
+//!
+//! ```ignore
+//! let input = ScriptedInput::new(1);
+//! input.set_script(0, |poll| ControllerState { buttons: press_start(poll), ..Default::default() });
+//! // attach the plugins, with Plugin::builtin_input(input.clone())
+//!
+//! let test = GoldenTest::new("tests/golden", "title_screen", 300)
+//!     .bless(std::env::var_os("BLESS").is_some())
+//!     .require_golden(std::env::var_os("CI").is_some());
+//! let report = mupen.run_golden_test(&test)?;
+//! assert!(report.passed(), "{:?}", report);
+//! ```
+
+use crate::image::Image;
+use crate::Error;
+use super::Mupen;
+use mupen64plus_sys::*;
+use std::cell::RefCell;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// A golden-file test; see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GoldenTest {
+    dir: PathBuf,
+    name: String,
+    frames: u32,
+    bless: bool,
+    require_golden: bool,
+}
+
+impl GoldenTest {
+    /// A test that runs for `frames` frames (at least one), with golden files named after `name`
+    /// in `dir`: `<name>.png` for the framebuffer and `<name>.rdram` for the RDRAM hash.
+    pub fn new<P: Into<PathBuf>, S: Into<String>>(dir: P, name: S, frames: u32) -> Self {
+        GoldenTest { dir: dir.into(), name: name.into(), frames: frames.max(1), bless: false, require_golden: false }
+    }
+
+    /// Overwrite the golden files with the result instead of comparing with them. Golden files
+    /// that don't exist yet are always written.
+    pub fn bless(mut self, bless: bool) -> Self {
+        self.bless = bless;
+        self
+    }
+
+    /// Fail with `Error::Io` (`NotFound`) when a golden file doesn't exist, instead of writing
+    /// it, e.g. on CI where a written file would be thrown away. Ignored when blessing.
+    pub fn require_golden(mut self, require: bool) -> Self {
+        self.require_golden = require;
+        self
+    }
+
+    /// The error for a golden file that doesn't exist, or `None` if it may be written instead.
+    fn missing(&self, path: &Path) -> Option<Error> {
+        if self.require_golden && !self.bless {
+            let message = format!("golden file {} doesn't exist", path.display());
+            Some(io::Error::new(io::ErrorKind::NotFound, message).into())
+        } else {
+            None
+        }
+    }
+
+    fn path(&self, suffix: &str) -> PathBuf {
+        self.dir.join(format!("{}{}", self.name, suffix))
+    }
+}
+
+/// The result of `Mupen::run_golden_test`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GoldenReport {
+    /// The golden files were written rather than compared.
+    pub blessed: bool,
+    /// The number of pixels that differ from the golden framebuffer, or the number of pixels in
+    /// the framebuffer if the size differs.
+    pub differing_pixels: usize,
+    pub rdram_hash: u32,
+    pub golden_rdram_hash: u32,
+    /// On a framebuffer mismatch, an image with the differing pixels in red, written next to the
+    /// golden files as `<name>.diff.png` along with the framebuffer as `<name>.actual.png`. Both
+    /// are removed when the framebuffer matches again.
+    pub diff_path: Option<PathBuf>,
+}
+
+impl GoldenReport {
+    pub fn passed(&self) -> bool {
+        self.differing_pixels == 0 && self.rdram_hash == self.golden_rdram_hash
+    }
+}
+
+/// The state at the end of the run.
+struct Capture {
+    framebuffer: Image,
+    rdram_hash: u32,
+}
+
+fn rdram_hash(rdram: &[u32]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for word in rdram {
+        hasher.update(&word.to_le_bytes());
+    }
+    hasher.finalize()
+}
+
+/// Reads an RDRAM hash file, or returns `None` if it doesn't exist.
+fn read_hash(path: &Path) -> Result<Option<u32>, Error> {
+    let s = match std::fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match u32::from_str_radix(s.trim(), 16) {
+        Ok(hash) => Ok(Some(hash)),
+        Err(_) => {
+            let message = format!("{} doesn't hold an RDRAM hash", path.display());
+            Err(io::Error::new(io::ErrorKind::InvalidData, message).into())
+        }
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), Error> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Compares two images, returning the number of differing pixels and an image showing them in
+/// red over a darkened copy of `golden`.
+fn diff(golden: &Image, actual: &Image) -> (usize, Image) {
+    if (golden.width, golden.height) != (actual.width, actual.height) {
+        let mut image = Image::new(actual.width, actual.height);
+        image.rgb.chunks_exact_mut(3).for_each(|p| p.copy_from_slice(&[255, 0, 0]));
+        return (actual.rgb.len() / 3, image);
+    }
+
+    let mut image = Image::new(golden.width, golden.height);
+    let mut count = 0;
+    let pixels = golden.rgb.chunks_exact(3).zip(actual.rgb.chunks_exact(3));
+    for (out, (g, a)) in image.rgb.chunks_exact_mut(3).zip(pixels) {
+        if g == a {
+            let luma = (g[0] as u32 * 3 + g[1] as u32 * 6 + g[2] as u32) / 10 / 3;
+            out.copy_from_slice(&[luma as u8; 3]);
+        } else {
+            count += 1;
+            out.copy_from_slice(&[255, 0, 0]);
+        }
+    }
+    (count, image)
+}
+
+impl GoldenTest {
+    /// Compares `capture` with the golden files, writing any that are missing or being blessed.
+    fn compare(&self, capture: Capture) -> Result<GoldenReport, Error> {
+        std::fs::create_dir_all(&self.dir)?;
+        let mut blessed = false;
+
+        let hash_path = self.path(".rdram");
+        let golden_rdram_hash = match read_hash(&hash_path)? {
+            Some(hash) if !self.bless => hash,
+            _ => {
+                if let Some(e) = self.missing(&hash_path) {
+                    return Err(e);
+                }
+                std::fs::write(&hash_path, format!("{:08x}\n", capture.rdram_hash))?;
+                blessed = true;
+                capture.rdram_hash
+            }
+        };
+
+        let png_path = self.path(".png");
+        let golden = if png_path.exists() && !self.bless {
+            Image::load_png(&png_path)?
+        } else {
+            if let Some(e) = self.missing(&png_path) {
+                return Err(e);
+            }
+            capture.framebuffer.save_png(&png_path)?;
+            blessed = true;
+            capture.framebuffer.clone()
+        };
+
+        let (differing_pixels, diff_image) = diff(&golden, &capture.framebuffer);
+        let diff_path = if differing_pixels > 0 {
+            let diff_path = self.path(".diff.png");
+            diff_image.save_png(&diff_path)?;
+            capture.framebuffer.save_png(self.path(".actual.png"))?;
+            Some(diff_path)
+        } else {
+            remove_if_exists(&self.path(".diff.png"))?;
+            remove_if_exists(&self.path(".actual.png"))?;
+            None
+        };
+
+        Ok(GoldenReport {
+            blessed,
+            differing_pixels,
+            rdram_hash: capture.rdram_hash,
+            golden_rdram_hash,
+            diff_path,
+        })
+    }
+}
+
+type CaptureSlot = Rc<RefCell<Option<Result<Capture, Error>>>>;
+
+impl Mupen {
+    /// Runs the open ROM for `test`'s number of frames, blocking like `execute`, then compares
+    /// the framebuffer and a hash of RDRAM with the golden files.
+    pub fn run_golden_test(&self, test: &GoldenTest) -> Result<GoldenReport, Error> {
+        let capture: CaptureSlot = Rc::new(RefCell::new(None));
+
+        let core = self.core.clone();
+        let c = capture.clone();
+        let target = test.frames;
+        let mut frames = 0u32;
+        let subscription = self.on_frame(Box::new(move |_, _| {
+            frames = frames.saturating_add(1);
+            if frames != target {
+                return;
+            }
+
+            let result = core.read_framebuffer().and_then(|framebuffer| {
+                Ok(Capture { framebuffer, rdram_hash: rdram_hash(core.rdram()?) })
+            });
+            *c.borrow_mut() = Some(result);
+
+            unsafe {
+                core.core_do_command.unwrap()(m64p_command_M64CMD_STOP, 0, std::ptr::null_mut());
+            }
+        }));
+
+        let result = self.execute();
+        drop(subscription);
+        result?;
+
+        // Emulation stopped before reaching the frame
+        let capture = capture.borrow_mut().take().ok_or(Error::InvalidState)??;
+        test.compare(capture)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_diff() {
+        let mut golden = Image::new(2, 2);
+        golden.set_pixel(0, 0, [30, 30, 30]);
+        let mut actual = golden.clone();
+        assert_eq!(diff(&golden, &actual).0, 0);
+
+        actual.set_pixel(1, 1, [255, 255, 255]);
+        let (count, image) = diff(&golden, &actual);
+        assert_eq!(count, 1);
+        assert_eq!(image.pixel(0, 0), [10, 10, 10]);
+        assert_eq!(image.pixel(1, 1), [255, 0, 0]);
+
+        assert_eq!(diff(&golden, &Image::new(3, 2)).0, 6);
+    }
+
+    #[test]
+    fn bless_and_compare() {
+        let dir = std::env::temp_dir().join(format!("mupen64plus-golden-{}", std::process::id()));
+        let test = GoldenTest::new(&dir, "boot", 10);
+        let capture = |pixel| {
+            let mut framebuffer = Image::new(2, 1);
+            framebuffer.set_pixel(0, 0, pixel);
+            Capture { framebuffer, rdram_hash: rdram_hash(&[1, 2, 3]) }
+        };
+
+        let report = test.compare(capture([0, 0, 0])).unwrap();
+        assert!(report.blessed && report.passed());
+
+        let report = test.compare(capture([0, 0, 0])).unwrap();
+        assert!(!report.blessed && report.passed());
+
+        let report = test.compare(capture([255, 0, 0])).unwrap();
+        assert!(!report.passed());
+        assert_eq!(report.differing_pixels, 1);
+        assert_eq!(report.diff_path, Some(dir.join("boot.diff.png")));
+        assert!(dir.join("boot.actual.png").exists());
+
+        let report = test.compare(capture([0, 0, 0])).unwrap();
+        assert!(report.passed());
+        assert!(!dir.join("boot.diff.png").exists() && !dir.join("boot.actual.png").exists());
+
+        std::fs::write(dir.join("boot.rdram"), "not a hash").unwrap();
+        assert!(test.compare(capture([0, 0, 0])).is_err());
+
+        let required = GoldenTest::new(&dir, "new", 10).require_golden(true);
+        assert!(matches!(required.compare(capture([0, 0, 0])), Err(Error::Io(e)) if e.kind() == io::ErrorKind::NotFound));
+        assert!(!dir.join("new.rdram").exists());
+        assert!(required.bless(true).compare(capture([0, 0, 0])).unwrap().blessed);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
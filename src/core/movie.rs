@@ -0,0 +1,457 @@
+use crate::movie::{Movie, MovieError, MovieStart};
+use crate::plugin::input::scripted::ScriptedInput;
+use crate::plugin::input::{ControllerConfig, ControllerState, InputPlugin, Pak};
+use crate::plugin::{Plugin, PluginInfo, Version};
+use crate::Error;
+use super::state::{self, StateSubscription};
+use super::{Core, FrameSubscription, Mupen};
+use mupen64plus_sys::*;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MovieMode {
+    /// Input comes from the live controllers.
+    Inactive,
+    /// Input comes from the live controllers and is added to the movie.
+    Recording,
+    /// Input comes from the movie, until it ends.
+    Playing,
+}
+
+/// A savestate the core hasn't finished saving or loading yet.
+enum PendingState {
+    /// Where to keep the movie once the savestate is saved.
+    Save(PathBuf),
+    /// The movie kept with the savestate being loaded.
+    Load(Movie),
+}
+
+struct MovieState {
+    mode: MovieMode,
+    movie: Movie,
+    /// The input poll being recorded or played.
+    sample: usize,
+    pending: Option<PendingState>,
+}
+
+impl MovieState {
+    fn new(mode: MovieMode, movie: Movie) -> Self {
+        MovieState { mode, movie, sample: 0, pending: None }
+    }
+
+    /// The movie up to the current input poll.
+    fn movie_so_far(&self) -> Movie {
+        let mut movie = self.movie.clone();
+        movie.input.truncate(self.sample);
+        movie
+    }
+
+    /// Finishes `save_state` or `load_state` when the core reports the savestate was saved or
+    /// loaded.
+    #[allow(non_upper_case_globals)]
+    fn state_changed(&mut self, param: m64p_core_param, success: bool) {
+        let pending = match (param, self.pending.take()) {
+            (m64p_core_param_M64CORE_STATE_SAVECOMPLETE, Some(PendingState::Save(path))) => {
+                PendingState::Save(path)
+            }
+            (m64p_core_param_M64CORE_STATE_LOADCOMPLETE, Some(PendingState::Load(saved))) => {
+                PendingState::Load(saved)
+            }
+            (_, pending) => {
+                self.pending = pending;
+                return;
+            }
+        };
+        if !success || self.mode == MovieMode::Inactive {
+            return;
+        }
+
+        match pending {
+            PendingState::Save(path) => {
+                if let Err(e) = self.movie_so_far().save(&path) {
+                    log::error!("failed to save movie for savestate to {:?}: {}", path, e);
+                }
+            }
+            PendingState::Load(saved) => {
+                if self.mode == MovieMode::Recording {
+                    self.movie.vi_count = saved.vi_count;
+                    self.movie.input = saved.input;
+                    self.movie.rerecords += 1;
+                    self.sample = self.movie.input.len();
+                } else {
+                    self.sample = saved.input.len();
+                }
+            }
+        }
+    }
+
+    /// Moves on to the next sample once the last present controller has been read.
+    fn polled(&mut self, controller: usize) {
+        let last = (0..4).rev().find(|&i| self.movie.controllers[i].present);
+        if last == Some(controller) {
+            self.sample += 1;
+        }
+    }
+}
+
+/// The input plugin used for movies. It reads from the live controllers, except during
+/// playback.
+#[derive(Clone)]
+struct MovieInput {
+    state: Arc<Mutex<MovieState>>,
+    live: ScriptedInput,
+}
+
+impl MovieInput {
+    fn lock(&self) -> MutexGuard<'_, MovieState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl PluginInfo for MovieInput {
+    const NAME: &'static str = "Movie Input";
+    const VERSION: Version = Version::new(1, 0, 0);
+}
+
+impl InputPlugin for MovieInput {
+    fn initiate_controllers(&mut self) -> [ControllerConfig; 4] {
+        let state = self.lock();
+        if state.mode != MovieMode::Playing {
+            drop(state);
+            return self.live.initiate_controllers();
+        }
+
+        let mut configs = [ControllerConfig::default(); 4];
+        for (config, controller) in configs.iter_mut().zip(state.movie.controllers.iter()) {
+            config.present = controller.present;
+            config.pak = if controller.mempak {
+                Pak::MemPak
+            } else if controller.rumble {
+                Pak::RumblePak
+            } else {
+                Pak::None
+            };
+        }
+        configs
+    }
+
+    fn get_keys(&mut self, controller: usize) -> ControllerState {
+        if controller >= 4 {
+            return ControllerState::default();
+        }
+
+        {
+            let mut state = self.lock();
+            if state.mode == MovieMode::Playing {
+                if let Some(sample) = state.movie.input.get(state.sample) {
+                    let keys = sample[controller];
+                    state.polled(controller);
+                    return keys;
+                }
+                log::info!("movie finished after {} input polls", state.sample);
+                state.mode = MovieMode::Inactive;
+            }
+        }
+
+        // The live input is read with the lock released, as its scripts may use it
+        let keys = self.live.get_keys(controller);
+
+        let mut state = self.lock();
+        if state.mode == MovieMode::Recording && state.movie.controllers[controller].present {
+            let sample = state.sample;
+            if state.movie.input.len() <= sample {
+                state.movie.input.resize(sample + 1, Default::default());
+            }
+            state.movie.input[sample][controller] = keys;
+            state.polled(controller);
+        }
+        keys
+    }
+}
+
+/// Handle for recording and playing back movies, returned by `Mupen::attach_movie_input`. Uses
+/// reference-counting for cheap cloning (e.g. passing to closures).
+///
+/// Savestates made with `save_state` keep the movie up to that point next to them, so loading
+/// one while recording rewinds the movie and counts a rerecord.
+#[derive(Clone)]
+pub struct MovieControl {
+    core: Rc<Core>,
+    input: MovieInput,
+    /// Counts frames while recording and follows savestates, until the last clone is dropped.
+    _subscriptions: Rc<(FrameSubscription, StateSubscription)>,
+}
+
+impl Mupen {
+    /// Attach the input plugin used for recording and playing back movies, in place of another
+    /// input plugin. This is a built-in plugin, so the frontend must use
+    /// `export_builtin_plugins!`; see the [`builtin`](crate::plugin::builtin) module.
+    ///
+    /// `controllers` live controllers are connected, which are read through
+    /// `MovieControl::live_input` when no movie is playing.
+    pub fn attach_movie_input(&mut self, controllers: usize) -> Result<MovieControl, Error> {
+        let input = MovieInput {
+            state: Arc::new(Mutex::new(MovieState::new(MovieMode::Inactive, Movie::default()))),
+            live: ScriptedInput::new(controllers),
+        };
+
+        let plugin = Plugin::builtin_input(input.clone()).map_err(|_| Error::SystemFail)?;
+        self.attach_plugin(plugin)?;
+
+        // Count the length of recordings in frames
+        let state = input.state.clone();
+        let frames = self.on_frame(Box::new(move |_, _| {
+            let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+            if state.mode == MovieMode::Recording {
+                state.movie.vi_count += 1;
+            }
+        }));
+
+        let state = input.state.clone();
+        let states = state::on_state_change(Box::new(move |param, value| {
+            state.lock().unwrap_or_else(|e| e.into_inner()).state_changed(param, value != 0);
+        }));
+
+        Ok(MovieControl {
+            core: self.core.clone(),
+            input,
+            _subscriptions: Rc::new((frames, states)),
+        })
+    }
+}
+
+/// Where `MovieControl::save_state` keeps the movie for a savestate.
+fn movie_state_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".m64");
+    PathBuf::from(path)
+}
+
+impl MovieControl {
+    fn state_command(&self, command: m64p_command, param: i32, path: &Path) -> Result<(), Error> {
+        let path = CString::new(path.to_string_lossy().into_owned()).map_err(|_| Error::InputInvalid)?;
+        let ret = unsafe {
+            self.core.core_do_command.unwrap()(command, param, path.as_ptr() as *mut std::os::raw::c_void)
+        };
+        if ret != m64p_error_M64ERR_SUCCESS {
+            Err(ret.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The controllers that are recorded, and used when no movie is playing.
+    pub fn live_input(&self) -> &ScriptedInput {
+        &self.input.live
+    }
+
+    pub fn mode(&self) -> MovieMode {
+        self.input.lock().mode
+    }
+
+    /// The input poll being recorded or played back.
+    pub fn sample(&self) -> usize {
+        self.input.lock().sample
+    }
+
+    /// A copy of the movie being recorded or played back.
+    pub fn movie(&self) -> Movie {
+        self.input.lock().movie.clone()
+    }
+
+    /// Start recording a movie, replacing any being recorded or played. `movie` gives the
+    /// metadata (author, description, plugin names and so on); the ROM details and controllers
+    /// are filled in.
+    ///
+    /// With a `snapshot` path, the movie starts from a savestate saved there, normally
+    /// `Movie::snapshot_path`. Otherwise it starts from power-on, so this should be called before
+    /// `Mupen::execute`.
+    pub fn record(&self, mut movie: Movie, snapshot: Option<&Path>) -> Result<(), Error> {
+        let mut header = std::mem::MaybeUninit::<m64p_rom_header>::zeroed();
+        let ret = unsafe {
+            self.core.core_do_command.unwrap()(
+                m64p_command_M64CMD_ROM_GET_HEADER,
+                std::mem::size_of::<m64p_rom_header>() as i32,
+                header.as_mut_ptr() as *mut std::os::raw::c_void,
+            )
+        };
+        if ret != m64p_error_M64ERR_SUCCESS {
+            return Err(ret.into());
+        }
+        let header = unsafe { header.assume_init() };
+
+        // The header is stored as it is in the ROM, so its words are big-endian
+        movie.rom_name = String::from_utf8_lossy(&header.Name).trim_end_matches(['\0', ' '].as_ref()).to_owned();
+        movie.rom_crc = u32::from_be(header.CRC1);
+        movie.rom_country = header.Country_code;
+
+        let mut live = self.input.live.clone();
+        for (controller, config) in movie.controllers.iter_mut().zip(live.initiate_controllers().iter()) {
+            controller.present = config.present;
+        }
+        movie.input.clear();
+        movie.vi_count = 0;
+        movie.rerecords = 0;
+
+        if let Some(snapshot) = snapshot {
+            movie.start = MovieStart::Snapshot;
+            self.state_command(m64p_command_M64CMD_STATE_SAVE, 1, snapshot)?;
+        } else if movie.start == MovieStart::Snapshot {
+            movie.start = MovieStart::PowerOn;
+        }
+
+        *self.input.lock() = MovieState::new(MovieMode::Recording, movie);
+        Ok(())
+    }
+
+    /// Start playing back a movie, replacing any being recorded or played. Movies that start
+    /// from a savestate need its path, normally `Movie::snapshot_path`; others start from
+    /// power-on, so this should be called before `Mupen::execute`.
+    pub fn play(&self, movie: Movie, snapshot: Option<&Path>) -> Result<(), Error> {
+        if movie.start == MovieStart::Snapshot {
+            match snapshot {
+                Some(snapshot) => self.state_command(m64p_command_M64CMD_STATE_LOAD, 0, snapshot)?,
+                None => return Err(Error::InputInvalid),
+            }
+        }
+
+        *self.input.lock() = MovieState::new(MovieMode::Playing, movie);
+        Ok(())
+    }
+
+    /// Stop recording or playing back, returning the movie.
+    pub fn stop(&self) -> Option<Movie> {
+        let mut state = self.input.lock();
+        if state.mode == MovieMode::Inactive {
+            return None;
+        }
+
+        state.mode = MovieMode::Inactive;
+        state.pending = None;
+        Some(state.movie_so_far())
+    }
+
+    /// Save a savestate, keeping the movie up to it at `path` plus `.m64`. The core saves at the
+    /// end of the current frame, and the movie is written once it has.
+    pub fn save_state<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        {
+            let mut state = self.input.lock();
+            if state.mode != MovieMode::Inactive {
+                state.pending = Some(PendingState::Save(movie_state_path(path)));
+            }
+        }
+        self.state_command(m64p_command_M64CMD_STATE_SAVE, 1, path)
+            .map_err(|e| self.cancel_pending(e))
+    }
+
+    /// Load a savestate made with `save_state`. Once the core has loaded it, the movie goes back
+    /// to where the savestate was made and the rerecord count goes up while recording; while
+    /// playing, playback continues from there. The movie is unchanged if the load fails.
+    pub fn load_state<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        {
+            let mut state = self.input.lock();
+            if state.mode != MovieMode::Inactive {
+                let saved = Movie::load(movie_state_path(path))?;
+                if saved.uid != state.movie.uid {
+                    log::warn!("savestate {:?} was made during a different movie", path);
+                }
+                if state.mode == MovieMode::Playing && saved.input.len() > state.movie.input.len() {
+                    return Err(MovieError::Truncated.into());
+                }
+                state.pending = Some(PendingState::Load(saved));
+            }
+        }
+        self.state_command(m64p_command_M64CMD_STATE_LOAD, 0, path)
+            .map_err(|e| self.cancel_pending(e))
+    }
+
+    fn cancel_pending(&self, e: Error) -> Error {
+        self.input.lock().pending = None;
+        e
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::input::Buttons;
+
+    #[test]
+    fn record_and_play() {
+        let input = MovieInput {
+            state: Arc::new(Mutex::new(MovieState::new(MovieMode::Recording, Movie::default()))),
+            live: ScriptedInput::new(2),
+        };
+        let mut plugin = input.clone();
+        {
+            let mut state = input.lock();
+            state.movie.controllers[0].present = true;
+            state.movie.controllers[1].present = true;
+        }
+
+        input.live.set_script(1, |poll| ControllerState { x: poll as i8, ..Default::default() });
+        input.live.set_state(0, ControllerState { buttons: Buttons::Z, ..Default::default() });
+        for _ in 0..3 {
+            plugin.get_keys(0);
+            plugin.get_keys(1);
+        }
+
+        let movie = {
+            let mut state = input.lock();
+            assert_eq!(state.sample, 3);
+            state.mode = MovieMode::Playing;
+            state.sample = 1;
+            state.movie.clone()
+        };
+        assert_eq!(movie.input.len(), 3);
+        assert_eq!(movie.input[2][1].x, 2);
+
+        input.live.set_state(0, ControllerState::default());
+        assert_eq!(plugin.get_keys(0).buttons, Buttons::Z);
+        assert_eq!(plugin.get_keys(1).x, 1);
+        plugin.get_keys(0);
+        plugin.get_keys(1);
+
+        // The movie has ended, so the live input takes over
+        assert_eq!(plugin.get_keys(0).buttons, Buttons::empty());
+        assert_eq!(input.lock().mode, MovieMode::Inactive);
+    }
+
+    #[test]
+    fn savestates_apply_when_complete() {
+        let mut state = MovieState::new(MovieMode::Recording, Movie::default());
+        state.movie.controllers[0].present = true;
+        state.movie.input = vec![Default::default(); 5];
+        state.movie.vi_count = 10;
+        state.sample = 5;
+
+        let mut saved = Movie::default();
+        saved.input = vec![Default::default(); 2];
+        saved.vi_count = 4;
+
+        // Nothing changes until the load completes, or if it fails
+        state.pending = Some(PendingState::Load(saved.clone()));
+        state.state_changed(m64p_core_param_M64CORE_STATE_SAVECOMPLETE, true);
+        assert_eq!(state.sample, 5);
+        state.state_changed(m64p_core_param_M64CORE_STATE_LOADCOMPLETE, false);
+        assert_eq!((state.sample, state.movie.rerecords), (5, 0));
+        assert!(state.pending.is_none());
+
+        state.pending = Some(PendingState::Load(saved));
+        state.state_changed(m64p_core_param_M64CORE_STATE_LOADCOMPLETE, true);
+        assert_eq!((state.sample, state.movie.vi_count, state.movie.rerecords), (2, 4, 1));
+
+        // The movie is written once the save completes
+        let path = std::env::temp_dir().join(format!("mupen64plus-movie-{}.st.m64", std::process::id()));
+        state.pending = Some(PendingState::Save(path.clone()));
+        assert!(!path.exists());
+        state.state_changed(m64p_core_param_M64CORE_STATE_SAVECOMPLETE, true);
+        assert_eq!(Movie::load(&path).unwrap().input.len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
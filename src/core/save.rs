@@ -0,0 +1,181 @@
+use crate::save::{SaveData, SaveError, SaveKind};
+use crate::Error;
+use super::Mupen;
+use mupen64plus_sys::*;
+use std::ffi::CStr;
+use std::path::{Path, PathBuf};
+
+/// The core's `SaveFilenameFormat` setting for the header name alone.
+const SAVE_FILENAME_HEADER_NAME: i32 = 0;
+
+/// Names save files the way the core's `get_save_filename` does. `goodname` and `md5` are from
+/// the ROM settings, `header_name` is the name field of the ROM header.
+fn save_file_name(format: i32, goodname: &[u8], md5: &[u8], header_name: &[u8]) -> String {
+    // The core trims whitespace from the header name and falls back to "unknown" if nothing is
+    // left
+    let header_name = header_name.split(|&b| b == 0).next().unwrap_or_default();
+    let header_name = String::from_utf8_lossy(header_name);
+    let header_name = match header_name.trim() {
+        "" => "unknown",
+        name => name,
+    };
+    let md5 = String::from_utf8_lossy(&md5[..md5.len().min(8)]);
+
+    if format == SAVE_FILENAME_HEADER_NAME {
+        header_name.to_string()
+    } else if goodname.windows(13).any(|w| w == b"(unknown rom)") {
+        format!("{}-{}", header_name, md5)
+    } else {
+        // "%.32s-%.8s"
+        format!("{}-{}", String::from_utf8_lossy(&goodname[..goodname.len().min(32)]), md5)
+    }
+}
+
+impl Mupen {
+    /// The directory the core keeps save files in: `SaveSRAMPath` from the `Core` config
+    /// section, or `save/` in the user data directory if that is empty.
+    pub fn save_directory(&self) -> Result<PathBuf, Error> {
+        unsafe {
+            let mut core_config = std::ptr::null_mut();
+
+            let ret = self.core.config_open_section.unwrap()(
+                CStr::from_bytes_with_nul_unchecked(b"Core\0").as_ptr(),
+                &mut core_config,
+            );
+            if ret != m64p_error_M64ERR_SUCCESS {
+                return Err(ret.into());
+            }
+
+            let path = self.core.config_get_param_string.unwrap()(
+                core_config,
+                CStr::from_bytes_with_nul_unchecked(b"SaveSRAMPath\0").as_ptr(),
+            );
+            if !path.is_null() && *path != 0 {
+                return Ok(PathBuf::from(CStr::from_ptr(path).to_string_lossy().into_owned()));
+            }
+
+            let data_path = self.core.config_get_user_data_path.unwrap()();
+            if data_path.is_null() {
+                return Err(Error::Files);
+            }
+            Ok(PathBuf::from(CStr::from_ptr(data_path).to_string_lossy().into_owned()).join("save"))
+        }
+    }
+
+    /// The name the core gives the open ROM's save files, before the extension. By default
+    /// this is the ROM's goodname and MD5, e.g. `Super Mario 64 (U) [!]-20B85441`, following the
+    /// core's `SaveFilenameFormat` setting.
+    pub fn save_name(&self) -> Result<String, Error> {
+        if !self.is_rom_open() {
+            return Err(Error::NoRomOpen);
+        }
+
+        let mut settings = std::mem::MaybeUninit::<m64p_rom_settings>::zeroed();
+        let ret = unsafe {
+            self.core.core_do_command.unwrap()(
+                m64p_command_M64CMD_ROM_GET_SETTINGS,
+                std::mem::size_of::<m64p_rom_settings>() as i32,
+                settings.as_mut_ptr() as *mut std::os::raw::c_void,
+            )
+        };
+        if ret != m64p_error_M64ERR_SUCCESS {
+            return Err(ret.into());
+        }
+        let settings = unsafe { settings.assume_init() };
+        let header = self.rom_header()?;
+
+        let format = unsafe {
+            let mut core_config = std::ptr::null_mut();
+            let ret = self.core.config_open_section.unwrap()(
+                CStr::from_bytes_with_nul_unchecked(b"Core\0").as_ptr(),
+                &mut core_config,
+            );
+            if ret != m64p_error_M64ERR_SUCCESS {
+                return Err(ret.into());
+            }
+            self.core.config_get_param_int.unwrap()(
+                core_config,
+                CStr::from_bytes_with_nul_unchecked(b"SaveFilenameFormat\0").as_ptr(),
+            )
+        };
+
+        let goodname = unsafe { CStr::from_ptr(settings.goodname.as_ptr()) }.to_bytes();
+        let md5 = unsafe { CStr::from_ptr(settings.MD5.as_ptr()) }.to_bytes();
+        Ok(save_file_name(format, goodname, md5, &header.Name))
+    }
+
+    /// The open ROM's header, as a copy of the big-endian ROM bytes.
+    pub(super) fn rom_header(&self) -> Result<m64p_rom_header, Error> {
+        let mut header = std::mem::MaybeUninit::<m64p_rom_header>::zeroed();
+        let ret = unsafe {
+            self.core.core_do_command.unwrap()(
+                m64p_command_M64CMD_ROM_GET_HEADER,
+                std::mem::size_of::<m64p_rom_header>() as i32,
+                header.as_mut_ptr() as *mut std::os::raw::c_void,
+            )
+        };
+        if ret != m64p_error_M64ERR_SUCCESS {
+            return Err(ret.into());
+        }
+        Ok(unsafe { header.assume_init() })
+    }
+
+    /// Where the core reads and writes the open ROM's save of the given kind.
+    pub fn save_path(&self, kind: SaveKind) -> Result<PathBuf, Error> {
+        Ok(self.save_directory()?.join(format!("{}.{}", self.save_name()?, kind.extension())))
+    }
+
+    /// Export the open ROM's saves.
+    pub fn read_saves(&self) -> Result<SaveData, Error> {
+        Ok(SaveData::load(self.save_directory()?, &self.save_name()?)?)
+    }
+
+    /// Import saves for the open ROM, replacing its existing save files. The core reads saves
+    /// when the game first accesses them, so this should be called before `execute`.
+    pub fn write_saves(&self, saves: &SaveData) -> Result<(), Error> {
+        Ok(saves.save(self.save_directory()?, &self.save_name()?)?)
+    }
+
+    /// Copy the open ROM's save files to `dir` as they are, returning the paths of the copies.
+    pub fn backup_saves<P: AsRef<Path>>(&self, dir: P) -> Result<Vec<PathBuf>, Error> {
+        let save_dir = self.save_directory()?;
+        let name = self.save_name()?;
+        let mut copies = Vec::new();
+
+        std::fs::create_dir_all(dir.as_ref()).map_err(SaveError::from)?;
+        for &kind in SaveKind::ALL.iter() {
+            let file = format!("{}.{}", name, kind.extension());
+            let from = save_dir.join(&file);
+
+            if from.is_file() {
+                let to = dir.as_ref().join(&file);
+                std::fs::copy(&from, &to).map_err(SaveError::from)?;
+                copies.push(to);
+            }
+        }
+
+        Ok(copies)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_file_names() {
+        let md5 = b"20B854B239203BAF6C961B850A4A51A2";
+        let header = b"SUPER MARIO 64      ";
+
+        assert_eq!(save_file_name(1, b"Super Mario 64 (U) [!]", md5, header), "Super Mario 64 (U) [!]-20B854B2");
+        assert_eq!(
+            save_file_name(1, b"A Very Long Goodname That Gets Truncated (U)", md5, header),
+            "A Very Long Goodname That Gets T-20B854B2"
+        );
+        assert_eq!(save_file_name(0, b"Super Mario 64 (U) [!]", md5, header), "SUPER MARIO 64");
+
+        // ROMs that aren't in the ROM database
+        assert_eq!(save_file_name(1, b"SUPER MARIO 64 (unknown rom)", md5, header), "SUPER MARIO 64-20B854B2");
+        assert_eq!(save_file_name(1, b" (unknown rom)", md5, &[b' '; 20]), "unknown-20B854B2");
+    }
+}
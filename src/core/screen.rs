@@ -0,0 +1,198 @@
+use crate::image::Image;
+use crate::plugin::RDRAM_SIZE;
+use crate::Error;
+use super::{Core, Mupen};
+use mupen64plus_sys::*;
+use std::os::raw::c_int;
+use std::rc::Rc;
+
+impl Core {
+    /// The size of the video plugin's output, from `M64CORE_VIDEO_SIZE`.
+    fn video_size(&self) -> Result<(u32, u32), Error> {
+        let mut size: c_int = 0;
+        let ret = unsafe {
+            self.core_do_command.unwrap()(
+                m64p_command_M64CMD_CORE_STATE_QUERY,
+                m64p_core_param_M64CORE_VIDEO_SIZE as c_int,
+                &mut size as *mut c_int as *mut _,
+            )
+        };
+        if ret != m64p_error_M64ERR_SUCCESS {
+            Err(ret.into())
+        } else {
+            Ok(((size as u32) >> 16, size as u32 & 0xFFFF))
+        }
+    }
+
+    /// Reads the video plugin's output with `M64CMD_READ_SCREEN`.
+    fn read_screen(&self, front: bool) -> Result<Image, Error> {
+        let (width, height) = self.video_size()?;
+        let mut image = Image::new(width, height);
+        if image.is_empty() {
+            return Ok(image);
+        }
+
+        let ret = unsafe {
+            self.core_do_command.unwrap()(
+                m64p_command_M64CMD_READ_SCREEN,
+                front as c_int,
+                image.rgb.as_mut_ptr() as *mut _,
+            )
+        };
+        if ret != m64p_error_M64ERR_SUCCESS {
+            return Err(ret.into());
+        }
+
+        // The plugin writes the bottom row first
+        image.flip_vertical();
+        Ok(image)
+    }
+
+    /// A pointer from `DebugMemGetPointer`.
+    fn mem_pointer(&self, memory: m64p_dbg_memptr_type) -> Result<*const u32, Error> {
+        let get_pointer = self.debug_mem_get_pointer.ok_or(Error::Unsupported)?;
+        let ptr = unsafe { get_pointer(memory) } as *const u32;
+        if ptr.is_null() {
+            Err(Error::NotInit)
+        } else {
+            Ok(ptr)
+        }
+    }
+
+    /// RDRAM as the core stores it, in native-endian words.
+    pub(super) fn rdram(&self) -> Result<&[u32], Error> {
+        let rdram = self.mem_pointer(m64p_dbg_memptr_type_M64P_DBG_PTR_RDRAM)?;
+        Ok(unsafe { std::slice::from_raw_parts(rdram, RDRAM_SIZE / 4) })
+    }
+
+    /// Decodes the framebuffer the VI is displaying from RDRAM.
+    pub(super) fn read_framebuffer(&self) -> Result<Image, Error> {
+        let vi = self.mem_pointer(m64p_dbg_memptr_type_M64P_DBG_PTR_VI_REG)?;
+        let vi = unsafe { &*(vi as *const [u32; VI_REGS]) };
+        Ok(decode_framebuffer(vi, self.rdram()?))
+    }
+}
+
+/// The number of VI registers, in the order `DebugMemGetPointer` gives them.
+const VI_REGS: usize = 14;
+const VI_CONTROL: usize = 0;
+const VI_ORIGIN: usize = 1;
+const VI_WIDTH: usize = 2;
+const VI_H_START: usize = 9;
+const VI_V_START: usize = 10;
+const VI_X_SCALE: usize = 12;
+const VI_Y_SCALE: usize = 13;
+
+/// Decodes the framebuffer described by the VI registers. `rdram` is in words, as the core
+/// stores it. Pixels outside of RDRAM are black.
+fn decode_framebuffer(vi: &[u32; VI_REGS], rdram: &[u32]) -> Image {
+    let bytes_per_pixel = match vi[VI_CONTROL] & 3 {
+        2 => 2,
+        3 => 4,
+        // Blank
+        _ => return Image::default(),
+    };
+
+    let origin = vi[VI_ORIGIN] & 0x00FF_FFFF;
+    let stride = vi[VI_WIDTH] & 0xFFF;
+    let x_scale = vi[VI_X_SCALE] & 0xFFF;
+    let y_scale = vi[VI_Y_SCALE] & 0xFFF;
+    let (h_start, h_end) = ((vi[VI_H_START] >> 16) & 0x3FF, vi[VI_H_START] & 0x3FF);
+    let (v_start, v_end) = ((vi[VI_V_START] >> 16) & 0x3FF, vi[VI_V_START] & 0x3FF);
+
+    // The scales are how far the VI moves through the framebuffer per output pixel (as 2.10
+    // fixed point), and the vertical range is in half-lines, so this is the displayed area of the
+    // framebuffer
+    let width = h_end.saturating_sub(h_start) * x_scale / 0x400;
+    let height = (v_end.saturating_sub(v_start) / 2) * y_scale / 0x400;
+    let mut image = Image::new(width, height);
+
+    for y in 0..height {
+        for x in 0..width {
+            let addr = (origin + (y * stride + x) * bytes_per_pixel) as usize;
+            let word = match rdram.get(addr / 4) {
+                Some(&word) => word,
+                None => continue,
+            };
+
+            let rgb = if bytes_per_pixel == 2 {
+                let pixel = if addr & 2 == 0 { word >> 16 } else { word & 0xFFFF };
+                let expand = |c: u32| ((c << 3) | (c >> 2)) as u8;
+                [expand((pixel >> 11) & 0x1F), expand((pixel >> 6) & 0x1F), expand((pixel >> 1) & 0x1F)]
+            } else {
+                [(word >> 24) as u8, (word >> 16) as u8, (word >> 8) as u8]
+            };
+            image.set_pixel(x, y, rgb);
+        }
+    }
+    image
+}
+
+/// Handle for reading the video plugin's output. Uses reference-counting for cheap cloning (e.g.
+/// passing to frame callbacks).
+#[derive(Clone)]
+pub struct Screen {
+    core: Rc<Core>,
+}
+
+impl Screen {
+    /// The size of the image `read` returns. Video plugins that don't draw anything report 0x0.
+    pub fn size(&self) -> Result<(u32, u32), Error> {
+        self.core.video_size()
+    }
+
+    /// A screenshot of the video plugin's front or back buffer. Only works while emulation is
+    /// running, so call it from a frame callback (see `Mupen::on_frame`). The image is empty if
+    /// the video plugin doesn't draw anything; use `read_framebuffer` then.
+    pub fn read(&self, front: bool) -> Result<Image, Error> {
+        self.core.read_screen(front)
+    }
+
+    /// The framebuffer the game is displaying, decoded from RDRAM using the VI registers. Works
+    /// with any video plugin, including ones that don't draw anything, but misses anything a
+    /// plugin only renders on the GPU. The image is empty while the VI is blank.
+    pub fn read_framebuffer(&self) -> Result<Image, Error> {
+        self.core.read_framebuffer()
+    }
+}
+
+impl Mupen {
+    pub fn screen(&self) -> Screen {
+        Screen { core: self.core.clone() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn framebuffer_16bit() {
+        let mut vi = [0u32; VI_REGS];
+        vi[VI_CONTROL] = 2;
+        vi[VI_ORIGIN] = 0x100;
+        vi[VI_WIDTH] = 4;
+        vi[VI_H_START] = (0x6C << 16) | (0x6C + 8);
+        vi[VI_V_START] = (0x25 << 16) | (0x25 + 4);
+        vi[VI_X_SCALE] = 0x200;
+        vi[VI_Y_SCALE] = 0x400;
+
+        let mut rdram = vec![0u32; 0x100];
+        // Red and green, then blue and white
+        rdram[0x40] = 0xF800_07C0;
+        rdram[0x41] = 0x003E_FFFF;
+        rdram[0x42] = 0xFFFF_0000;
+
+        let image = decode_framebuffer(&vi, &rdram);
+        assert_eq!((image.width, image.height), (4, 2));
+        assert_eq!(image.pixel(0, 0), [255, 0, 0]);
+        assert_eq!(image.pixel(1, 0), [0, 255, 0]);
+        assert_eq!(image.pixel(2, 0), [0, 0, 255]);
+        assert_eq!(image.pixel(3, 0), [255, 255, 255]);
+        assert_eq!(image.pixel(0, 1), [255, 255, 255]);
+        assert_eq!(image.pixel(2, 1), [0, 0, 0]);
+
+        vi[VI_CONTROL] = 0;
+        assert!(decode_framebuffer(&vi, &rdram).is_empty());
+    }
+}
@@ -0,0 +1,41 @@
+use super::subscribers::{self, Subscribers};
+use mupen64plus_sys::*;
+use std::cell::RefCell;
+use std::os::raw::{c_int, c_void};
+
+/// Called with the core parameter that changed and its new value.
+pub(super) type StateCallback = Box<dyn FnMut(m64p_core_param, i32)>;
+
+// thread_local is OK because the core calls back on the thread running Mupen::execute, or the
+// one sending the command that changed the state
+thread_local! {
+    static STATE_SUBSCRIBERS: RefCell<Subscribers<dyn FnMut(m64p_core_param, i32)>> = const { RefCell::new(Subscribers::new()) };
+}
+
+pub(super) fn clear_subscribers() {
+    STATE_SUBSCRIBERS.with(|s| s.borrow_mut().clear());
+}
+
+/// The state callback given to `CoreStartup`.
+pub(super) unsafe extern "C" fn callback_state(_context: *mut c_void, param: m64p_core_param, value: c_int) {
+    subscribers::notify(&STATE_SUBSCRIBERS, |subscriber| subscriber(param, value));
+}
+
+/// A callback registered with `on_state_change`. Dropping it removes the callback.
+pub(super) struct StateSubscription {
+    id: u64,
+}
+
+impl Drop for StateSubscription {
+    fn drop(&mut self) {
+        let _ = STATE_SUBSCRIBERS.try_with(|s| s.borrow_mut().unsubscribe(self.id));
+    }
+}
+
+/// Calls `callback` when the core reports a change in its state, such as a savestate finishing
+/// (`M64CORE_STATE_SAVECOMPLETE`, with 1 for success).
+pub(super) fn on_state_change(callback: StateCallback) -> StateSubscription {
+    StateSubscription {
+        id: STATE_SUBSCRIBERS.with(|s| s.borrow_mut().subscribe(callback)),
+    }
+}
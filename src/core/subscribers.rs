@@ -0,0 +1,121 @@
+//! Lists of callbacks for the core's callbacks, which may be added to and removed from while
+//! they run.
+
+use std::cell::RefCell;
+use std::thread::LocalKey;
+
+pub(super) struct Subscribers<F: ?Sized> {
+    next_id: u64,
+    callbacks: Vec<(u64, Box<F>)>,
+    /// While the callbacks run they are taken out of `callbacks`, and this holds the ones
+    /// removed in the meantime.
+    removed: Option<Vec<u64>>,
+    /// Set if `clear` was called while the callbacks ran.
+    cleared: bool,
+}
+
+impl<F: ?Sized> Subscribers<F> {
+    pub(super) const fn new() -> Self {
+        Subscribers {
+            next_id: 0,
+            callbacks: Vec::new(),
+            removed: None,
+            cleared: false,
+        }
+    }
+
+    /// Adds a callback, returning its id for `unsubscribe`.
+    pub(super) fn subscribe(&mut self, callback: Box<F>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.callbacks.push((id, callback));
+        id
+    }
+
+    pub(super) fn unsubscribe(&mut self, id: u64) {
+        self.callbacks.retain(|(i, _)| *i != id);
+        if let Some(removed) = &mut self.removed {
+            removed.push(id);
+        }
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.callbacks.clear();
+        self.cleared = self.removed.is_some();
+    }
+
+    fn is_removed(&self, id: u64) -> bool {
+        self.cleared || self.removed.as_ref().is_some_and(|removed| removed.contains(&id))
+    }
+}
+
+/// Calls each callback in `subscribers` with `call`. The list isn't borrowed while a callback
+/// runs, so callbacks can add and remove callbacks: added ones are first called by the next
+/// `notify`, removed ones aren't called again. Calls made from inside a callback are ignored.
+pub(super) fn notify<F: ?Sized>(subscribers: &'static LocalKey<RefCell<Subscribers<F>>>, mut call: impl FnMut(&mut F)) {
+    let callbacks = subscribers.with(|s| {
+        let mut s = s.borrow_mut();
+        if s.removed.is_some() {
+            return None;
+        }
+        s.removed = Some(Vec::new());
+        Some(std::mem::take(&mut s.callbacks))
+    });
+    let mut callbacks = match callbacks {
+        Some(callbacks) => callbacks,
+        None => return,
+    };
+
+    for (id, callback) in callbacks.iter_mut() {
+        if !subscribers.with(|s| s.borrow().is_removed(*id)) {
+            call(callback);
+        }
+    }
+
+    subscribers.with(|s| {
+        let mut s = s.borrow_mut();
+        let removed = s.removed.take().unwrap_or_default();
+        if std::mem::take(&mut s.cleared) {
+            callbacks.clear();
+        }
+        callbacks.retain(|(id, _)| !removed.contains(id));
+        callbacks.append(&mut s.callbacks);
+        s.callbacks = callbacks;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Callback = dyn FnMut(&mut Vec<u32>);
+
+    thread_local! {
+        static SUBSCRIBERS: RefCell<Subscribers<Callback>> = const { RefCell::new(Subscribers::new()) };
+    }
+
+    #[test]
+    fn change_while_notifying() {
+        let first = SUBSCRIBERS.with(|s| {
+            s.borrow_mut().subscribe(Box::new(|calls: &mut Vec<u32>| {
+                calls.push(1);
+                // Added while running, and removing the next one
+                SUBSCRIBERS.with(|s| {
+                    let mut s = s.borrow_mut();
+                    s.subscribe(Box::new(|calls: &mut Vec<u32>| calls.push(3)));
+                    s.unsubscribe(1);
+                });
+            }))
+        });
+        SUBSCRIBERS.with(|s| s.borrow_mut().subscribe(Box::new(|calls: &mut Vec<u32>| calls.push(2))));
+
+        let mut calls = Vec::new();
+        notify(&SUBSCRIBERS, |callback| callback(&mut calls));
+        assert_eq!(calls, [1]);
+
+        SUBSCRIBERS.with(|s| s.borrow_mut().unsubscribe(first));
+        calls.clear();
+        notify(&SUBSCRIBERS, |callback| callback(&mut calls));
+        assert_eq!(calls, [3]);
+    }
+}
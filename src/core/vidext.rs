@@ -0,0 +1,427 @@
+use crate::Error;
+use super::Mupen;
+use mupen64plus_sys::*;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_void};
+use std::sync::Mutex;
+
+// Not thread_local: video plugins may render on a thread of their own (e.g. GLideN64's
+// threaded video), and call the extension from there
+static VIDEO_EXTENSION: Mutex<Option<Box<dyn VideoExtension + Send>>> = Mutex::new(None);
+
+fn video_extension() -> std::sync::MutexGuard<'static, Option<Box<dyn VideoExtension + Send>>> {
+    VIDEO_EXTENSION.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub(super) fn clear_video_extension() {
+    *video_extension() = None;
+}
+
+/// How the video plugin wants to display its output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoMode {
+    None,
+    Windowed,
+    Fullscreen,
+}
+
+impl VideoMode {
+    fn from_ffi(mode: c_int) -> Self {
+        #[allow(non_upper_case_globals)]
+        match mode as m64p_video_mode {
+            m64p_video_mode_M64VIDEO_WINDOWED => VideoMode::Windowed,
+            m64p_video_mode_M64VIDEO_FULLSCREEN => VideoMode::Fullscreen,
+            _ => VideoMode::None,
+        }
+    }
+}
+
+/// An OpenGL context attribute requested by the video plugin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GlAttribute {
+    DoubleBuffer,
+    BufferSize,
+    DepthSize,
+    RedSize,
+    GreenSize,
+    BlueSize,
+    AlphaSize,
+    SwapControl,
+    MultisampleBuffers,
+    MultisampleSamples,
+    ContextMajorVersion,
+    ContextMinorVersion,
+    /// One of the `M64P_GL_CONTEXT_PROFILE_*` values.
+    ContextProfileMask,
+    Other(u32),
+}
+
+impl From<m64p_GLattr> for GlAttribute {
+    fn from(attr: m64p_GLattr) -> Self {
+        #[allow(non_upper_case_globals)]
+        match attr {
+            m64p_GLattr_M64P_GL_DOUBLEBUFFER => GlAttribute::DoubleBuffer,
+            m64p_GLattr_M64P_GL_BUFFER_SIZE => GlAttribute::BufferSize,
+            m64p_GLattr_M64P_GL_DEPTH_SIZE => GlAttribute::DepthSize,
+            m64p_GLattr_M64P_GL_RED_SIZE => GlAttribute::RedSize,
+            m64p_GLattr_M64P_GL_GREEN_SIZE => GlAttribute::GreenSize,
+            m64p_GLattr_M64P_GL_BLUE_SIZE => GlAttribute::BlueSize,
+            m64p_GLattr_M64P_GL_ALPHA_SIZE => GlAttribute::AlphaSize,
+            m64p_GLattr_M64P_GL_SWAP_CONTROL => GlAttribute::SwapControl,
+            m64p_GLattr_M64P_GL_MULTISAMPLEBUFFERS => GlAttribute::MultisampleBuffers,
+            m64p_GLattr_M64P_GL_MULTISAMPLESAMPLES => GlAttribute::MultisampleSamples,
+            m64p_GLattr_M64P_GL_CONTEXT_MAJOR_VERSION => GlAttribute::ContextMajorVersion,
+            m64p_GLattr_M64P_GL_CONTEXT_MINOR_VERSION => GlAttribute::ContextMinorVersion,
+            m64p_GLattr_M64P_GL_CONTEXT_PROFILE_MASK => GlAttribute::ContextProfileMask,
+            _ => GlAttribute::Other(attr),
+        }
+    }
+}
+
+/// Replaces the window and OpenGL context that video plugins normally create with SDL, so they
+/// can render into a window or offscreen context owned by the frontend. Register with
+/// `Mupen::set_video_extension`.
+///
+/// Methods are called by the video plugin, usually on the thread running `Mupen::execute`, but
+/// plugins that render on their own thread call them from there. Calls never overlap.
+pub trait VideoExtension {
+    /// Prepare to create a context. Called when the video plugin starts.
+    fn init(&mut self) -> Result<(), Error>;
+
+    /// Destroy the context. Called when the video plugin stops.
+    fn quit(&mut self) -> Result<(), Error>;
+
+    /// The `(width, height)` of the available fullscreen modes.
+    fn list_fullscreen_modes(&mut self) -> Vec<(u32, u32)> {
+        Vec::new()
+    }
+
+    /// The refresh rates available at a fullscreen size.
+    fn list_rates(&mut self, _width: u32, _height: u32) -> Vec<i32> {
+        Vec::new()
+    }
+
+    /// Create the window or surface and make its OpenGL context current, using the attributes
+    /// set with `gl_set_attribute`.
+    fn set_video_mode(
+        &mut self,
+        width: i32,
+        height: i32,
+        bits_per_pixel: i32,
+        mode: VideoMode,
+        resizable: bool,
+    ) -> Result<(), Error>;
+
+    /// Like `set_video_mode`, with a refresh rate from `list_rates`.
+    fn set_video_mode_with_rate(
+        &mut self,
+        width: i32,
+        height: i32,
+        _refresh_rate: i32,
+        bits_per_pixel: i32,
+        mode: VideoMode,
+        resizable: bool,
+    ) -> Result<(), Error> {
+        self.set_video_mode(width, height, bits_per_pixel, mode, resizable)
+    }
+
+    /// The address of an OpenGL function, or null if there is no such function.
+    fn gl_get_proc_address(&mut self, name: &CStr) -> *const c_void;
+
+    /// Called before `set_video_mode` for each attribute the plugin needs.
+    fn gl_set_attribute(&mut self, attr: GlAttribute, value: i32) -> Result<(), Error>;
+
+    fn gl_get_attribute(&mut self, _attr: GlAttribute) -> Result<i32, Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// Present the frame the plugin has finished drawing.
+    fn gl_swap_buffers(&mut self) -> Result<(), Error>;
+
+    fn set_caption(&mut self, _title: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn toggle_fullscreen(&mut self) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// Resize the surface after the window was resized. Only called if `set_video_mode` was
+    /// told the plugin supports resizing.
+    fn resize_window(&mut self, width: i32, height: i32) -> Result<(), Error>;
+
+    /// The OpenGL framebuffer object the plugin should draw into, or 0 for the default one.
+    fn gl_default_framebuffer(&mut self) -> u32 {
+        0
+    }
+}
+
+fn to_m64p_error(result: Result<(), Error>) -> m64p_error {
+    match result {
+        Ok(()) => m64p_error_M64ERR_SUCCESS,
+        Err(Error::NotInit) => m64p_error_M64ERR_NOT_INIT,
+        Err(Error::AlreadyInit) => m64p_error_M64ERR_ALREADY_INIT,
+        Err(Error::Incompatible) => m64p_error_M64ERR_INCOMPATIBLE,
+        Err(Error::InputAssert) => m64p_error_M64ERR_INPUT_ASSERT,
+        Err(Error::InputInvalid) => m64p_error_M64ERR_INPUT_INVALID,
+        Err(Error::InputNotFound) => m64p_error_M64ERR_INPUT_NOT_FOUND,
+        Err(Error::NoMemory) => m64p_error_M64ERR_NO_MEMORY,
+        Err(Error::Files) => m64p_error_M64ERR_FILES,
+        Err(Error::Internal) => m64p_error_M64ERR_INTERNAL,
+        Err(Error::InvalidState) => m64p_error_M64ERR_INVALID_STATE,
+        Err(Error::PluginFail) => m64p_error_M64ERR_PLUGIN_FAIL,
+        Err(Error::Unsupported) => m64p_error_M64ERR_UNSUPPORTED,
+        Err(Error::WrongType) => m64p_error_M64ERR_WRONG_TYPE,
+        Err(e) => {
+            log::error!("video extension: {}", e);
+            m64p_error_M64ERR_SYSTEM_FAIL
+        }
+    }
+}
+
+/// Calls `f` with the registered extension.
+fn with_extension<T>(default: T, f: impl FnOnce(&mut (dyn VideoExtension + Send)) -> T) -> T {
+    match video_extension().as_mut() {
+        Some(extension) => f(extension.as_mut()),
+        None => default,
+    }
+}
+
+fn call(f: impl FnOnce(&mut (dyn VideoExtension + Send)) -> Result<(), Error>) -> m64p_error {
+    with_extension(m64p_error_M64ERR_NOT_INIT, |e| to_m64p_error(f(e)))
+}
+
+mod ffi {
+    use super::*;
+
+    pub unsafe extern "C" fn init() -> m64p_error {
+        call(|e| e.init())
+    }
+
+    pub unsafe extern "C" fn quit() -> m64p_error {
+        call(|e| e.quit())
+    }
+
+    pub unsafe extern "C" fn list_modes(sizes: *mut m64p_2d_size, count: *mut c_int) -> m64p_error {
+        if sizes.is_null() || count.is_null() {
+            return m64p_error_M64ERR_INPUT_ASSERT;
+        }
+        let modes = with_extension(Vec::new(), |e| e.list_fullscreen_modes());
+        let len = modes.len().min((*count).max(0) as usize);
+        for (i, &(width, height)) in modes.iter().take(len).enumerate() {
+            *sizes.add(i) = m64p_2d_size { uiWidth: width, uiHeight: height };
+        }
+        *count = len as c_int;
+        m64p_error_M64ERR_SUCCESS
+    }
+
+    pub unsafe extern "C" fn list_rates(size: m64p_2d_size, count: *mut c_int, rates: *mut c_int) -> m64p_error {
+        if rates.is_null() || count.is_null() {
+            return m64p_error_M64ERR_INPUT_ASSERT;
+        }
+        let list = with_extension(Vec::new(), |e| e.list_rates(size.uiWidth, size.uiHeight));
+        let len = list.len().min((*count).max(0) as usize);
+        for (i, &rate) in list.iter().take(len).enumerate() {
+            *rates.add(i) = rate;
+        }
+        *count = len as c_int;
+        m64p_error_M64ERR_SUCCESS
+    }
+
+    pub unsafe extern "C" fn set_mode(width: c_int, height: c_int, bpp: c_int, mode: c_int, flags: c_int) -> m64p_error {
+        let resizable = flags as m64p_video_flags & m64p_video_flags_M64VIDEOFLAG_SUPPORT_RESIZING != 0;
+        call(|e| e.set_video_mode(width, height, bpp, VideoMode::from_ffi(mode), resizable))
+    }
+
+    pub unsafe extern "C" fn set_mode_with_rate(
+        width: c_int,
+        height: c_int,
+        rate: c_int,
+        bpp: c_int,
+        mode: c_int,
+        flags: c_int,
+    ) -> m64p_error {
+        let resizable = flags as m64p_video_flags & m64p_video_flags_M64VIDEOFLAG_SUPPORT_RESIZING != 0;
+        call(|e| e.set_video_mode_with_rate(width, height, rate, bpp, VideoMode::from_ffi(mode), resizable))
+    }
+
+    pub unsafe extern "C" fn gl_get_proc(name: *const c_char) -> m64p_function {
+        if name.is_null() {
+            return None;
+        }
+        let name = CStr::from_ptr(name);
+        let ptr = with_extension(std::ptr::null(), |e| e.gl_get_proc_address(name));
+        if ptr.is_null() {
+            None
+        } else {
+            Some(std::mem::transmute::<*const c_void, unsafe extern "C" fn()>(ptr))
+        }
+    }
+
+    pub unsafe extern "C" fn gl_set_attr(attr: m64p_GLattr, value: c_int) -> m64p_error {
+        call(|e| e.gl_set_attribute(attr.into(), value))
+    }
+
+    pub unsafe extern "C" fn gl_get_attr(attr: m64p_GLattr, value: *mut c_int) -> m64p_error {
+        if value.is_null() {
+            return m64p_error_M64ERR_INPUT_ASSERT;
+        }
+        call(|e| {
+            *value = e.gl_get_attribute(attr.into())?;
+            Ok(())
+        })
+    }
+
+    pub unsafe extern "C" fn gl_swap_buf() -> m64p_error {
+        call(|e| e.gl_swap_buffers())
+    }
+
+    pub unsafe extern "C" fn set_caption(title: *const c_char) -> m64p_error {
+        if title.is_null() {
+            return m64p_error_M64ERR_INPUT_ASSERT;
+        }
+        let title = CStr::from_ptr(title).to_string_lossy();
+        call(|e| e.set_caption(&title))
+    }
+
+    pub unsafe extern "C" fn toggle_fs() -> m64p_error {
+        call(|e| e.toggle_fullscreen())
+    }
+
+    pub unsafe extern "C" fn resize_window(width: c_int, height: c_int) -> m64p_error {
+        call(|e| e.resize_window(width, height))
+    }
+
+    pub unsafe extern "C" fn gl_get_default_framebuffer() -> u32 {
+        with_extension(0, |e| e.gl_default_framebuffer())
+    }
+}
+
+impl Mupen {
+    /// Use `extension` instead of SDL for the video plugin's window and OpenGL context. Call
+    /// before attaching the video plugin, as plugins create their window when they start.
+    ///
+    /// There is one extension for the whole process, as there is one core.
+    pub fn set_video_extension<E: VideoExtension + Send + 'static>(&self, extension: E) -> Result<(), Error> {
+        *video_extension() = Some(Box::new(extension));
+
+        let mut functions = m64p_video_extension_functions {
+            Functions: 14,
+            VidExtFuncInit: Some(ffi::init),
+            VidExtFuncQuit: Some(ffi::quit),
+            VidExtFuncListModes: Some(ffi::list_modes),
+            VidExtFuncListRates: Some(ffi::list_rates),
+            VidExtFuncSetMode: Some(ffi::set_mode),
+            VidExtFuncSetModeWithRate: Some(ffi::set_mode_with_rate),
+            VidExtFuncGLGetProc: Some(ffi::gl_get_proc),
+            VidExtFuncGLSetAttr: Some(ffi::gl_set_attr),
+            VidExtFuncGLGetAttr: Some(ffi::gl_get_attr),
+            VidExtFuncGLSwapBuf: Some(ffi::gl_swap_buf),
+            VidExtFuncSetCaption: Some(ffi::set_caption),
+            VidExtFuncToggleFS: Some(ffi::toggle_fs),
+            VidExtFuncResizeWindow: Some(ffi::resize_window),
+            VidExtFuncGLGetDefaultFramebuffer: Some(ffi::gl_get_default_framebuffer),
+        };
+        let ret = unsafe { self.core.core_override_vid_ext.unwrap()(&mut functions) };
+        if ret != m64p_error_M64ERR_SUCCESS {
+            clear_video_extension();
+            Err(ret.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Go back to the core's SDL video extension.
+    pub fn clear_video_extension(&self) -> Result<(), Error> {
+        let ret = unsafe { self.core.core_override_vid_ext.unwrap()(std::ptr::null_mut()) };
+        clear_video_extension();
+        if ret != m64p_error_M64ERR_SUCCESS {
+            Err(ret.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    struct Offscreen {
+        attributes: Arc<Mutex<Vec<(GlAttribute, i32)>>>,
+    }
+
+    impl VideoExtension for Offscreen {
+        fn init(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn quit(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn list_fullscreen_modes(&mut self) -> Vec<(u32, u32)> {
+            vec![(640, 480), (320, 240)]
+        }
+
+        fn set_video_mode(&mut self, _: i32, _: i32, _: i32, mode: VideoMode, _: bool) -> Result<(), Error> {
+            match mode {
+                VideoMode::Fullscreen => Err(Error::Unsupported),
+                _ => Ok(()),
+            }
+        }
+
+        fn gl_get_proc_address(&mut self, _name: &CStr) -> *const c_void {
+            std::ptr::null()
+        }
+
+        fn gl_set_attribute(&mut self, attr: GlAttribute, value: i32) -> Result<(), Error> {
+            self.attributes.lock().unwrap().push((attr, value));
+            Ok(())
+        }
+
+        fn gl_swap_buffers(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn resize_window(&mut self, _width: i32, _height: i32) -> Result<(), Error> {
+            Err(Error::InvalidState)
+        }
+    }
+
+    #[test]
+    fn extension_calls() {
+        assert_eq!(unsafe { ffi::init() }, m64p_error_M64ERR_NOT_INIT);
+        let attributes = Arc::new(Mutex::new(Vec::new()));
+        let extension = Offscreen { attributes: attributes.clone() };
+        *video_extension() = Some(Box::new(extension));
+
+        unsafe {
+            assert_eq!(ffi::init(), m64p_error_M64ERR_SUCCESS);
+            assert_eq!(ffi::gl_set_attr(m64p_GLattr_M64P_GL_DEPTH_SIZE, 24), m64p_error_M64ERR_SUCCESS);
+            assert_eq!(ffi::set_mode(320, 240, 32, m64p_video_mode_M64VIDEO_WINDOWED as c_int, 0), m64p_error_M64ERR_SUCCESS);
+            assert_eq!(
+                ffi::set_mode(320, 240, 32, m64p_video_mode_M64VIDEO_FULLSCREEN as c_int, 0),
+                m64p_error_M64ERR_UNSUPPORTED
+            );
+            assert_eq!(ffi::resize_window(640, 480), m64p_error_M64ERR_INVALID_STATE);
+            assert_eq!(ffi::toggle_fs(), m64p_error_M64ERR_UNSUPPORTED);
+            assert!(ffi::gl_get_proc(b"glClear\0".as_ptr() as *const c_char).is_none());
+
+            let mut sizes = [m64p_2d_size { uiWidth: 0, uiHeight: 0 }; 1];
+            let mut count = 1;
+            assert_eq!(ffi::list_modes(sizes.as_mut_ptr(), &mut count), m64p_error_M64ERR_SUCCESS);
+            assert_eq!((count, sizes[0].uiWidth, sizes[0].uiHeight), (1, 640, 480));
+        }
+
+        assert_eq!(*attributes.lock().unwrap(), vec![(GlAttribute::DepthSize, 24)]);
+
+        // The plugin may call from its own rendering thread
+        let swap = std::thread::spawn(|| unsafe { ffi::gl_swap_buf() });
+        assert_eq!(swap.join().unwrap(), m64p_error_M64ERR_SUCCESS);
+
+        clear_video_extension();
+        assert_eq!(unsafe { ffi::gl_swap_buf() }, m64p_error_M64ERR_NOT_INIT);
+    }
+}
@@ -0,0 +1,138 @@
+//! RGB images, such as screenshots and framebuffers read from RDRAM.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ImageError {
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("png encoding: {0}")]
+    Encoding(#[from] png::EncodingError),
+    #[error("png decoding: {0}")]
+    Decoding(#[from] png::DecodingError),
+    #[error("unsupported png: {0:?} {1:?}")]
+    UnsupportedPng(png::ColorType, png::BitDepth),
+}
+
+/// A 24-bit RGB image, top row first.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    /// `width * height` pixels of 3 bytes each.
+    pub rgb: Vec<u8>,
+}
+
+impl Image {
+    /// A black image.
+    pub fn new(width: u32, height: u32) -> Self {
+        Image { width, height, rgb: vec![0; width as usize * height as usize * 3] }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 3] {
+        let i = (y as usize * self.width as usize + x as usize) * 3;
+        [self.rgb[i], self.rgb[i + 1], self.rgb[i + 2]]
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, rgb: [u8; 3]) {
+        let i = (y as usize * self.width as usize + x as usize) * 3;
+        self.rgb[i..i + 3].copy_from_slice(&rgb);
+    }
+
+    /// Reverses the order of the rows, for images read bottom row first.
+    pub fn flip_vertical(&mut self) {
+        let stride = self.width as usize * 3;
+        let height = self.height as usize;
+        for y in 0..height / 2 {
+            let (top, bottom) = self.rgb.split_at_mut((height - 1 - y) * stride);
+            top[y * stride..(y + 1) * stride].swap_with_slice(&mut bottom[..stride]);
+        }
+    }
+
+    /// Resizes the image with nearest-neighbour sampling.
+    pub fn scaled(&self, width: u32, height: u32) -> Image {
+        if (width, height) == (self.width, self.height) {
+            return self.clone();
+        }
+        let mut scaled = Image::new(width, height);
+        if self.is_empty() {
+            return scaled;
+        }
+        for y in 0..height {
+            let src_y = (y as u64 * self.height as u64 / height as u64) as u32;
+            for x in 0..width {
+                let src_x = (x as u64 * self.width as u64 / width as u64) as u32;
+                scaled.set_pixel(x, y, self.pixel(src_x, src_y));
+            }
+        }
+        scaled
+    }
+
+    pub fn write_png<W: Write>(&self, w: W) -> Result<(), ImageError> {
+        let mut encoder = png::Encoder::new(w, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.rgb)?;
+        Ok(writer.finish()?)
+    }
+
+    /// Reads an 8-bit RGB or RGBA PNG, ignoring alpha.
+    pub fn read_png<R: Read>(r: R) -> Result<Image, ImageError> {
+        let mut reader = png::Decoder::new(r).read_info()?;
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data)?;
+        data.truncate(info.buffer_size());
+
+        let rgb = match (info.color_type, info.bit_depth) {
+            (png::ColorType::Rgb, png::BitDepth::Eight) => data,
+            (png::ColorType::Rgba, png::BitDepth::Eight) => {
+                data.chunks_exact(4).flat_map(|p| [p[0], p[1], p[2]]).collect()
+            }
+            (color, depth) => return Err(ImageError::UnsupportedPng(color, depth)),
+        };
+        Ok(Image { width: info.width, height: info.height, rgb })
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> Result<(), ImageError> {
+        let mut file = BufWriter::new(File::create(path)?);
+        self.write_png(&mut file)?;
+        Ok(file.flush()?)
+    }
+
+    pub fn load_png<P: AsRef<Path>>(path: P) -> Result<Image, ImageError> {
+        Image::read_png(BufReader::new(File::open(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn png_round_trip() {
+        let mut image = Image::new(3, 2);
+        image.set_pixel(0, 0, [255, 0, 0]);
+        image.set_pixel(2, 1, [0, 0, 255]);
+
+        let mut flipped = image.clone();
+        flipped.flip_vertical();
+        assert_eq!(flipped.pixel(0, 1), [255, 0, 0]);
+        assert_eq!(flipped.pixel(2, 0), [0, 0, 255]);
+
+        let scaled = image.scaled(6, 4);
+        assert_eq!(scaled.pixel(1, 1), [255, 0, 0]);
+        assert_eq!(scaled.pixel(5, 3), [0, 0, 255]);
+
+        let mut png = Vec::new();
+        image.write_png(&mut png).unwrap();
+        assert_eq!(Image::read_png(&png[..]).unwrap(), image);
+    }
+}
@@ -1,10 +1,21 @@
 use thiserror::Error;
 use mupen64plus_sys::*;
 
+pub mod cheat;
+pub mod config;
 pub mod core;
+pub mod image;
+pub mod mempak;
+pub mod movie;
+pub mod patch;
 pub mod plugin;
+pub mod rom;
+pub mod romdb;
+pub mod save;
 
 pub use crate::core::Core;
+#[doc(hidden)]
+pub use mupen64plus_sys as __sys;
 pub use plugin::Plugin;
 
 #[derive(Error, Debug)]
@@ -42,6 +53,26 @@ pub enum Error {
     NoPluginStartup,
     #[error("no ROM open")]
     NoRomOpen,
+    #[error("invalid cheat code: {0}")]
+    InvalidCheat(#[from] cheat::gameshark::CodeError),
+    #[error("{0}")]
+    Rom(#[from] rom::RomError),
+    #[error("{0}")]
+    Save(#[from] save::SaveError),
+    #[error("{0}")]
+    Movie(#[from] movie::MovieError),
+    #[error("{0}")]
+    Image(#[from] image::ImageError),
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{0}")]
+    Load(Box<plugin::LoadError>),
+}
+
+impl From<plugin::LoadError> for Error {
+    fn from(err: plugin::LoadError) -> Self {
+        Error::Load(Box::new(err))
+    }
 }
 
 impl From<m64p_error> for Error {
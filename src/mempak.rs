@@ -0,0 +1,431 @@
+//! The Controller Pak (mempak) filesystem.
+//!
+//! A Controller Pak is 32KB, split into 128 pages of 256 bytes:
+//!
+//! - Page 0 is the ID sector, holding several checksummed copies of the pak's ID block.
+//! - Page 1 is the index table, a linked list of pages for each note, and page 2 is its backup.
+//! - Pages 3 and 4 are the note table: 16 entries naming each note and its first page.
+//! - Pages 5 to 127 hold the notes' data.
+//!
+//! Mupen64Plus's `.mpk` files hold four paks, one per controller, which can be opened with
+//! `Mempak::from_bytes(mpk[n * PAK_SIZE..(n + 1) * PAK_SIZE].to_vec())`.
+//!
+//! Notes are exported to and imported from `.note` files: the 32-byte note table entry followed
+//! by the note's pages, in order.
+
+use std::collections::HashSet;
+use thiserror::Error;
+
+pub const PAGE_SIZE: usize = 0x100;
+pub const PAK_SIZE: usize = 0x8000;
+pub const NUM_PAGES: usize = PAK_SIZE / PAGE_SIZE;
+pub const NUM_NOTES: usize = 16;
+/// The first page that can hold note data.
+pub const FIRST_DATA_PAGE: usize = 5;
+
+const INDEX_PAGE: usize = 1;
+const INDEX_BACKUP_PAGE: usize = 2;
+const NOTE_TABLE: usize = 3 * PAGE_SIZE;
+const NOTE_SIZE: usize = 32;
+/// Offsets of the copies of the ID block in the ID sector.
+const ID_BLOCKS: [usize; 4] = [0x20, 0x60, 0x80, 0xC0];
+
+/// Index table entry for the last page of a note.
+const PAGE_END: u16 = 0x0001;
+/// Index table entry for a page not used by any note.
+const PAGE_FREE: u16 = 0x0003;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum MempakError {
+    #[error("Controller Pak of {0:#X} bytes is the wrong size")]
+    BadSize(usize),
+    #[error("no note {0}")]
+    NoNote(usize),
+    #[error("note {note} has a broken page chain at page {page}")]
+    BadChain { note: usize, page: u16 },
+    #[error("the note table is full")]
+    NoteTableFull,
+    #[error("note needs {needed} pages, but only {free} are free")]
+    NotEnoughSpace { needed: usize, free: usize },
+    #[error("not a valid .note file")]
+    BadNoteFile,
+}
+
+/// A problem found by `Mempak::check`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Problem {
+    /// None of the copies of the ID block have a valid checksum.
+    BadIdChecksum,
+    BadIndexChecksum,
+    /// The backup index table differs from the index table.
+    IndexBackupMismatch,
+    /// A note's page chain points outside the data pages, at a free page, or loops.
+    BadChain { note: usize, page: u16 },
+    /// A page belongs to more than one note.
+    CrossLinked { page: usize },
+    /// Pages marked as used that no note reaches.
+    LostPages(Vec<usize>),
+}
+
+/// An entry in the note table.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Note {
+    /// Position in the note table.
+    pub index: usize,
+    /// The game's 4-character code from its ROM header, e.g. `NSME`.
+    pub game_code: String,
+    /// 2-character publisher code, e.g. `01` for Nintendo.
+    pub publisher: String,
+    pub name: String,
+    pub extension: String,
+    pub start_page: u16,
+    /// Number of pages the note uses, or `None` if its chain is broken.
+    pub pages: Option<usize>,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Mempak {
+    data: Vec<u8>,
+}
+
+impl std::fmt::Debug for Mempak {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Mempak")
+            .field("notes", &self.notes())
+            .field("free_pages", &self.free_pages())
+            .finish()
+    }
+}
+
+impl Default for Mempak {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mempak {
+    /// A freshly formatted, empty pak.
+    pub fn new() -> Mempak {
+        let mut pak = Mempak { data: vec![0; PAK_SIZE] };
+
+        let mut id = [0; 32];
+        id[0x19] = 0x01; // device ID
+        id[0x1A] = 0x01; // bank size
+        let (sum, inverse) = id_checksums(&id);
+        id[0x1C..0x1E].copy_from_slice(&sum.to_be_bytes());
+        id[0x1E..0x20].copy_from_slice(&inverse.to_be_bytes());
+        for &offset in ID_BLOCKS.iter() {
+            pak.data[offset..offset + 32].copy_from_slice(&id);
+        }
+
+        for page in FIRST_DATA_PAGE..NUM_PAGES {
+            pak.set_index(page, PAGE_FREE);
+        }
+        pak.write_index();
+        pak
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Mempak, MempakError> {
+        if data.len() != PAK_SIZE {
+            return Err(MempakError::BadSize(data.len()));
+        }
+        Ok(Mempak { data })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    fn index(&self, page: usize) -> u16 {
+        let at = INDEX_PAGE * PAGE_SIZE + page * 2;
+        u16::from_be_bytes([self.data[at], self.data[at + 1]])
+    }
+
+    fn set_index(&mut self, page: usize, value: u16) {
+        let at = INDEX_PAGE * PAGE_SIZE + page * 2;
+        self.data[at..at + 2].copy_from_slice(&value.to_be_bytes());
+    }
+
+    fn index_checksum(&self) -> u8 {
+        let start = INDEX_PAGE * PAGE_SIZE;
+        self.data[start + FIRST_DATA_PAGE * 2..start + PAGE_SIZE]
+            .iter()
+            .fold(0u8, |sum, &b| sum.wrapping_add(b))
+    }
+
+    /// Updates the index table's checksum and copies it to the backup.
+    fn write_index(&mut self) {
+        let start = INDEX_PAGE * PAGE_SIZE;
+        self.data[start + 1] = self.index_checksum();
+        self.data.copy_within(start..start + PAGE_SIZE, INDEX_BACKUP_PAGE * PAGE_SIZE);
+    }
+
+    fn note_entry(&self, index: usize) -> &[u8] {
+        let at = NOTE_TABLE + index * NOTE_SIZE;
+        &self.data[at..at + NOTE_SIZE]
+    }
+
+    fn note_entry_mut(&mut self, index: usize) -> &mut [u8] {
+        let at = NOTE_TABLE + index * NOTE_SIZE;
+        &mut self.data[at..at + NOTE_SIZE]
+    }
+
+    fn is_note_used(&self, index: usize) -> bool {
+        let entry = self.note_entry(index);
+        entry[0..4] != [0; 4] && u16::from_be_bytes([entry[6], entry[7]]) != 0
+    }
+
+    /// The pages of a note, in order.
+    fn chain(&self, index: usize) -> Result<Vec<usize>, MempakError> {
+        let entry = self.note_entry(index);
+        let mut page = u16::from_be_bytes([entry[6], entry[7]]);
+        let mut pages = Vec::new();
+        let mut seen = HashSet::new();
+
+        loop {
+            let p = page as usize;
+            if !(FIRST_DATA_PAGE..NUM_PAGES).contains(&p) || !seen.insert(p) {
+                return Err(MempakError::BadChain { note: index, page });
+            }
+            pages.push(p);
+
+            match self.index(p) {
+                PAGE_END => return Ok(pages),
+                next => page = next,
+            }
+        }
+    }
+
+    /// The notes in the note table.
+    pub fn notes(&self) -> Vec<Note> {
+        (0..NUM_NOTES)
+            .filter(|&i| self.is_note_used(i))
+            .map(|i| {
+                let entry = self.note_entry(i);
+                Note {
+                    index: i,
+                    game_code: String::from_utf8_lossy(&entry[0..4]).into_owned(),
+                    publisher: String::from_utf8_lossy(&entry[4..6]).into_owned(),
+                    start_page: u16::from_be_bytes([entry[6], entry[7]]),
+                    extension: decode_text(&entry[0x0C..0x10]),
+                    name: decode_text(&entry[0x10..0x20]),
+                    pages: self.chain(i).ok().map(|c| c.len()),
+                }
+            })
+            .collect()
+    }
+
+    /// Number of data pages not used by any note.
+    pub fn free_pages(&self) -> usize {
+        (FIRST_DATA_PAGE..NUM_PAGES).filter(|&p| self.index(p) == PAGE_FREE).count()
+    }
+
+    /// The contents of a note.
+    pub fn read_note(&self, index: usize) -> Result<Vec<u8>, MempakError> {
+        if index >= NUM_NOTES || !self.is_note_used(index) {
+            return Err(MempakError::NoNote(index));
+        }
+
+        Ok(self
+            .chain(index)?
+            .into_iter()
+            .flat_map(|p| self.data[p * PAGE_SIZE..(p + 1) * PAGE_SIZE].iter().copied())
+            .collect())
+    }
+
+    /// A note as a `.note` file.
+    pub fn export_note(&self, index: usize) -> Result<Vec<u8>, MempakError> {
+        let data = self.read_note(index)?;
+        let mut note = self.note_entry(index).to_vec();
+        note.extend_from_slice(&data);
+        Ok(note)
+    }
+
+    /// Adds a `.note` file to the pak, returning its index in the note table.
+    pub fn import_note(&mut self, note: &[u8]) -> Result<usize, MempakError> {
+        if note.len() <= NOTE_SIZE || note[0..4] == [0; 4] {
+            return Err(MempakError::BadNoteFile);
+        }
+        let (entry, data) = note.split_at(NOTE_SIZE);
+        if data.len() & (PAGE_SIZE - 1) != 0 {
+            return Err(MempakError::BadNoteFile);
+        }
+
+        let index = (0..NUM_NOTES)
+            .find(|&i| !self.is_note_used(i))
+            .ok_or(MempakError::NoteTableFull)?;
+
+        let needed = data.len() / PAGE_SIZE;
+        let pages: Vec<usize> = (FIRST_DATA_PAGE..NUM_PAGES)
+            .filter(|&p| self.index(p) == PAGE_FREE)
+            .take(needed)
+            .collect();
+        if pages.len() < needed {
+            return Err(MempakError::NotEnoughSpace {
+                needed,
+                free: pages.len(),
+            });
+        }
+
+        for (i, (&page, chunk)) in pages.iter().zip(data.chunks(PAGE_SIZE)).enumerate() {
+            self.data[page * PAGE_SIZE..(page + 1) * PAGE_SIZE].copy_from_slice(chunk);
+            let next = pages.get(i + 1).map(|&p| p as u16).unwrap_or(PAGE_END);
+            self.set_index(page, next);
+        }
+        self.write_index();
+
+        let start = pages[0] as u16;
+        let slot = self.note_entry_mut(index);
+        slot.copy_from_slice(entry);
+        slot[6..8].copy_from_slice(&start.to_be_bytes());
+        slot[8] |= 0x02; // in use
+
+        Ok(index)
+    }
+
+    /// Removes a note, freeing its pages.
+    pub fn delete_note(&mut self, index: usize) -> Result<(), MempakError> {
+        if index >= NUM_NOTES || !self.is_note_used(index) {
+            return Err(MempakError::NoNote(index));
+        }
+
+        // Free what we can of a broken chain too
+        let pages = self.chain(index).unwrap_or_default();
+        for page in pages {
+            self.set_index(page, PAGE_FREE);
+        }
+        self.write_index();
+        self.note_entry_mut(index).iter_mut().for_each(|b| *b = 0);
+
+        Ok(())
+    }
+
+    /// Checks the pak's checksums and page chains, returning every problem found.
+    pub fn check(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+
+        let id_ok = ID_BLOCKS.iter().any(|&offset| {
+            let id = &self.data[offset..offset + 32];
+            let (sum, inverse) = id_checksums(id);
+            id[0x1C..0x1E] == sum.to_be_bytes() && id[0x1E..0x20] == inverse.to_be_bytes()
+        });
+        if !id_ok {
+            problems.push(Problem::BadIdChecksum);
+        }
+
+        let index = INDEX_PAGE * PAGE_SIZE;
+        if self.data[index + 1] != self.index_checksum() {
+            problems.push(Problem::BadIndexChecksum);
+        }
+        let backup = INDEX_BACKUP_PAGE * PAGE_SIZE;
+        if self.data[index..index + PAGE_SIZE] != self.data[backup..backup + PAGE_SIZE] {
+            problems.push(Problem::IndexBackupMismatch);
+        }
+
+        let mut owner = [None; NUM_PAGES];
+        for note in 0..NUM_NOTES {
+            if !self.is_note_used(note) {
+                continue;
+            }
+            match self.chain(note) {
+                Ok(pages) => {
+                    for page in pages {
+                        if owner[page].is_some() {
+                            problems.push(Problem::CrossLinked { page });
+                        }
+                        owner[page] = Some(note);
+                    }
+                }
+                Err(MempakError::BadChain { note, page }) => problems.push(Problem::BadChain { note, page }),
+                Err(_) => unreachable!(),
+            }
+        }
+
+        let lost: Vec<usize> = (FIRST_DATA_PAGE..NUM_PAGES)
+            .filter(|&p| self.index(p) != PAGE_FREE && owner[p].is_none())
+            .collect();
+        if !lost.is_empty() {
+            problems.push(Problem::LostPages(lost));
+        }
+
+        problems
+    }
+}
+
+/// The two checksums at the end of an ID block: the sum of its first 14 halfwords, and 0xFFF2
+/// minus that.
+fn id_checksums(id: &[u8]) -> (u16, u16) {
+    let sum = id[..0x1C]
+        .chunks_exact(2)
+        .fold(0u16, |sum, w| sum.wrapping_add(u16::from_be_bytes([w[0], w[1]])));
+    (sum, 0xFFF2u16.wrapping_sub(sum))
+}
+
+/// Decodes text in the N64 font, stopping at the first NUL. Kana are replaced with `?`.
+fn decode_text(text: &[u8]) -> String {
+    const PUNCTUATION: &[u8] = b"!\"#'*+,-./:=?@";
+
+    text.iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| match c {
+            0x0F => ' ',
+            0x10..=0x19 => (b'0' + c - 0x10) as char,
+            0x1A..=0x33 => (b'A' + c - 0x1A) as char,
+            0x34..=0x41 => PUNCTUATION[(c - 0x34) as usize] as char,
+            _ => '?',
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn import_export_delete() {
+        let mut pak = Mempak::new();
+        assert_eq!(pak.check(), vec![]);
+        assert_eq!(pak.free_pages(), 123);
+
+        let mut note = vec![0; NOTE_SIZE];
+        note[0..4].copy_from_slice(b"NSME");
+        note[4..6].copy_from_slice(b"01");
+        note[0x10..0x16].copy_from_slice(&[0x1C, 0x1A, 0x27, 0x2D, 0x0F, 0x11]); // "CANT 1"
+        note.extend((0..3 * PAGE_SIZE).map(|i| i as u8));
+
+        assert_eq!(pak.import_note(&note).unwrap(), 0);
+        assert_eq!(pak.import_note(&note).unwrap(), 1);
+        pak.delete_note(0).unwrap();
+        assert_eq!(pak.import_note(&note[..NOTE_SIZE + PAGE_SIZE]).unwrap(), 0);
+        assert_eq!(pak.check(), vec![]);
+        assert_eq!(pak.free_pages(), 123 - 4);
+
+        let notes = pak.notes();
+        assert_eq!(notes.len(), 2);
+        assert_eq!(notes[1].game_code, "NSME");
+        assert_eq!(notes[1].publisher, "01");
+        assert_eq!(notes[1].name, "CANT 1");
+        assert_eq!(notes[1].pages, Some(3));
+
+        let exported = pak.export_note(1).unwrap();
+        assert_eq!(exported[NOTE_SIZE..], note[NOTE_SIZE..]);
+
+        // Point note 1's second page back at its first
+        let first = notes[1].start_page as usize;
+        let second = pak.index(first) as usize;
+        let third = pak.index(second) as usize;
+        pak.set_index(second, first as u16);
+        pak.write_index();
+        assert_eq!(
+            pak.check(),
+            vec![
+                Problem::BadChain { note: 1, page: first as u16 },
+                Problem::LostPages(vec![first, second, third]),
+            ]
+        );
+    }
+}
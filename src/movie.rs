@@ -0,0 +1,311 @@
+//! Mupen64 `.m64` movies, as recorded by Mupen64-rr and used for tool-assisted speedruns.
+//!
+//! A movie is a 1KB header followed by the input of each present controller for every input
+//! poll, as little-endian `BUTTONS` values. Movies either start from power-on or from a
+//! savestate kept next to the movie (see [`Movie::snapshot_path`]). Recording and playback are
+//! done by [`Mupen::attach_movie_input`](crate::core::Mupen::attach_movie_input).
+
+use crate::plugin::input::ControllerState;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+pub mod bk2;
+
+#[derive(Error, Debug)]
+pub enum MovieError {
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("not a .m64 movie")]
+    BadMagic,
+    #[error("unsupported .m64 version {0}")]
+    UnsupportedVersion(u32),
+    #[error("unknown movie start type {0}")]
+    BadStartType(u16),
+    #[error("movie is truncated")]
+    Truncated,
+    #[error("movie has {0} input samples but no controllers")]
+    NoControllers(usize),
+    #[error("zip: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("bad .bk2: {0}")]
+    BadBk2(String),
+}
+
+const MAGIC: &[u8; 4] = b"M64\x1A";
+const VERSION: u32 = 3;
+const HEADER_SIZE: usize = 0x400;
+
+/// How a movie starts.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub enum MovieStart {
+    /// From a savestate, which is kept in a separate file.
+    Snapshot,
+    /// From power-on, with no save data.
+    #[default]
+    PowerOn,
+    /// From power-on, with the game's existing save data.
+    Eeprom,
+}
+
+impl MovieStart {
+    fn from_u16(value: u16) -> Result<Self, MovieError> {
+        match value {
+            1 => Ok(MovieStart::Snapshot),
+            2 => Ok(MovieStart::PowerOn),
+            4 => Ok(MovieStart::Eeprom),
+            _ => Err(MovieError::BadStartType(value)),
+        }
+    }
+
+    fn to_u16(self) -> u16 {
+        match self {
+            MovieStart::Snapshot => 1,
+            MovieStart::PowerOn => 2,
+            MovieStart::Eeprom => 4,
+        }
+    }
+}
+
+/// What is plugged into a controller port during a movie.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub struct MovieController {
+    pub present: bool,
+    pub mempak: bool,
+    pub rumble: bool,
+}
+
+/// A `.m64` movie.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Movie {
+    /// Identifies the movie, and the savestates made while recording it. Usually the time
+    /// recording started.
+    pub uid: u32,
+    /// Length in frames (vertical interrupts), or 0 if it isn't known.
+    pub vi_count: u32,
+    pub rerecords: u32,
+    /// 60 for NTSC games, 50 for PAL.
+    pub vis_per_second: u8,
+    pub start: MovieStart,
+    pub controllers: [MovieController; 4],
+    /// Internal name of the ROM, from its header.
+    pub rom_name: String,
+    /// `crc1` from the ROM header.
+    pub rom_crc: u32,
+    pub rom_country: u16,
+    pub video_plugin: String,
+    pub audio_plugin: String,
+    pub input_plugin: String,
+    pub rsp_plugin: String,
+    pub author: String,
+    pub description: String,
+    /// The controller states for each input poll. Ports that aren't present are ignored.
+    pub input: Vec<[ControllerState; 4]>,
+}
+
+impl Default for Movie {
+    fn default() -> Self {
+        Movie {
+            uid: 0,
+            vi_count: 0,
+            rerecords: 0,
+            vis_per_second: 60,
+            start: MovieStart::PowerOn,
+            controllers: [MovieController::default(); 4],
+            rom_name: String::new(),
+            rom_crc: 0,
+            rom_country: 0,
+            video_plugin: String::new(),
+            audio_plugin: String::new(),
+            input_plugin: String::new(),
+            rsp_plugin: String::new(),
+            author: String::new(),
+            description: String::new(),
+            input: Vec::new(),
+        }
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+/// Reads a NUL-padded string field.
+fn read_str(data: &[u8], offset: usize, len: usize) -> String {
+    let field = &data[offset..offset + len];
+    let end = field.iter().position(|&b| b == 0).unwrap_or(len);
+    String::from_utf8_lossy(&field[..end]).trim_end().to_owned()
+}
+
+/// Writes a NUL-padded string field, cutting it short at a character boundary if needed.
+fn write_str(data: &mut [u8], offset: usize, len: usize, s: &str) {
+    let mut end = s.len().min(len);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    data[offset..offset + end].copy_from_slice(&s.as_bytes()[..end]);
+}
+
+impl Movie {
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, MovieError> {
+        if data.len() < 8 || &data[..4] != MAGIC {
+            return Err(MovieError::BadMagic);
+        }
+        let version = read_u32(data, 0x04);
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        if data.len() < HEADER_SIZE {
+            return Err(MovieError::Truncated);
+        }
+
+        let flags = read_u32(data, 0x20);
+        let mut controllers = [MovieController::default(); 4];
+        for (i, controller) in controllers.iter_mut().enumerate() {
+            controller.present = flags & (1 << i) != 0;
+            controller.mempak = flags & (1 << (i + 4)) != 0;
+            controller.rumble = flags & (1 << (i + 8)) != 0;
+        }
+
+        let mut movie = Movie {
+            uid: read_u32(data, 0x08),
+            vi_count: read_u32(data, 0x0C),
+            rerecords: read_u32(data, 0x10),
+            vis_per_second: data[0x14],
+            start: MovieStart::from_u16(read_u16(data, 0x1C))?,
+            controllers,
+            rom_name: read_str(data, 0xC4, 32),
+            rom_crc: read_u32(data, 0xE4),
+            rom_country: read_u16(data, 0xE8),
+            video_plugin: read_str(data, 0x122, 64),
+            audio_plugin: read_str(data, 0x162, 64),
+            input_plugin: read_str(data, 0x1A2, 64),
+            rsp_plugin: read_str(data, 0x1E2, 64),
+            author: read_str(data, 0x222, 222),
+            description: read_str(data, 0x300, 256),
+            input: Vec::new(),
+        };
+
+        let ports: Vec<usize> = (0..4).filter(|&i| movie.controllers[i].present).collect();
+        let samples = read_u32(data, 0x18) as usize;
+        let mut words = data[HEADER_SIZE..].chunks_exact(4).map(|w| read_u32(w, 0));
+        // Without controllers there is no input to bound the count by
+        if ports.is_empty() && samples != 0 {
+            return Err(MovieError::NoControllers(samples));
+        }
+        while movie.input.len() < samples {
+            let mut sample = [ControllerState::default(); 4];
+            for &port in ports.iter() {
+                sample[port] = words.next().ok_or(MovieError::Truncated)?.into();
+            }
+            movie.input.push(sample);
+        }
+
+        Ok(movie)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let ports: Vec<usize> = (0..4).filter(|&i| self.controllers[i].present).collect();
+        let mut data = vec![0u8; HEADER_SIZE];
+
+        let mut flags = 0u32;
+        for (i, controller) in self.controllers.iter().enumerate() {
+            flags |= (controller.present as u32) << i
+                | (controller.mempak as u32) << (i + 4)
+                | (controller.rumble as u32) << (i + 8);
+        }
+
+        data[0x00..0x04].copy_from_slice(MAGIC);
+        data[0x04..0x08].copy_from_slice(&VERSION.to_le_bytes());
+        data[0x08..0x0C].copy_from_slice(&self.uid.to_le_bytes());
+        data[0x0C..0x10].copy_from_slice(&self.vi_count.to_le_bytes());
+        data[0x10..0x14].copy_from_slice(&self.rerecords.to_le_bytes());
+        data[0x14] = self.vis_per_second;
+        data[0x15] = ports.len() as u8;
+        // Samples are only stored for the controllers present
+        let samples = if ports.is_empty() { 0 } else { u32::try_from(self.input.len()).unwrap_or(u32::MAX) };
+        data[0x18..0x1C].copy_from_slice(&samples.to_le_bytes());
+        data[0x1C..0x1E].copy_from_slice(&self.start.to_u16().to_le_bytes());
+        data[0x20..0x24].copy_from_slice(&flags.to_le_bytes());
+        write_str(&mut data, 0xC4, 32, &self.rom_name);
+        data[0xE4..0xE8].copy_from_slice(&self.rom_crc.to_le_bytes());
+        data[0xE8..0xEA].copy_from_slice(&self.rom_country.to_le_bytes());
+        write_str(&mut data, 0x122, 64, &self.video_plugin);
+        write_str(&mut data, 0x162, 64, &self.audio_plugin);
+        write_str(&mut data, 0x1A2, 64, &self.input_plugin);
+        write_str(&mut data, 0x1E2, 64, &self.rsp_plugin);
+        write_str(&mut data, 0x222, 222, &self.author);
+        write_str(&mut data, 0x300, 256, &self.description);
+
+        for sample in self.input.iter() {
+            for &port in ports.iter() {
+                data.extend_from_slice(&u32::from(sample[port]).to_le_bytes());
+            }
+        }
+        data
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Movie, MovieError> {
+        Movie::from_bytes(&std::fs::read(path)?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), MovieError> {
+        Ok(std::fs::write(path, self.to_bytes())?)
+    }
+
+    /// Where the savestate of a movie that starts from a snapshot is kept: next to the movie,
+    /// with the extension `.st`.
+    pub fn snapshot_path<P: AsRef<Path>>(movie_path: P) -> PathBuf {
+        movie_path.as_ref().with_extension("st")
+    }
+
+    /// The number of controllers present.
+    pub fn controller_count(&self) -> usize {
+        self.controllers.iter().filter(|c| c.present).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::input::Buttons;
+
+    #[test]
+    fn round_trip() {
+        let mut movie = Movie {
+            uid: 0x5F00_0000,
+            vi_count: 3,
+            rerecords: 12,
+            rom_name: "SUPER MARIO 64".into(),
+            rom_crc: 0x635A_2BFF,
+            rom_country: 0x45,
+            author: "Ｎ64 fan".into(),
+            ..Default::default()
+        };
+        movie.controllers[0] = MovieController { present: true, mempak: false, rumble: true };
+        movie.controllers[2].present = true;
+
+        let a = ControllerState { buttons: Buttons::A, x: 0, y: 127 };
+        let mut sample = [ControllerState::default(); 4];
+        sample[2] = a;
+        movie.input = vec![sample; 2];
+
+        let data = movie.to_bytes();
+        assert_eq!(data.len(), 0x400 + 2 * 2 * 4);
+        assert_eq!(&data[0x20..0x24], &[0x05, 0x01, 0, 0]);
+        assert_eq!(&data[0x404..0x408], &u32::from(a).to_le_bytes());
+        assert_eq!(Movie::from_bytes(&data).unwrap(), movie);
+
+        assert!(matches!(Movie::from_bytes(&data[..0x404]), Err(MovieError::Truncated)));
+        assert!(matches!(Movie::from_bytes(b"M64\x1A\x01\0\0\0"), Err(MovieError::UnsupportedVersion(1))));
+
+        // A huge sample count without controllers
+        let mut data = Movie::default().to_bytes();
+        data[0x18..0x1C].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(Movie::from_bytes(&data), Err(MovieError::NoControllers(_))));
+    }
+}
@@ -0,0 +1,454 @@
+//! Converting movies to and from BizHawk's `.bk2` format.
+//!
+//! A `.bk2` is a zip archive with a `Header.txt` of key-value lines, an `Input Log.txt` with one
+//! line per frame, and the core's settings in `SyncSettings.json`. Each input poll in a `.m64`
+//! becomes one line of the input log and vice versa, which only matches if the game polls once
+//! per frame (see [`Unsupported::PollsAsFrames`]). Anything that can't be carried over is
+//! reported as [`Unsupported`].
+
+use super::{Movie, MovieController, MovieError, MovieStart};
+use crate::plugin::input::{Buttons, ControllerState};
+use std::io::{Cursor, Read, Write};
+
+/// Something in a movie that the other format can't represent.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Unsupported {
+    /// The movie starts from a savestate, which can't be converted between emulators.
+    Savestate,
+    /// The movie starts with existing save data, which isn't converted.
+    SaveData,
+    /// `.m64` identifies the ROM by its header CRC and `.bk2` by its SHA-1, so the ROM check is
+    /// left out.
+    RomHash,
+    /// A controller has a Transfer Pak, which `.m64` can't describe.
+    TransferPak { controller: usize },
+    /// The reset or power button is pressed on a frame; `.m64` has no console buttons.
+    ConsoleButtons { frame: usize },
+    /// `.m64` records input polls and `.bk2` records frames, and each poll is converted to one
+    /// frame. Games that poll more or less than once per frame, such as on lag frames, go out of
+    /// sync. Converting from `.bk2` always reports this, and leaves the `.m64`'s frame count
+    /// unknown; converting to `.bk2` reports it if the movie's frame count differs from its
+    /// number of polls.
+    PollsAsFrames,
+}
+
+const LOG_HEADER: &str = "[Input]";
+const LOG_FOOTER: &str = "[/Input]";
+const CONSOLE_KEY: &str = "#Reset|Power|";
+
+/// A column of a player's group in the input log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Column {
+    XAxis,
+    YAxis,
+    Button(Buttons),
+    /// BizHawk's `A Up`, `A Down`, `A Left` and `A Right`: the stick held all the way in one
+    /// direction, overriding the axis.
+    StickX(i8),
+    StickY(i8),
+    /// An axis this module doesn't know, which is skipped.
+    OtherAxis,
+    /// A button this module doesn't know, which is ignored.
+    Other,
+}
+
+/// The buttons of a BizHawk N64 controller, in input log order, with their names and
+/// mnemonics. The axes come before them.
+const BUTTONS: [(Column, &str, char); 18] = [
+    (Column::StickY(i8::MAX), "A Up", 'U'),
+    (Column::StickY(i8::MIN), "A Down", 'D'),
+    (Column::StickX(i8::MIN), "A Left", 'L'),
+    (Column::StickX(i8::MAX), "A Right", 'R'),
+    (Column::Button(Buttons::U_DPAD), "DPad U", 'U'),
+    (Column::Button(Buttons::D_DPAD), "DPad D", 'D'),
+    (Column::Button(Buttons::L_DPAD), "DPad L", 'L'),
+    (Column::Button(Buttons::R_DPAD), "DPad R", 'R'),
+    (Column::Button(Buttons::START), "Start", 'S'),
+    (Column::Button(Buttons::Z), "Z", 'Z'),
+    (Column::Button(Buttons::B), "B", 'B'),
+    (Column::Button(Buttons::A), "A", 'A'),
+    (Column::Button(Buttons::U_CBUTTON), "C Up", 'u'),
+    (Column::Button(Buttons::D_CBUTTON), "C Down", 'd'),
+    (Column::Button(Buttons::R_CBUTTON), "C Right", 'r'),
+    (Column::Button(Buttons::L_CBUTTON), "C Left", 'l'),
+    (Column::Button(Buttons::L_TRIG), "L", 'L'),
+    (Column::Button(Buttons::R_TRIG), "R", 'R'),
+];
+
+/// BizHawk's `N64ControllerPakType`.
+const PAK_NONE: u32 = 1;
+const PAK_MEMORY_CARD: u32 = 2;
+const PAK_RUMBLE: u32 = 3;
+const PAK_TRANSFER: u32 = 4;
+
+fn bad(message: &str) -> MovieError {
+    MovieError::BadBk2(message.to_owned())
+}
+
+fn log_key(ports: &[usize]) -> String {
+    let mut key = String::from("LogKey:");
+    key.push_str(CONSOLE_KEY);
+    for &port in ports {
+        let player = port + 1;
+        key.push_str(&format!("#P{0} X Axis|P{0} Y Axis|", player));
+        for (_, name, _) in BUTTONS.iter() {
+            key.push_str(&format!("P{} {}|", player, name));
+        }
+    }
+    key
+}
+
+fn sync_settings(movie: &Movie) -> String {
+    let controllers: Vec<String> = movie
+        .controllers
+        .iter()
+        .map(|c| {
+            let pak = if c.mempak {
+                PAK_MEMORY_CARD
+            } else if c.rumble {
+                PAK_RUMBLE
+            } else {
+                PAK_NONE
+            };
+            format!("{{\"IsConnected\":{},\"PakType\":{}}}", c.present, pak)
+        })
+        .collect();
+
+    format!(
+        "{{\"o\":{{\"$type\":\"BizHawk.Emulation.Cores.Nintendo.N64.N64SyncSettings, BizHawk.Emulation.Cores\",\"Controllers\":[{}]}}}}",
+        controllers.join(",")
+    )
+}
+
+/// Converts a `.m64` movie to the contents of a `.bk2` file.
+pub fn to_bk2(movie: &Movie) -> Result<(Vec<u8>, Vec<Unsupported>), MovieError> {
+    let mut unsupported = vec![Unsupported::RomHash];
+    match movie.start {
+        MovieStart::Snapshot => unsupported.push(Unsupported::Savestate),
+        MovieStart::Eeprom => unsupported.push(Unsupported::SaveData),
+        MovieStart::PowerOn => {}
+    }
+    if movie.vi_count as usize != movie.input.len() {
+        unsupported.push(Unsupported::PollsAsFrames);
+    }
+
+    let mut header = String::new();
+    header.push_str("MovieVersion BizHawk v2.0.0\n");
+    header.push_str("Platform N64\n");
+    header.push_str("Core Mupen64Plus\n");
+    header.push_str(&format!("GameName {}\n", movie.rom_name));
+    header.push_str(&format!("Author {}\n", movie.author));
+    header.push_str(&format!("rerecordCount {}\n", movie.rerecords));
+    if movie.start == MovieStart::Snapshot {
+        header.push_str("StartsFromSavestate True\n");
+    }
+    if movie.start == MovieStart::Eeprom {
+        header.push_str("StartsFromSaveRam True\n");
+    }
+
+    let ports: Vec<usize> = (0..4).filter(|&i| movie.controllers[i].present).collect();
+    let mut log = format!("{}\n{}\n", LOG_HEADER, log_key(&ports));
+    for sample in movie.input.iter() {
+        log.push_str("|..|");
+        for &port in ports.iter() {
+            let state = sample[port];
+            log.push_str(&format!("{:5},{:5},", state.x, state.y));
+            for &(column, _, mnemonic) in BUTTONS.iter() {
+                let pressed = match column {
+                    Column::Button(button) => state.buttons.contains(button),
+                    // The axes already say where the stick is
+                    _ => false,
+                };
+                log.push(if pressed { mnemonic } else { '.' });
+            }
+            log.push('|');
+        }
+        log.push('\n');
+    }
+    log.push_str(LOG_FOOTER);
+    log.push('\n');
+
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    let options = zip::write::FileOptions::default();
+    let mut files = vec![
+        ("Header.txt", header),
+        ("Input Log.txt", log),
+        ("SyncSettings.json", sync_settings(movie)),
+    ];
+    if !movie.description.is_empty() {
+        files.push(("Comments.txt", format!("{}\n", movie.description)));
+    }
+    for (name, contents) in files {
+        zip.start_file(name, options)?;
+        zip.write_all(contents.as_bytes())?;
+    }
+
+    Ok((zip.finish()?.into_inner(), unsupported))
+}
+
+/// Reads the paks from `SyncSettings.json`, without a JSON parser: the controllers are the only
+/// objects with a `PakType`.
+fn read_paks(json: &str) -> Vec<u32> {
+    json.match_indices("\"PakType\":")
+        .map(|(i, key)| {
+            let value: String = json[i + key.len()..]
+                .trim_start()
+                .chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect();
+            value.parse().unwrap_or(PAK_NONE)
+        })
+        .collect()
+}
+
+fn parse_axis(s: &str) -> Result<i8, MovieError> {
+    let value: i32 = s.trim().parse().map_err(|_| bad("bad axis value"))?;
+    Ok(value.max(i8::MIN as i32).min(i8::MAX as i32) as i8)
+}
+
+fn column(name: &str) -> Column {
+    match name {
+        "X Axis" => Column::XAxis,
+        "Y Axis" => Column::YAxis,
+        _ if name.ends_with("Axis") => Column::OtherAxis,
+        _ => BUTTONS.iter().find(|(_, n, _)| *n == name).map_or(Column::Other, |&(c, _, _)| c),
+    }
+}
+
+/// A group of the log key: the controller port it's for, or `None` for the console's buttons,
+/// and its columns.
+type KeyGroup = (Option<usize>, Vec<Column>);
+
+/// Reads the groups of the log key, looking up the columns by name.
+fn parse_log_key(key: &str) -> Result<Vec<KeyGroup>, MovieError> {
+    key.split('#')
+        .filter(|group| !group.is_empty())
+        .map(|group| {
+            let names: Vec<&str> = group.split('|').filter(|name| !name.is_empty()).collect();
+            let player = names
+                .first()
+                .and_then(|name| name.strip_prefix('P'))
+                .and_then(|name| name.split(' ').next())
+                .and_then(|player| player.parse::<usize>().ok());
+            match player {
+                Some(player @ 1..=4) => {
+                    let prefix = format!("P{} ", player);
+                    let columns = names.iter().map(|name| column(name.strip_prefix(&prefix).unwrap_or(""))).collect();
+                    Ok((Some(player - 1), columns))
+                }
+                Some(_) => Err(bad("player out of range")),
+                None => Ok((None, vec![Column::Other; names.len()])),
+            }
+        })
+        .collect()
+}
+
+/// Reads a group of an input log line: a comma-terminated number for each axis and a character
+/// for each button, as laid out by `columns`. Also returns whether any `Column::Other` button is
+/// pressed.
+fn parse_group(s: &str, columns: &[Column]) -> Result<(ControllerState, bool), MovieError> {
+    let mut state = ControllerState::default();
+    let mut stick = (None, None);
+    let mut other = false;
+    let mut rest = s;
+    for &column in columns {
+        if let Column::XAxis | Column::YAxis | Column::OtherAxis = column {
+            let end = rest.find(',').ok_or_else(|| bad("missing axis value"))?;
+            let value = parse_axis(&rest[..end])?;
+            rest = &rest[end + 1..];
+            match column {
+                Column::XAxis => state.x = value,
+                Column::YAxis => state.y = value,
+                _ => {}
+            }
+            continue;
+        }
+
+        let mut chars = rest.chars();
+        let c = chars.next().ok_or_else(|| bad("wrong number of buttons"))?;
+        rest = chars.as_str();
+        if c == '.' || c == ' ' {
+            continue;
+        }
+        match column {
+            Column::Button(button) => state.buttons |= button,
+            Column::StickX(x) => stick.0 = Some(x),
+            Column::StickY(y) => stick.1 = Some(y),
+            _ => other = true,
+        }
+    }
+    if !rest.is_empty() {
+        return Err(bad("wrong number of buttons"));
+    }
+
+    state.x = stick.0.unwrap_or(state.x);
+    state.y = stick.1.unwrap_or(state.y);
+    Ok((state, other))
+}
+
+fn read_file(archive: &mut zip::ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<Option<String>, MovieError> {
+    let mut file = match archive.by_name(name) {
+        Ok(file) => file,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    Ok(Some(contents))
+}
+
+/// Converts the contents of an N64 `.bk2` file to a `.m64` movie.
+pub fn from_bk2(data: &[u8]) -> Result<(Movie, Vec<Unsupported>), MovieError> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data))?;
+    let header = read_file(&mut archive, "Header.txt")?.ok_or_else(|| bad("no Header.txt"))?;
+    let log = read_file(&mut archive, "Input Log.txt")?.ok_or_else(|| bad("no Input Log.txt"))?;
+    let settings = read_file(&mut archive, "SyncSettings.json")?.unwrap_or_default();
+    let comments = read_file(&mut archive, "Comments.txt")?.unwrap_or_default();
+
+    let mut movie = Movie::default();
+    let mut unsupported = vec![Unsupported::RomHash];
+
+    for line in header.lines() {
+        let mut parts = line.splitn(2, ' ');
+        let (key, value) = (parts.next().unwrap_or(""), parts.next().unwrap_or("").trim());
+        match key {
+            "Platform" if value != "N64" => return Err(bad("not an N64 movie")),
+            "GameName" => movie.rom_name = value.to_owned(),
+            "Author" => movie.author = value.to_owned(),
+            "rerecordCount" => movie.rerecords = value.parse().unwrap_or(0),
+            "StartsFromSavestate" if value.eq_ignore_ascii_case("true") => {
+                movie.start = MovieStart::Snapshot;
+                unsupported.push(Unsupported::Savestate);
+            }
+            "StartsFromSaveRam" if value.eq_ignore_ascii_case("true") => {
+                movie.start = MovieStart::Eeprom;
+                unsupported.push(Unsupported::SaveData);
+            }
+            _ => {}
+        }
+    }
+    movie.description = comments.trim_end().to_owned();
+
+    // The log key says which players are in the log, and where each button is
+    let key = log
+        .lines()
+        .find_map(|line| line.strip_prefix("LogKey:"))
+        .ok_or_else(|| bad("no LogKey"))?;
+    let groups = parse_log_key(key)?;
+    let ports: Vec<usize> = groups.iter().filter_map(|&(port, _)| port).collect();
+
+    let paks = read_paks(&settings);
+    for &port in ports.iter() {
+        let pak = paks.get(port).copied().unwrap_or(PAK_NONE);
+        movie.controllers[port] = MovieController {
+            present: true,
+            mempak: pak == PAK_MEMORY_CARD,
+            rumble: pak == PAK_RUMBLE,
+        };
+        if pak == PAK_TRANSFER {
+            unsupported.push(Unsupported::TransferPak { controller: port });
+        }
+    }
+
+    for line in log.lines().filter(|line| line.starts_with('|')) {
+        let values: Vec<&str> = line.trim_end_matches('|').split('|').skip(1).collect();
+        if values.len() != groups.len() {
+            return Err(bad("input log line doesn't match the log key"));
+        }
+
+        let mut sample = [ControllerState::default(); 4];
+        let mut console_buttons = false;
+        for ((port, columns), values) in groups.iter().zip(values) {
+            let (state, other) = parse_group(values, columns)?;
+            match port {
+                Some(port) => sample[*port] = state,
+                None => console_buttons |= other,
+            }
+        }
+        if console_buttons {
+            unsupported.push(Unsupported::ConsoleButtons { frame: movie.input.len() });
+        }
+        movie.input.push(sample);
+    }
+    // The frames aren't the polls the .m64 counts
+    unsupported.push(Unsupported::PollsAsFrames);
+
+    Ok((movie, unsupported))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut movie = Movie {
+            rom_name: "SUPER MARIO 64".into(),
+            author: "someone".into(),
+            description: "any%".into(),
+            rerecords: 5,
+            vi_count: 2,
+            ..Default::default()
+        };
+        movie.controllers[0] = MovieController { present: true, mempak: false, rumble: true };
+        movie.controllers[1].present = true;
+
+        let mut sample = [ControllerState::default(); 4];
+        sample[0] = ControllerState { buttons: Buttons::A | Buttons::L_TRIG | Buttons::L_DPAD, x: -128, y: 127 };
+        sample[1] = ControllerState { buttons: Buttons::U_CBUTTON, x: 3, y: 0 };
+        movie.input = vec![sample, [ControllerState::default(); 4]];
+
+        let (bk2, unsupported) = to_bk2(&movie).unwrap();
+        assert_eq!(unsupported, [Unsupported::RomHash]);
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(&bk2[..])).unwrap();
+        let log = read_file(&mut archive, "Input Log.txt").unwrap().unwrap();
+        assert_eq!(log.lines().nth(2), Some("|..| -128,  127,......L....A....L.|    3,    0,............u.....|"));
+
+        let (converted, unsupported) = from_bk2(&bk2).unwrap();
+        assert_eq!(unsupported, [Unsupported::RomHash, Unsupported::PollsAsFrames]);
+        assert_eq!(converted, Movie { vi_count: 0, ..movie.clone() });
+
+        // More frames than polls, as with lag frames
+        movie.vi_count = 3;
+        let (_, unsupported) = to_bk2(&movie).unwrap();
+        assert_eq!(unsupported, [Unsupported::RomHash, Unsupported::PollsAsFrames]);
+    }
+
+    fn bk2(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            zip.start_file(*name, zip::write::FileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn bizhawk_input_log() {
+        // As written by BizHawk's Mupen64Plus core, with the stick directions as buttons
+        let log = "[Input]\r\n\
+            LogKey:#Reset|Power|#P1 X Axis|P1 Y Axis|P1 A Up|P1 A Down|P1 A Left|P1 A Right|P1 DPad U|P1 DPad D|P1 DPad L|P1 DPad R|P1 Start|P1 Z|P1 B|P1 A|P1 C Up|P1 C Down|P1 C Right|P1 C Left|P1 L|P1 R|\r\n\
+            |..|    0,    0,..................|\r\n\
+            |..|   12,  -40,........S.....r...|\r\n\
+            |.P|    0,    0,U..........A......|\r\n\
+            [/Input]\r\n";
+        let data = bk2(&[
+            ("Header.txt", "MovieVersion BizHawk v2.0.0\r\nPlatform N64\r\nGameName Super Mario 64 (USA)\r\n"),
+            ("Input Log.txt", log),
+        ]);
+
+        let (movie, unsupported) = from_bk2(&data).unwrap();
+        assert_eq!(unsupported, [Unsupported::RomHash, Unsupported::ConsoleButtons { frame: 2 }, Unsupported::PollsAsFrames]);
+        assert!(movie.controllers[0].present && !movie.controllers[1].present);
+        assert_eq!(movie.input.len(), 3);
+        assert_eq!(movie.input[1][0], ControllerState { buttons: Buttons::START | Buttons::R_CBUTTON, x: 12, y: -40 });
+        assert_eq!(movie.input[2][0], ControllerState { buttons: Buttons::A, x: 0, y: i8::MAX });
+
+        // And the log key written is the same
+        let (written, _) = to_bk2(&movie).unwrap();
+        let mut archive = zip::ZipArchive::new(Cursor::new(&written[..])).unwrap();
+        let written_log = read_file(&mut archive, "Input Log.txt").unwrap().unwrap();
+        assert_eq!(written_log.lines().nth(1), log.lines().nth(1));
+    }
+}
@@ -0,0 +1,449 @@
+//! Applying IPS, BPS and UPS patches to ROMs in memory.
+//!
+//! ```no_run
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! use mupen64plus::{patch, rom::Rom};
+//!
+//! let rom = Rom::load("game.z64")?;
+//! let hack = patch::patch_rom(&rom, &std::fs::read("hack.bps")?)?;
+//! // mupen.open_rom(&mut hack.into_bytes())?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::rom::{Rom, RomError};
+use std::convert::TryFrom;
+use thiserror::Error;
+
+/// The largest ROM a patch may produce, the size of the cartridge address space. Larger sizes in
+/// BPS and UPS headers are rejected before anything is allocated.
+pub const MAX_TARGET_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Error, Debug)]
+pub enum PatchError {
+    #[error("unknown patch format")]
+    UnknownFormat,
+    #[error("patch is truncated")]
+    Truncated,
+    #[error("patch reads or writes past the end of the ROM")]
+    OutOfBounds,
+    #[error("source is {actual:#X} bytes, patch expects {expected:#X}")]
+    SourceSize { expected: usize, actual: usize },
+    #[error("source CRC32 is {actual:08X}, patch expects {expected:08X}")]
+    SourceCrc { expected: u32, actual: u32 },
+    #[error("patched CRC32 is {actual:08X}, patch expects {expected:08X}")]
+    TargetCrc { expected: u32, actual: u32 },
+    #[error("patch CRC32 is {actual:08X}, but it says {expected:08X}; the patch is corrupt")]
+    PatchCrc { expected: u32, actual: u32 },
+    #[error("{0}")]
+    Rom(#[from] RomError),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum PatchFormat {
+    Ips,
+    Bps,
+    Ups,
+}
+
+impl PatchFormat {
+    /// Detects the format of a patch from its magic.
+    pub fn detect(patch: &[u8]) -> Option<PatchFormat> {
+        if patch.starts_with(b"PATCH") {
+            Some(PatchFormat::Ips)
+        } else if patch.starts_with(b"BPS1") {
+            Some(PatchFormat::Bps)
+        } else if patch.starts_with(b"UPS1") {
+            Some(PatchFormat::Ups)
+        } else {
+            None
+        }
+    }
+}
+
+/// Applies a patch of any supported format to `source`, returning the patched data.
+pub fn apply(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    match PatchFormat::detect(patch).ok_or(PatchError::UnknownFormat)? {
+        PatchFormat::Ips => apply_ips(source, patch),
+        PatchFormat::Bps => apply_bps(source, patch),
+        PatchFormat::Ups => apply_ups(source, patch),
+    }
+}
+
+/// Patches a ROM and fixes the checksum in its header, so the result can be opened directly.
+///
+/// The patch is applied to the ROM in the byte order it was loaded in, which is what patching
+/// tools would see. ROMs with an unrecognised CIC are left with the CRCs the patch gave them.
+pub fn patch_rom(rom: &Rom, patch: &[u8]) -> Result<Rom, PatchError> {
+    let data = apply(&rom.to_order(rom.original_order()), patch)?;
+    let mut patched = Rom::from_bytes(data)?;
+
+    match patched.fix_crc() {
+        Ok(true) => log::debug!("patch: fixed header CRCs"),
+        Ok(false) => {}
+        Err(RomError::UnknownCic(crc)) => {
+            log::warn!("patch: unknown CIC (IPL3 CRC32 {:08X}), not fixing header CRCs", crc)
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(patched)
+}
+
+/// A cursor over a patch, failing with `Truncated` instead of panicking.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Reader { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or(PatchError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, PatchError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn be(&mut self, len: usize) -> Result<usize, PatchError> {
+        Ok(self.bytes(len)?.iter().fold(0, |n, &b| (n << 8) | b as usize))
+    }
+
+    /// The variable-length integer used by BPS and UPS.
+    fn varint(&mut self) -> Result<usize, PatchError> {
+        let mut n: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let b = self.byte()?;
+            n = (b as usize & 0x7F)
+                .checked_mul(shift)
+                .and_then(|x| n.checked_add(x))
+                .ok_or(PatchError::Truncated)?;
+            if b & 0x80 != 0 {
+                return Ok(n);
+            }
+            shift = shift.checked_shl(7).ok_or(PatchError::Truncated)?;
+            n = n.checked_add(shift).ok_or(PatchError::Truncated)?;
+        }
+    }
+}
+
+pub fn apply_ips(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    const EOF: usize = 0x45_4F_46;
+
+    let mut target = source.to_vec();
+    let mut r = Reader::new(patch, 5);
+
+    loop {
+        let offset = r.be(3)?;
+        if offset == EOF {
+            break;
+        }
+
+        let (len, rle) = match r.be(2)? {
+            0 => (r.be(2)?, Some(r.byte()?)),
+            len => (len, None),
+        };
+
+        if target.len() < offset + len {
+            target.resize(offset + len, 0);
+        }
+        match rle {
+            Some(b) => target[offset..offset + len].iter_mut().for_each(|t| *t = b),
+            None => target[offset..offset + len].copy_from_slice(r.bytes(len)?),
+        }
+    }
+
+    // Optional extension: a new size for the file
+    if let Ok(size) = r.be(3) {
+        target.truncate(size);
+    }
+
+    Ok(target)
+}
+
+/// Reads the footer common to BPS and UPS, checking the patch's own CRC.
+fn read_footer(patch: &[u8]) -> Result<(u32, u32), PatchError> {
+    if patch.len() < 16 {
+        return Err(PatchError::Truncated);
+    }
+
+    let word = |offset: usize| {
+        u32::from_le_bytes([patch[offset], patch[offset + 1], patch[offset + 2], patch[offset + 3]])
+    };
+    let footer = patch.len() - 12;
+
+    let expected = word(footer + 8);
+    let actual = crc32fast::hash(&patch[..footer + 8]);
+    if expected != actual {
+        return Err(PatchError::PatchCrc { expected, actual });
+    }
+
+    Ok((word(footer), word(footer + 4)))
+}
+
+fn check_source(source: &[u8], size: usize, crc: u32) -> Result<(), PatchError> {
+    if source.len() != size {
+        return Err(PatchError::SourceSize {
+            expected: size,
+            actual: source.len(),
+        });
+    }
+
+    let actual = crc32fast::hash(source);
+    if actual != crc {
+        return Err(PatchError::SourceCrc { expected: crc, actual });
+    }
+
+    Ok(())
+}
+
+fn check_target(target: &[u8], crc: u32) -> Result<(), PatchError> {
+    let actual = crc32fast::hash(target);
+    if actual != crc {
+        return Err(PatchError::TargetCrc { expected: crc, actual });
+    }
+
+    Ok(())
+}
+
+pub fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    const SOURCE_READ: usize = 0;
+    const TARGET_READ: usize = 1;
+    const SOURCE_COPY: usize = 2;
+
+    let (source_crc, target_crc) = read_footer(patch)?;
+    let actions_end = patch.len() - 12;
+    let mut r = Reader::new(&patch[..actions_end], 4);
+
+    let source_size = r.varint()?;
+    let target_size = r.varint()?;
+    let metadata_size = r.varint()?;
+    r.bytes(metadata_size)?;
+
+    if target_size > MAX_TARGET_SIZE {
+        return Err(PatchError::OutOfBounds);
+    }
+    check_source(source, source_size, source_crc)?;
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset: isize = 0;
+    let mut target_offset: isize = 0;
+
+    // Copy offsets are stored as a sign bit and a magnitude
+    let signed = |n: usize| if n & 1 != 0 { -((n >> 1) as isize) } else { (n >> 1) as isize };
+    let offset = |at: isize, by: isize| at.checked_add(by).ok_or(PatchError::OutOfBounds);
+
+    while r.pos < actions_end {
+        let data = r.varint()?;
+        let len = (data >> 2) + 1;
+
+        if target.len() + len > target_size {
+            return Err(PatchError::OutOfBounds);
+        }
+
+        match data & 3 {
+            SOURCE_READ => {
+                let at = target.len();
+                target.extend_from_slice(source.get(at..at + len).ok_or(PatchError::OutOfBounds)?);
+            }
+            TARGET_READ => target.extend_from_slice(r.bytes(len)?),
+            SOURCE_COPY => {
+                source_offset = offset(source_offset, signed(r.varint()?))?;
+                let from = usize::try_from(source_offset).map_err(|_| PatchError::OutOfBounds)?;
+                target.extend_from_slice(source.get(from..from + len).ok_or(PatchError::OutOfBounds)?);
+                source_offset += len as isize;
+            }
+            _ => {
+                // Target copies may overlap what they're writing, so go byte by byte
+                target_offset = offset(target_offset, signed(r.varint()?))?;
+                let from = usize::try_from(target_offset).map_err(|_| PatchError::OutOfBounds)?;
+                if from >= target.len() {
+                    return Err(PatchError::OutOfBounds);
+                }
+                for i in from..from + len {
+                    target.push(target[i]);
+                }
+                target_offset += len as isize;
+            }
+        }
+    }
+
+    if target.len() != target_size {
+        return Err(PatchError::Truncated);
+    }
+    check_target(&target, target_crc)?;
+
+    Ok(target)
+}
+
+pub fn apply_ups(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (source_crc, target_crc) = read_footer(patch)?;
+    let hunks_end = patch.len() - 12;
+    let mut r = Reader::new(&patch[..hunks_end], 4);
+
+    let source_size = r.varint()?;
+    let target_size = r.varint()?;
+    if target_size > MAX_TARGET_SIZE {
+        return Err(PatchError::OutOfBounds);
+    }
+    check_source(source, source_size, source_crc)?;
+
+    let mut target = source.to_vec();
+    target.resize(target_size, 0);
+
+    // Each hunk skips some bytes, then XORs bytes up to a terminating zero
+    let mut at = 0;
+    while r.pos < hunks_end {
+        at = r.varint()?.checked_add(at).ok_or(PatchError::OutOfBounds)?;
+        loop {
+            let x = r.byte()?;
+            if x != 0 {
+                *target.get_mut(at).ok_or(PatchError::OutOfBounds)? ^= x;
+            }
+            at += 1;
+            if x == 0 {
+                break;
+            }
+        }
+    }
+
+    check_target(&target, target_crc)?;
+
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut n: usize, out: &mut Vec<u8>) {
+        loop {
+            let x = (n & 0x7F) as u8;
+            n >>= 7;
+            if n == 0 {
+                out.push(0x80 | x);
+                return;
+            }
+            out.push(x);
+            n -= 1;
+        }
+    }
+
+    fn footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(&patch).to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn ips() {
+        let patch = b"PATCH\x00\x00\x01\x00\x02AB\x00\x00\x06\x00\x00\x00\x03ZEOF\x00\x00\x08";
+        assert_eq!(apply(b"0123456789", patch).unwrap(), b"0AB345ZZ");
+        assert!(matches!(apply(b"0123", b"PATCH\x00\x00\x01\x00"), Err(PatchError::Truncated)));
+    }
+
+    #[test]
+    fn bps_and_ups() {
+        let source = b"Hello, world!";
+        let target = b"Hello, Hello, N64!";
+
+        let mut bps = b"BPS1".to_vec();
+        varint(source.len(), &mut bps);
+        varint(target.len(), &mut bps);
+        varint(0, &mut bps);
+        varint(6 << 2, &mut bps); // SourceRead "Hello, "
+        varint((6 << 2) | 3, &mut bps); // TargetCopy "Hello, " from 0
+        varint(0, &mut bps);
+        varint((2 << 2) | 1, &mut bps); // TargetRead "N64"
+        bps.extend_from_slice(b"N64");
+        varint(2, &mut bps); // SourceCopy "!" from 12
+        varint(12 << 1, &mut bps);
+        let bps = footer(bps, source, target);
+        assert_eq!(apply(source, &bps).unwrap(), target);
+        assert!(matches!(apply(b"Hello, World!", &bps), Err(PatchError::SourceCrc { .. })));
+
+        let mut ups = b"UPS1".to_vec();
+        varint(source.len(), &mut ups);
+        varint(target.len(), &mut ups);
+        let diff = |i: usize| target[i] ^ source.get(i).unwrap_or(&0);
+        let (mut i, mut last) = (0, 0);
+        while i < target.len() {
+            if diff(i) == 0 {
+                i += 1;
+                continue;
+            }
+            varint(i - last, &mut ups);
+            while i < target.len() && diff(i) != 0 {
+                ups.push(diff(i));
+                i += 1;
+            }
+            ups.push(0);
+            i += 1;
+            last = i;
+        }
+        let ups = footer(ups, source, target);
+        assert_eq!(apply(source, &ups).unwrap(), target);
+    }
+
+    #[test]
+    fn huge_sizes_and_offsets() {
+        let source = b"Hello, world!";
+
+        for magic in [b"BPS1", b"UPS1"] {
+            let mut patch = magic.to_vec();
+            varint(source.len(), &mut patch);
+            varint(usize::MAX >> 1, &mut patch);
+            varint(0, &mut patch);
+            let patch = footer(patch, source, source);
+            assert!(matches!(apply(source, &patch), Err(PatchError::OutOfBounds)));
+        }
+
+        let mut bps = b"BPS1".to_vec();
+        varint(source.len(), &mut bps);
+        varint(2, &mut bps);
+        varint(0, &mut bps);
+        varint(2, &mut bps); // SourceCopy from far past the end
+        varint(usize::MAX - 1, &mut bps);
+        let bps = footer(bps, source, b"He");
+        assert!(matches!(apply(source, &bps), Err(PatchError::OutOfBounds)));
+
+        let mut bps = b"BPS1".to_vec();
+        varint(source.len(), &mut bps);
+        varint(source.len(), &mut bps);
+        varint(usize::MAX - 1, &mut bps); // Metadata size
+        let bps = footer(bps, source, source);
+        assert!(matches!(apply(source, &bps), Err(PatchError::Truncated)));
+
+        let mut ups = b"UPS1".to_vec();
+        varint(source.len(), &mut ups);
+        varint(source.len(), &mut ups);
+        varint(1, &mut ups);
+        ups.extend_from_slice(b"\x01\x00");
+        varint(usize::MAX - 1, &mut ups);
+        ups.extend_from_slice(b"\x01\x00");
+        let ups = footer(ups, source, source);
+        assert!(matches!(apply(source, &ups), Err(PatchError::OutOfBounds)));
+    }
+
+    #[test]
+    fn patch_fixes_crc() {
+        let rom = Rom::load(concat!(env!("CARGO_MANIFEST_DIR"), "/examples/m64p_test_rom.v64")).unwrap();
+        let patched = patch_rom(&rom, b"PATCH\x00\x20\x00\x00\x04\x00\x00\x00\x01EOF").unwrap();
+
+        assert_eq!(patched.original_order(), rom.original_order());
+        assert_ne!(patched.header().crc2, rom.header().crc2);
+        assert!(patched.verify_crc().unwrap());
+    }
+}
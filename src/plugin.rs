@@ -1,11 +1,19 @@
 use mupen64plus_sys::*;
-use semver::Version;
+pub use semver::Version;
 use bitflags::bitflags;
 use std::ffi::CStr;
 use std::path::Path;
 use libloading::Library;
 use crate::Error;
 
+pub mod audio;
+pub mod builtin;
+pub mod export;
+pub mod input;
+pub mod null;
+pub mod rsp;
+pub mod video;
+
 pub const MINIMUM_CORE_VERSION: Version = mupen_to_version(0x016300);
 pub const CORE_API_VERSION: Version = mupen_to_version(0x020001);
 
@@ -122,11 +130,72 @@ impl PluginVersion<'_> {
     }
 }
 
+/// The name and version a plugin written with this crate reports from `PluginGetVersion`.
+pub trait PluginInfo {
+    const NAME: &'static str;
+    const VERSION: Version;
+}
+
+/// Size of RDRAM with the Expansion Pak, as allocated by the core.
+pub const RDRAM_SIZE: usize = 0x80_0000;
+/// Size of the RSP's data and instruction memories.
+pub const SP_MEM_SIZE: usize = 0x1000;
+
+/// A region of memory shared with the core, or an empty slice if the core didn't provide it.
+pub(crate) unsafe fn memory<'a>(ptr: *mut u8, len: usize) -> &'a [u8] {
+    if ptr.is_null() {
+        &[]
+    } else {
+        std::slice::from_raw_parts(ptr, len)
+    }
+}
+
+pub(crate) unsafe fn memory_mut<'a>(ptr: *mut u8, len: usize) -> &'a mut [u8] {
+    if ptr.is_null() {
+        &mut []
+    } else {
+        std::slice::from_raw_parts_mut(ptr, len)
+    }
+}
+
+/// A hardware register shared with the core, as given to plugins in `AUDIO_INFO`, `GFX_INFO` and
+/// `RSP_INFO`. Reads of a register the core didn't provide return 0.
+#[derive(Debug, Clone, Copy)]
+pub struct Register(*mut u32);
+
+impl Register {
+    /// # Safety
+    /// `ptr` must be null, or valid for as long as the register is used.
+    pub unsafe fn from_ptr(ptr: *mut u32) -> Self {
+        Register(ptr)
+    }
+
+    pub fn get(self) -> u32 {
+        if self.0.is_null() {
+            0
+        } else {
+            unsafe { self.0.read_volatile() }
+        }
+    }
+
+    pub fn set(self, value: u32) {
+        if !self.0.is_null() {
+            unsafe { self.0.write_volatile(value) }
+        }
+    }
+}
+
+// Registers are only touched from the emulation thread, but plugins may be created elsewhere
+unsafe impl Send for Register {}
+unsafe impl Sync for Register {}
+
 pub struct Plugin {
     pub(crate) lib: m64p_dynlib_handle,
     plugin_get_version: ptr_PluginGetVersion,
     pub(crate) plugin_startup: ptr_PluginStartup,
     pub(crate) plugin_shutdown: ptr_PluginShutdown,
+    /// Set for plugins built into the executable.
+    pub(crate) builtin: Option<builtin::Builtin>,
 }
 
 impl Plugin {
@@ -149,6 +218,7 @@ impl Plugin {
             plugin_get_version: unsafe { lib.get(b"PluginGetVersion\0") }.ok().and_then(|p| *p),
             plugin_startup: unsafe { lib.get(b"PluginStartup\0") }.ok().and_then(|p| *p),
             plugin_shutdown: unsafe { lib.get(b"PluginShutdown\0") }.ok().and_then(|p| *p),
+            builtin: None,
             lib: {
                 #[cfg(unix)]
                 use libloading::os::unix::Library;
@@ -175,6 +245,17 @@ impl Plugin {
     }
 
     pub fn get_version(&self) -> Result<PluginVersion<'_>, Error> {
+        if let Some(builtin) = &self.builtin {
+            let description = &builtin.description;
+            return Ok(PluginVersion {
+                plugin_type: description.plugin_type,
+                plugin_version: description.version.clone(),
+                api_version: mupen_to_version(description.api_version),
+                plugin_name: description.name.into(),
+                capabilities: Capability::empty(),
+            });
+        }
+
         PluginVersion::from_ffi(self.plugin_get_version)
     }
 }
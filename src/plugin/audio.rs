@@ -0,0 +1,366 @@
+//! Writing audio plugins in Rust.
+//!
+//! Implement [`AudioPlugin`] and [`PluginInfo`](super::PluginInfo) for a `Default` type, then
+//! export it from a `cdylib` crate with [`export_audio_plugin!`](crate::export_audio_plugin).
+//! The core calls `ai_len_changed` whenever the game queues a buffer of samples, which
+//! [`AudioInfo::samples`] reads out of RDRAM.
+
+use super::export::Slot;
+use super::{memory, memory_mut, Register, RDRAM_SIZE, SP_MEM_SIZE};
+use mupen64plus_sys::*;
+use semver::Version;
+
+pub mod capture;
+
+/// The version of the audio plugin API implemented by `export_audio_plugin!`.
+pub const AUDIO_API_VERSION: Version = super::mupen_to_version(0x020000);
+
+/// The TV system of the ROM, which sets the clock the audio DAC rate divides.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum SystemType {
+    Ntsc,
+    Pal,
+    Mpal,
+}
+
+impl SystemType {
+    /// The video clock in Hz.
+    pub fn vi_clock(self) -> u32 {
+        match self {
+            SystemType::Ntsc => 48_681_812,
+            SystemType::Pal => 49_656_530,
+            SystemType::Mpal => 48_628_316,
+        }
+    }
+}
+
+impl From<m64p_system_type> for SystemType {
+    fn from(system_type: m64p_system_type) -> Self {
+        #[allow(non_upper_case_globals)]
+        match system_type {
+            m64p_system_type_SYSTEM_PAL => SystemType::Pal,
+            m64p_system_type_SYSTEM_MPAL => SystemType::Mpal,
+            _ => SystemType::Ntsc,
+        }
+    }
+}
+
+/// The memory and registers the core shares with the audio plugin (`AUDIO_INFO`). It stays valid
+/// until the plugin is shut down.
+///
+/// RDRAM, DMEM and IMEM are stored as native-endian 32-bit words, so on little-endian hosts the
+/// bytes of each word are reversed compared to the N64.
+#[derive(Debug)]
+pub struct AudioInfo {
+    rdram: *mut u8,
+    dmem: *mut u8,
+    imem: *mut u8,
+    pub mi_intr: Register,
+    pub ai_dram_addr: Register,
+    pub ai_len: Register,
+    pub ai_control: Register,
+    pub ai_status: Register,
+    pub ai_dacrate: Register,
+    pub ai_bitrate: Register,
+    check_interrupts: Option<unsafe extern "C" fn()>,
+}
+
+unsafe impl Send for AudioInfo {}
+
+impl AudioInfo {
+    /// # Safety
+    /// The pointers in `info` must be valid for as long as the `AudioInfo` is used.
+    pub unsafe fn from_ffi(info: &AUDIO_INFO) -> Self {
+        AudioInfo {
+            rdram: info.RDRAM,
+            dmem: info.DMEM,
+            imem: info.IMEM,
+            mi_intr: Register::from_ptr(info.MI_INTR_REG),
+            ai_dram_addr: Register::from_ptr(info.AI_DRAM_ADDR_REG),
+            ai_len: Register::from_ptr(info.AI_LEN_REG),
+            ai_control: Register::from_ptr(info.AI_CONTROL_REG),
+            ai_status: Register::from_ptr(info.AI_STATUS_REG),
+            ai_dacrate: Register::from_ptr(info.AI_DACRATE_REG),
+            ai_bitrate: Register::from_ptr(info.AI_BITRATE_REG),
+            check_interrupts: info.CheckInterrupts,
+        }
+    }
+
+    pub fn rdram(&self) -> &[u8] {
+        unsafe { memory(self.rdram, RDRAM_SIZE) }
+    }
+
+    pub fn rdram_mut(&mut self) -> &mut [u8] {
+        unsafe { memory_mut(self.rdram, RDRAM_SIZE) }
+    }
+
+    pub fn dmem(&self) -> &[u8] {
+        unsafe { memory(self.dmem, SP_MEM_SIZE) }
+    }
+
+    pub fn dmem_mut(&mut self) -> &mut [u8] {
+        unsafe { memory_mut(self.dmem, SP_MEM_SIZE) }
+    }
+
+    pub fn imem(&self) -> &[u8] {
+        unsafe { memory(self.imem, SP_MEM_SIZE) }
+    }
+
+    pub fn imem_mut(&mut self) -> &mut [u8] {
+        unsafe { memory_mut(self.imem, SP_MEM_SIZE) }
+    }
+
+    /// Tells the core to check for interrupts raised through `mi_intr`.
+    pub fn check_interrupts(&self) {
+        if let Some(f) = self.check_interrupts {
+            unsafe { f() };
+        }
+    }
+
+    /// The sample rate set by the game, in Hz.
+    pub fn frequency(&self, system: SystemType) -> u32 {
+        system.vi_clock() / (self.ai_dacrate.get() + 1)
+    }
+
+    /// The buffer of interleaved stereo samples pointed to by `ai_dram_addr` and `ai_len`.
+    pub fn samples(&self) -> Vec<i16> {
+        let start = (self.ai_dram_addr.get() & 0x00FF_FFF8) as usize;
+        let len = (self.ai_len.get() & 0x0003_FFF8) as usize;
+
+        match self.rdram().get(start..start + len) {
+            Some(buffer) => buffer
+                .chunks_exact(4)
+                .flat_map(|word| {
+                    // Each word is a left and right sample
+                    let word = u32::from_ne_bytes([word[0], word[1], word[2], word[3]]);
+                    [(word >> 16) as i16, word as i16]
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// An audio plugin. Every method but `initiate_audio` has a default that does nothing.
+pub trait AudioPlugin: Send + 'static {
+    /// Called when emulation starts. Returning false stops it from starting.
+    fn initiate_audio(&mut self, info: AudioInfo) -> bool;
+
+    /// The game changed `ai_dacrate`; see `AudioInfo::frequency`.
+    fn ai_dacrate_changed(&mut self, _system: SystemType) {}
+
+    /// The game queued a buffer of samples; see `AudioInfo::samples`.
+    fn ai_len_changed(&mut self) {}
+
+    /// Called with an audio task for plugins that handle audio microcode (HLE) themselves.
+    fn process_alist(&mut self) {}
+
+    fn rom_open(&mut self) {}
+
+    fn rom_closed(&mut self) {}
+
+    /// The emulation speed changed, as a percentage of full speed.
+    fn set_speed_factor(&mut self, _percent: i32) {}
+
+    fn volume_up(&mut self) {}
+
+    fn volume_down(&mut self) {}
+
+    /// The volume as a percentage.
+    fn volume_level(&mut self) -> i32 {
+        100
+    }
+
+    fn set_volume_level(&mut self, _level: i32) {}
+
+    /// Toggles mute.
+    fn volume_mute(&mut self) {}
+
+    /// A description of the volume for on-screen display, e.g. `Mute` or `80%`.
+    fn volume_string(&mut self) -> String {
+        format!("{}%", self.volume_level())
+    }
+}
+
+/// The running audio plugin.
+pub static SLOT: Slot<dyn AudioPlugin> = Slot::new();
+
+/// Implementations of the audio plugin API, called by the functions `export_audio_plugin!`
+/// exports.
+#[doc(hidden)]
+pub mod ffi {
+    use super::*;
+    use std::ffi::CString;
+    use std::os::raw::{c_char, c_int};
+    use std::sync::Mutex;
+
+    pub unsafe fn initiate_audio(info: AUDIO_INFO) -> c_int {
+        let info = AudioInfo::from_ffi(&info);
+        SLOT.with(false, |p| p.initiate_audio(info)) as c_int
+    }
+
+    pub fn ai_dacrate_changed(system_type: c_int) {
+        SLOT.with((), |p| p.ai_dacrate_changed((system_type as m64p_system_type).into()));
+    }
+
+    pub fn ai_len_changed() {
+        SLOT.with((), |p| p.ai_len_changed());
+    }
+
+    pub fn process_alist() {
+        SLOT.with((), |p| p.process_alist());
+    }
+
+    pub fn rom_open() -> c_int {
+        SLOT.with((), |p| p.rom_open());
+        1
+    }
+
+    pub fn rom_closed() {
+        SLOT.with((), |p| p.rom_closed());
+    }
+
+    pub fn set_speed_factor(percent: c_int) {
+        SLOT.with((), |p| p.set_speed_factor(percent));
+    }
+
+    pub fn volume_up() {
+        SLOT.with((), |p| p.volume_up());
+    }
+
+    pub fn volume_down() {
+        SLOT.with((), |p| p.volume_down());
+    }
+
+    pub fn volume_get_level() -> c_int {
+        SLOT.with(0, |p| p.volume_level())
+    }
+
+    pub fn volume_set_level(level: c_int) {
+        SLOT.with((), |p| p.set_volume_level(level));
+    }
+
+    pub fn volume_mute() {
+        SLOT.with((), |p| p.volume_mute());
+    }
+
+    /// The string stays valid until the next call, like in the C plugins.
+    pub fn volume_get_string() -> *const c_char {
+        static STRING: Mutex<Option<CString>> = Mutex::new(None);
+
+        let s = SLOT.with(String::new(), |p| p.volume_string());
+        let mut string = STRING.lock().unwrap();
+        string.insert(CString::new(s.replace('\0', "")).unwrap()).as_ptr()
+    }
+}
+
+/// Exports the audio plugin functions other than `RomOpen` and `RomClosed`, which are shared
+/// between plugin types.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __export_audio_functions {
+    () => {
+        #[no_mangle]
+        pub unsafe extern "C" fn InitiateAudio(info: $crate::__sys::AUDIO_INFO) -> ::std::os::raw::c_int {
+            $crate::plugin::audio::ffi::initiate_audio(info)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn AiDacrateChanged(system_type: ::std::os::raw::c_int) {
+            $crate::plugin::audio::ffi::ai_dacrate_changed(system_type)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn AiLenChanged() {
+            $crate::plugin::audio::ffi::ai_len_changed()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn ProcessAList() {
+            $crate::plugin::audio::ffi::process_alist()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn SetSpeedFactor(percent: ::std::os::raw::c_int) {
+            $crate::plugin::audio::ffi::set_speed_factor(percent)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn VolumeUp() {
+            $crate::plugin::audio::ffi::volume_up()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn VolumeDown() {
+            $crate::plugin::audio::ffi::volume_down()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn VolumeGetLevel() -> ::std::os::raw::c_int {
+            $crate::plugin::audio::ffi::volume_get_level()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn VolumeSetLevel(level: ::std::os::raw::c_int) {
+            $crate::plugin::audio::ffi::volume_set_level(level)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn VolumeMute() {
+            $crate::plugin::audio::ffi::volume_mute()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn VolumeGetString() -> *const ::std::os::raw::c_char {
+            $crate::plugin::audio::ffi::volume_get_string()
+        }
+    };
+}
+
+/// Exports the audio plugin API for a type implementing `AudioPlugin`, `PluginInfo` and
+/// `Default`. Use this once, in a `cdylib` crate.
+#[macro_export]
+macro_rules! export_audio_plugin {
+    ($plugin:ty) => {
+        $crate::__export_plugin_common!(
+            $plugin,
+            $crate::plugin::PluginType::Audio,
+            $crate::plugin::version_to_mupen(&$crate::plugin::audio::AUDIO_API_VERSION),
+            $crate::plugin::audio::SLOT
+        );
+
+        $crate::__export_audio_functions!();
+
+        #[no_mangle]
+        pub extern "C" fn RomOpen() -> ::std::os::raw::c_int {
+            $crate::plugin::audio::ffi::rom_open()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn RomClosed() {
+            $crate::plugin::audio::ffi::rom_closed()
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples() {
+        let mut rdram = vec![0u8; RDRAM_SIZE];
+        let (mut addr, mut len) = (0x1000u32, 8u32);
+        rdram[0x1000..0x1004].copy_from_slice(&0x0001_FFFFu32.to_ne_bytes());
+        rdram[0x1004..0x1008].copy_from_slice(&0x8000_7FFFu32.to_ne_bytes());
+
+        let mut info: AUDIO_INFO = unsafe { std::mem::zeroed() };
+        info.RDRAM = rdram.as_mut_ptr();
+        info.AI_DRAM_ADDR_REG = &mut addr;
+        info.AI_LEN_REG = &mut len;
+        let info = unsafe { AudioInfo::from_ffi(&info) };
+
+        assert_eq!(info.samples(), vec![1, -1, -32768, 32767]);
+        assert_eq!(info.frequency(SystemType::Ntsc), 48_681_812);
+    }
+}
@@ -0,0 +1,282 @@
+//! An audio plugin that hands the game's audio to the frontend instead of playing it, for
+//! recording and headless tests.
+//!
+//! [`AudioCapture`] is a handle shared between the plugin and the host. Attach a clone of it with
+//! `Plugin::builtin_audio` (see the [`builtin`](crate::plugin::builtin) module):
+//!
+//! ```ignore
+//! let audio = AudioCapture::new();
+//! mupen.attach_plugin(Plugin::builtin_audio(audio.clone())?)?;
+//!
+//! audio.record_wav("game.wav")?;
+//! audio.on_samples(|samples, rate| hasher.update(samples));
+//! ```
+
+use super::{AudioInfo, AudioPlugin, SystemType};
+use crate::plugin::PluginInfo;
+use semver::Version;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Called with interleaved stereo samples and the sample rate in Hz.
+pub type SampleCallback = Box<dyn FnMut(&[i16], u32) + Send>;
+
+/// Writes 16-bit stereo PCM to a `.wav` file.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    /// Bytes of samples written so far.
+    len: u32,
+}
+
+const WAV_HEADER_SIZE: u32 = 44;
+/// The most sample bytes a `.wav` can hold, as the RIFF size has to fit in 32 bits: a little
+/// under 4 GiB, rounded down to whole stereo samples.
+const WAV_MAX_LEN: u32 = (u32::MAX - (WAV_HEADER_SIZE - 8)) & !3;
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P, rate: u32) -> io::Result<WavWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        file.write_all(b"RIFF")?;
+        file.write_all(&(WAV_HEADER_SIZE - 8).to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?; // PCM
+        file.write_all(&2u16.to_le_bytes())?; // Channels
+        file.write_all(&rate.to_le_bytes())?;
+        file.write_all(&(rate * 4).to_le_bytes())?; // Bytes per second
+        file.write_all(&4u16.to_le_bytes())?; // Bytes per frame
+        file.write_all(&16u16.to_le_bytes())?; // Bits per sample
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter { file, len: 0 })
+    }
+
+    /// Appends interleaved stereo samples. Once the file reaches the size limit of the format
+    /// (about 4 GiB), the samples that don't fit are dropped and an error is returned.
+    pub fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        let room = ((WAV_MAX_LEN - self.len) / 2) as usize;
+        for sample in samples.iter().take(room) {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.len += samples.len().min(room) as u32 * 2;
+
+        if samples.len() > room {
+            return Err(io::Error::other("reached the .wav size limit"));
+        }
+        Ok(())
+    }
+
+    /// Fills in the sizes in the header.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(WAV_HEADER_SIZE - 8 + self.len).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.len.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    info: Option<AudioInfo>,
+    rate: u32,
+    /// Where to record to, and the file once samples have arrived and the rate is known. Writing
+    /// stops at the first error.
+    wav_path: Option<std::path::PathBuf>,
+    wav: Option<WavWriter>,
+    samples: u64,
+}
+
+/// An audio plugin that captures the game's audio. Clones share the same capture.
+#[derive(Clone, Default)]
+pub struct AudioCapture {
+    inner: Arc<Mutex<Inner>>,
+    on_samples: Arc<Mutex<Option<SampleCallback>>>,
+}
+
+impl PluginInfo for AudioCapture {
+    const NAME: &'static str = "Audio Capture";
+    const VERSION: Version = Version::new(1, 0, 0);
+}
+
+impl AudioCapture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Calls `callback` with each buffer of samples the game plays.
+    pub fn on_samples<F>(&self, callback: F)
+    where
+        F: FnMut(&[i16], u32) + Send + 'static,
+    {
+        *self.on_samples.lock().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(callback));
+    }
+
+    /// The sample rate set by the game in Hz, or 0 if it hasn't set one yet.
+    pub fn frequency(&self) -> u32 {
+        self.lock().rate
+    }
+
+    /// The number of stereo samples captured so far.
+    pub fn sample_count(&self) -> u64 {
+        self.lock().samples
+    }
+
+    /// Start writing the audio to a `.wav` file, finishing any file already being written. The
+    /// file is created when the first samples arrive after the game has set the sample rate.
+    /// Writing stops when the file reaches the size limit of the format (about 4 GiB, or
+    /// 6 hours at 48 kHz).
+    pub fn record_wav<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut inner = self.lock();
+        if let Some(wav) = inner.wav.take() {
+            wav.finish()?;
+        }
+        inner.wav_path = Some(path.as_ref().to_owned());
+        Ok(())
+    }
+
+    /// Finish the `.wav` file being written, if any.
+    pub fn stop_wav(&self) -> io::Result<()> {
+        let mut inner = self.lock();
+        inner.wav_path = None;
+        match inner.wav.take() {
+            Some(wav) => wav.finish(),
+            None => Ok(()),
+        }
+    }
+
+    fn write_wav(inner: &mut Inner, samples: &[i16]) -> io::Result<()> {
+        if inner.wav.is_none() && inner.rate != 0 {
+            if let Some(path) = inner.wav_path.as_ref() {
+                inner.wav = Some(WavWriter::create(path, inner.rate)?);
+            }
+        }
+        match inner.wav.as_mut() {
+            Some(wav) => wav.write(samples),
+            None => Ok(()),
+        }
+    }
+}
+
+impl AudioPlugin for AudioCapture {
+    fn initiate_audio(&mut self, info: AudioInfo) -> bool {
+        self.lock().info = Some(info);
+        true
+    }
+
+    fn ai_dacrate_changed(&mut self, system: SystemType) {
+        let mut inner = self.lock();
+        if let Some(info) = &inner.info {
+            let rate = info.frequency(system);
+            if inner.wav.is_some() && rate != inner.rate {
+                log::warn!("sample rate changed to {} Hz while writing a .wav", rate);
+            }
+            inner.rate = rate;
+        }
+    }
+
+    fn ai_len_changed(&mut self) {
+        let (samples, rate) = {
+            let mut inner = self.lock();
+            let samples = match &inner.info {
+                Some(info) => info.samples(),
+                None => return,
+            };
+            inner.samples += samples.len() as u64 / 2;
+
+            if let Err(e) = Self::write_wav(&mut inner, &samples) {
+                log::error!("stopped writing .wav: {}", e);
+                inner.wav_path = None;
+                if let Some(Err(e)) = inner.wav.take().map(WavWriter::finish) {
+                    log::error!("failed to finish .wav: {}", e);
+                }
+            }
+            (samples, inner.rate)
+        };
+
+        if let Some(callback) = self.on_samples.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+            callback(&samples, rate);
+        }
+    }
+
+    fn rom_closed(&mut self) {
+        if let Err(e) = self.stop_wav() {
+            log::error!("failed to finish .wav: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::RDRAM_SIZE;
+    use mupen64plus_sys::AUDIO_INFO;
+
+    #[test]
+    fn capture_to_wav() {
+        let mut rdram = vec![0u8; RDRAM_SIZE];
+        let (mut addr, mut len, mut dacrate) = (0x100u32, 8u32, 1103u32);
+        rdram[0x100..0x104].copy_from_slice(&0x0001_FFFFu32.to_ne_bytes());
+        rdram[0x104..0x108].copy_from_slice(&0x7FFF_8000u32.to_ne_bytes());
+
+        let mut info: AUDIO_INFO = unsafe { std::mem::zeroed() };
+        info.RDRAM = rdram.as_mut_ptr();
+        info.AI_DRAM_ADDR_REG = &mut addr;
+        info.AI_LEN_REG = &mut len;
+        info.AI_DACRATE_REG = &mut dacrate;
+
+        let capture = AudioCapture::new();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let r = received.clone();
+        capture.on_samples(move |samples, rate| r.lock().unwrap().push((samples.to_vec(), rate)));
+
+        let path = std::env::temp_dir().join(format!("mupen64plus-capture-{}.wav", std::process::id()));
+        capture.record_wav(&path).unwrap();
+
+        let mut plugin = capture.clone();
+        assert!(plugin.initiate_audio(unsafe { AudioInfo::from_ffi(&info) }));
+        plugin.ai_dacrate_changed(SystemType::Ntsc);
+        plugin.ai_len_changed();
+        plugin.rom_closed();
+
+        assert_eq!(capture.frequency(), 44_095);
+        assert_eq!(capture.sample_count(), 2);
+        assert_eq!(*received.lock().unwrap(), vec![(vec![1, -1, 32767, -32768], 44_095)]);
+
+        let wav = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(wav.len(), 44 + 8);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[4..8], &44u32.to_le_bytes());
+        assert_eq!(&wav[24..28], &44_095u32.to_le_bytes());
+        assert_eq!(&wav[40..44], &8u32.to_le_bytes());
+        assert_eq!(&wav[44..], &[1, 0, 0xFF, 0xFF, 0xFF, 0x7F, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn wav_size_limit() {
+        let path = std::env::temp_dir().join(format!("mupen64plus-limit-{}.wav", std::process::id()));
+        let mut wav = WavWriter::create(&path, 44_100).unwrap();
+
+        // Pretend it's almost full
+        wav.len = WAV_MAX_LEN - 4;
+        assert!(wav.write(&[1, 2, 3, 4]).is_err());
+        assert_eq!(wav.len, WAV_MAX_LEN);
+        assert!(wav.write(&[5, 6]).is_err());
+        wav.finish().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(data.len(), 44 + 4);
+        assert_eq!(&data[4..8], &(WAV_MAX_LEN + 36).to_le_bytes());
+        assert_eq!(&data[40..44], &WAV_MAX_LEN.to_le_bytes());
+    }
+}
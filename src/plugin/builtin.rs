@@ -0,0 +1,438 @@
+//! Plugins built into the frontend executable, instead of loaded from a separate library.
+//!
+//! The core looks up a plugin's functions by name in the library handle it is given, so the
+//! executable has to export the plugin API itself. Call
+//! [`export_builtin_plugins!`](crate::export_builtin_plugins) once in the binary crate, and link
+//! it with `-rdynamic` (e.g. `RUSTFLAGS="-C link-arg=-rdynamic"`) so the functions are visible
+//! to the core. Then attach plugins created with `Plugin::builtin_video`, `builtin_audio`,
+//! `builtin_input` or `builtin_rsp`:
+//!
+//! ```ignore
+//! mupen64plus::export_builtin_plugins!();
+//!
+//! mupen.attach_plugin(Plugin::load_from_path(video_path)?)?;
+//! mupen.attach_plugin(Plugin::load_from_path(audio_path)?)?;
+//! mupen.attach_plugin(Plugin::builtin_input(MyInput::default())?)?;
+//! mupen.attach_plugin(Plugin::load_from_path(rsp_path)?)?;
+//! ```
+//!
+//! Built-in and loaded plugins can be mixed, but only one of each type can be built in.
+
+use super::export::{self, Description};
+use super::{audio, input, rsp, video};
+use super::{LoadError, Plugin, PluginInfo, PluginType};
+use audio::AudioPlugin;
+use input::InputPlugin;
+use rsp::RspPlugin;
+use video::VideoPlugin;
+use mupen64plus_sys::*;
+use std::os::raw::{c_char, c_int};
+use std::sync::Mutex;
+
+type StartFn = Box<dyn FnOnce() + Send>;
+
+/// A built-in plugin waiting to be attached.
+pub(crate) struct Builtin {
+    pub(crate) description: Description,
+    start: Mutex<Option<StartFn>>,
+}
+
+impl Builtin {
+    /// Starts the plugin, if it hasn't been already, for the core loaded as `core_lib`.
+    pub(crate) fn start(&self, core_lib: m64p_dynlib_handle) {
+        let start = self.start.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(start) = start {
+            register(self.description.clone());
+            unsafe { export::record_core_version(core_lib) };
+            start();
+        }
+    }
+}
+
+/// The order the core calls `RomOpen` in.
+const ROM_OPEN_ORDER: [PluginType; 3] = [PluginType::Gfx, PluginType::Audio, PluginType::Input];
+/// The order the core calls `RomClosed` in. If a `RomOpen` fails, the core only closes the
+/// plugins opened before it, in this order.
+const ROM_CLOSED_ORDER: [PluginType; 4] = [PluginType::Rsp, PluginType::Input, PluginType::Audio, PluginType::Gfx];
+
+struct Registry {
+    /// The running built-in plugins.
+    plugins: Vec<Description>,
+    /// The type being passed to `CoreAttachPlugin`, which `PluginGetVersion` reports.
+    attaching: Option<PluginType>,
+    /// The built-in plugins whose `RomOpen` has succeeded and whose `RomClosed` hasn't been
+    /// called, to work out which plugin each call of the shared functions is for.
+    open: Vec<PluginType>,
+    /// Whether the RSP plugin, which has no `RomOpen`, has been closed since the others opened.
+    rsp_closed: bool,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry::new());
+
+impl Registry {
+    const fn new() -> Self {
+        Registry {
+            plugins: Vec::new(),
+            attaching: None,
+            open: Vec::new(),
+            rsp_closed: false,
+        }
+    }
+
+    fn is_builtin(&self, plugin_type: PluginType) -> bool {
+        self.plugins.iter().any(|d| d.plugin_type == plugin_type)
+    }
+
+    /// The plugin the next `RomOpen` is for: the first built-in one that isn't open yet.
+    fn next_open(&self) -> Option<PluginType> {
+        ROM_OPEN_ORDER.iter().copied().find(|&t| self.is_builtin(t) && !self.open.contains(&t))
+    }
+
+    fn opened(&mut self, plugin_type: PluginType) {
+        self.open.push(plugin_type);
+        self.rsp_closed = false;
+    }
+
+    /// The plugin the next `RomClosed` is for: the first open built-in one. The core closes the
+    /// RSP plugin first, but only once every plugin opened.
+    fn next_closed(&self) -> Option<PluginType> {
+        let all_open = ROM_OPEN_ORDER.iter().all(|&t| !self.is_builtin(t) || self.open.contains(&t));
+        let others_builtin = ROM_OPEN_ORDER.iter().any(|&t| self.is_builtin(t));
+
+        ROM_CLOSED_ORDER.iter().copied().filter(|&t| self.is_builtin(t)).find(|&t| match t {
+            PluginType::Rsp => !others_builtin || (all_open && !self.rsp_closed),
+            _ => self.open.contains(&t),
+        })
+    }
+
+    fn closed(&mut self, plugin_type: PluginType) {
+        match plugin_type {
+            PluginType::Rsp => self.rsp_closed = true,
+            _ => self.open.retain(|&t| t != plugin_type),
+        }
+    }
+}
+
+fn registry() -> std::sync::MutexGuard<'static, Registry> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn register(description: Description) {
+    let mut registry = registry();
+    registry.plugins.retain(|d| d.plugin_type != description.plugin_type);
+    registry.open.retain(|&t| t != description.plugin_type);
+    registry.plugins.push(description);
+}
+
+fn unregister(plugin_type: PluginType) {
+    let mut registry = registry();
+    registry.plugins.retain(|d| d.plugin_type != plugin_type);
+    registry.open.retain(|&t| t != plugin_type);
+}
+
+/// Calls `attach` (which calls `CoreAttachPlugin`) with `PluginGetVersion` reporting
+/// `plugin_type`.
+pub(crate) fn attaching<R>(plugin_type: PluginType, attach: impl FnOnce() -> R) -> R {
+    registry().attaching = Some(plugin_type);
+    let result = attach();
+    registry().attaching = None;
+    result
+}
+
+/// A handle to the running executable, for the core to look up the exported functions in.
+fn this_process() -> Result<m64p_dynlib_handle, LoadError> {
+    #[cfg(unix)]
+    let lib = libloading::os::unix::Library::this();
+    #[cfg(windows)]
+    let lib = libloading::os::windows::Library::this()?;
+
+    Ok(lib.into_raw())
+}
+
+impl Plugin {
+    fn builtin(description: Description, start: Box<dyn FnOnce() + Send>, shutdown: ptr_PluginShutdown) -> Result<Self, LoadError> {
+        Ok(Plugin {
+            lib: this_process()?,
+            plugin_get_version: None,
+            plugin_startup: None,
+            plugin_shutdown: shutdown,
+            builtin: Some(Builtin {
+                description,
+                start: Mutex::new(Some(start)),
+            }),
+        })
+    }
+
+    /// A video plugin built into the executable; see the [`builtin`](self) module.
+    pub fn builtin_video<P: VideoPlugin + PluginInfo>(plugin: P) -> Result<Self, LoadError> {
+        Self::builtin(
+            Description::of::<P>(PluginType::Gfx, super::version_to_mupen(&video::VIDEO_API_VERSION)),
+            Box::new(move || video::SLOT.start(Box::new(plugin))),
+            Some(shutdown_video),
+        )
+    }
+
+    /// An audio plugin built into the executable; see the [`builtin`](self) module.
+    pub fn builtin_audio<P: AudioPlugin + PluginInfo>(plugin: P) -> Result<Self, LoadError> {
+        Self::builtin(
+            Description::of::<P>(PluginType::Audio, super::version_to_mupen(&audio::AUDIO_API_VERSION)),
+            Box::new(move || audio::SLOT.start(Box::new(plugin))),
+            Some(shutdown_audio),
+        )
+    }
+
+    /// An input plugin built into the executable; see the [`builtin`](self) module.
+    pub fn builtin_input<P: InputPlugin + PluginInfo>(plugin: P) -> Result<Self, LoadError> {
+        Self::builtin(
+            Description::of::<P>(PluginType::Input, super::version_to_mupen(&input::INPUT_API_VERSION)),
+            Box::new(move || input::SLOT.start(Box::new(plugin))),
+            Some(shutdown_input),
+        )
+    }
+
+    /// An RSP plugin built into the executable; see the [`builtin`](self) module.
+    pub fn builtin_rsp<P: RspPlugin + PluginInfo>(plugin: P) -> Result<Self, LoadError> {
+        Self::builtin(
+            Description::of::<P>(PluginType::Rsp, super::version_to_mupen(&rsp::RSP_API_VERSION)),
+            Box::new(move || rsp::SLOT.start(Box::new(plugin))),
+            Some(shutdown_rsp),
+        )
+    }
+}
+
+unsafe extern "C" fn shutdown_video() -> m64p_error {
+    unregister(PluginType::Gfx);
+    export::shutdown(&video::SLOT)
+}
+
+unsafe extern "C" fn shutdown_audio() -> m64p_error {
+    unregister(PluginType::Audio);
+    export::shutdown(&audio::SLOT)
+}
+
+unsafe extern "C" fn shutdown_input() -> m64p_error {
+    unregister(PluginType::Input);
+    export::shutdown(&input::SLOT)
+}
+
+unsafe extern "C" fn shutdown_rsp() -> m64p_error {
+    unregister(PluginType::Rsp);
+    export::shutdown(&rsp::SLOT)
+}
+
+/// Implementations of the functions shared between plugin types, called by the functions
+/// `export_builtin_plugins!` exports.
+#[doc(hidden)]
+pub mod ffi {
+    use super::*;
+
+    /// Reports the plugin being attached, or the last one attached.
+    pub unsafe fn get_version(
+        out_type: *mut m64p_plugin_type,
+        out_version: *mut c_int,
+        out_api_version: *mut c_int,
+        out_name: *mut *const c_char,
+        out_capabilities: *mut c_int,
+    ) -> m64p_error {
+        let registry = registry();
+        let description = match registry.attaching {
+            Some(plugin_type) => registry.plugins.iter().find(|d| d.plugin_type == plugin_type),
+            None => registry.plugins.last(),
+        };
+
+        match description {
+            Some(description) => {
+                export::get_version(description, out_type, out_version, out_api_version, out_name, out_capabilities)
+            }
+            None => m64p_error_M64ERR_NOT_INIT,
+        }
+    }
+
+    pub fn rom_open() -> c_int {
+        let plugin_type = registry().next_open();
+
+        let ok = match plugin_type {
+            Some(PluginType::Gfx) => video::ffi::rom_open(),
+            Some(PluginType::Audio) => audio::ffi::rom_open(),
+            Some(PluginType::Input) => input::ffi::rom_open(),
+            _ => 1,
+        };
+        if let (Some(plugin_type), true) = (plugin_type, ok != 0) {
+            registry().opened(plugin_type);
+        }
+        ok
+    }
+
+    pub fn rom_closed() {
+        let plugin_type = {
+            let mut registry = registry();
+            let plugin_type = registry.next_closed();
+            if let Some(plugin_type) = plugin_type {
+                registry.closed(plugin_type);
+            }
+            plugin_type
+        };
+
+        match plugin_type {
+            Some(PluginType::Gfx) => video::ffi::rom_closed(),
+            Some(PluginType::Audio) => audio::ffi::rom_closed(),
+            Some(PluginType::Input) => input::ffi::rom_closed(),
+            Some(PluginType::Rsp) => rsp::ffi::rom_closed(),
+            _ => {}
+        }
+    }
+}
+
+/// Exports the whole plugin API from the frontend executable, for plugins attached with
+/// `Plugin::builtin_video` and friends. Use this once, in a binary crate linked with `-rdynamic`.
+#[macro_export]
+macro_rules! export_builtin_plugins {
+    () => {
+        #[no_mangle]
+        pub unsafe extern "C" fn PluginGetVersion(
+            plugin_type: *mut $crate::__sys::m64p_plugin_type,
+            plugin_version: *mut ::std::os::raw::c_int,
+            api_version: *mut ::std::os::raw::c_int,
+            plugin_name: *mut *const ::std::os::raw::c_char,
+            capabilities: *mut ::std::os::raw::c_int,
+        ) -> $crate::__sys::m64p_error {
+            $crate::plugin::builtin::ffi::get_version(plugin_type, plugin_version, api_version, plugin_name, capabilities)
+        }
+
+        $crate::__export_video_functions!();
+        $crate::__export_audio_functions!();
+        $crate::__export_input_functions!();
+        $crate::__export_rsp_functions!();
+
+        #[no_mangle]
+        pub extern "C" fn RomOpen() -> ::std::os::raw::c_int {
+            $crate::plugin::builtin::ffi::rom_open()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn RomClosed() {
+            $crate::plugin::builtin::ffi::rom_closed()
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use semver::Version;
+
+    fn description(plugin_type: PluginType) -> Description {
+        Description {
+            plugin_type,
+            api_version: 0,
+            name: "test",
+            version: Version::new(0, 0, 0),
+        }
+    }
+
+    #[derive(Default)]
+    struct Idle;
+
+    impl PluginInfo for Idle {
+        const NAME: &'static str = "Idle";
+        const VERSION: Version = Version::new(1, 2, 3);
+    }
+
+    impl InputPlugin for Idle {
+        fn initiate_controllers(&mut self) -> [input::ControllerConfig; 4] {
+            Default::default()
+        }
+
+        fn get_keys(&mut self, _controller: usize) -> input::ControllerState {
+            Default::default()
+        }
+    }
+
+    #[test]
+    fn builtin_version() {
+        let plugin = Plugin::builtin_input(Idle).unwrap();
+        let version = plugin.get_version().unwrap();
+        assert_eq!(version.plugin_type, PluginType::Input);
+        assert_eq!(version.plugin_name, "Idle");
+        assert_eq!(version.plugin_version, Version::new(1, 2, 3));
+
+        plugin.builtin.as_ref().unwrap().start(std::ptr::null_mut());
+        assert!(input::SLOT.is_running());
+
+        let (mut plugin_type, mut api_version) = (0, 0);
+        let ret = attaching(PluginType::Input, || unsafe {
+            let null = std::ptr::null_mut();
+            ffi::get_version(&mut plugin_type, null, &mut api_version, null as _, null)
+        });
+        assert_eq!(ret, m64p_error_M64ERR_SUCCESS);
+        assert_eq!(PluginType::from(plugin_type), PluginType::Input);
+        assert_eq!(api_version, 0x020100);
+
+        assert_eq!(unsafe { plugin.plugin_shutdown.unwrap()() }, m64p_error_M64ERR_SUCCESS);
+        assert!(!input::SLOT.is_running());
+    }
+
+    fn registry_of(types: &[PluginType]) -> Registry {
+        let mut registry = Registry::new();
+        registry.plugins = types.iter().map(|&t| description(t)).collect();
+        registry
+    }
+
+    fn open(registry: &mut Registry, ok: bool) -> Option<PluginType> {
+        let plugin_type = registry.next_open();
+        if let (Some(t), true) = (plugin_type, ok) {
+            registry.opened(t);
+        }
+        plugin_type
+    }
+
+    fn close(registry: &mut Registry) -> Option<PluginType> {
+        let plugin_type = registry.next_closed();
+        if let Some(t) = plugin_type {
+            registry.closed(t);
+        }
+        plugin_type
+    }
+
+    #[test]
+    fn dispatch_order() {
+        // Video loaded from a library, the rest built in
+        let mut registry = registry_of(&[PluginType::Input, PluginType::Audio, PluginType::Rsp]);
+
+        assert_eq!(open(&mut registry, true), Some(PluginType::Audio));
+        assert_eq!(open(&mut registry, true), Some(PluginType::Input));
+        assert_eq!(close(&mut registry), Some(PluginType::Rsp));
+        assert_eq!(close(&mut registry), Some(PluginType::Input));
+        assert_eq!(close(&mut registry), Some(PluginType::Audio));
+        assert_eq!(close(&mut registry), None);
+
+        // The same again
+        assert_eq!(open(&mut registry, true), Some(PluginType::Audio));
+        assert_eq!(open(&mut registry, true), Some(PluginType::Input));
+        assert_eq!(close(&mut registry), Some(PluginType::Rsp));
+
+        // Only the RSP plugin built in
+        let mut registry = registry_of(&[PluginType::Rsp]);
+        assert_eq!(open(&mut registry, true), None);
+        assert_eq!(close(&mut registry), Some(PluginType::Rsp));
+        assert_eq!(close(&mut registry), Some(PluginType::Rsp));
+    }
+
+    #[test]
+    fn partial_open() {
+        let mut registry = registry_of(&[PluginType::Input, PluginType::Audio, PluginType::Rsp]);
+
+        // Input fails to open, so the core only closes audio
+        assert_eq!(open(&mut registry, true), Some(PluginType::Audio));
+        assert_eq!(open(&mut registry, false), Some(PluginType::Input));
+        assert_eq!(close(&mut registry), Some(PluginType::Audio));
+        assert_eq!(close(&mut registry), None);
+
+        // The next open starts over
+        assert_eq!(open(&mut registry, true), Some(PluginType::Audio));
+        assert_eq!(open(&mut registry, true), Some(PluginType::Input));
+        assert_eq!(close(&mut registry), Some(PluginType::Rsp));
+        assert_eq!(close(&mut registry), Some(PluginType::Input));
+        assert_eq!(close(&mut registry), Some(PluginType::Audio));
+    }
+}
@@ -0,0 +1,284 @@
+//! Runtime support for the plugin export macros, such as `export_input_plugin!`. Plugin authors
+//! shouldn't need anything in here directly.
+
+use super::{version_to_mupen, PluginInfo, PluginType};
+use mupen64plus_sys::*;
+use std::collections::HashMap;
+use std::ffi::CString;
+use semver::Version;
+use std::os::raw::{c_char, c_int, c_void};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+
+/// Holds the running instance of one type of plugin, between `PluginStartup` and `PluginShutdown`.
+pub struct Slot<P: ?Sized> {
+    instance: Mutex<Option<Box<P>>>,
+}
+
+impl<P: ?Sized> Slot<P> {
+    pub const fn new() -> Self {
+        Slot {
+            instance: Mutex::new(None),
+        }
+    }
+
+    pub fn start(&self, plugin: Box<P>) {
+        *self.instance.lock().unwrap() = Some(plugin);
+    }
+
+    pub fn stop(&self) {
+        self.instance.lock().unwrap().take();
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.instance.lock().unwrap().is_some()
+    }
+
+    /// Calls `f` with the plugin, or returns `default` if it isn't running (the core calls some
+    /// functions before `PluginStartup` or after `PluginShutdown`).
+    ///
+    /// The slot stays locked while `f` runs, so calls into the core that call back into the same
+    /// plugin (such as a video plugin sending `M64CMD_READ_SCREEN` from `update_screen`)
+    /// deadlock. Calls from other threads wait until `f` returns.
+    pub fn with<R>(&self, default: R, f: impl FnOnce(&mut P) -> R) -> R {
+        match self.instance.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+            Some(plugin) => f(plugin),
+            None => default,
+        }
+    }
+}
+
+impl<P: ?Sized> Default for Slot<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns a NUL-terminated copy of `s` that lives for the rest of the program.
+pub fn static_cstr(s: &'static str) -> *const c_char {
+    static STRINGS: Mutex<Option<HashMap<&'static str, CString>>> = Mutex::new(None);
+
+    let mut strings = STRINGS.lock().unwrap();
+    // The CString's buffer doesn't move when the map grows, so the pointer stays valid
+    strings
+        .get_or_insert_with(HashMap::new)
+        .entry(s)
+        .or_insert_with(|| CString::new(s.replace('\0', "")).unwrap())
+        .as_ptr()
+}
+
+/// What a plugin reports from `PluginGetVersion`.
+#[derive(Debug, Clone)]
+pub struct Description {
+    pub plugin_type: PluginType,
+    pub api_version: i32,
+    pub name: &'static str,
+    pub version: semver::Version,
+}
+
+impl Description {
+    pub fn of<T: PluginInfo>(plugin_type: PluginType, api_version: i32) -> Self {
+        Description {
+            plugin_type,
+            api_version,
+            name: T::NAME,
+            version: T::VERSION,
+        }
+    }
+}
+
+/// Implements `PluginGetVersion`.
+///
+/// # Safety
+/// Each pointer must be null or valid for writes.
+pub unsafe fn get_version(
+    description: &Description,
+    out_type: *mut m64p_plugin_type,
+    out_version: *mut c_int,
+    out_api_version: *mut c_int,
+    out_name: *mut *const c_char,
+    out_capabilities: *mut c_int,
+) -> m64p_error {
+    if !out_type.is_null() {
+        *out_type = description.plugin_type.into();
+    }
+    if !out_version.is_null() {
+        *out_version = version_to_mupen(&description.version);
+    }
+    if !out_api_version.is_null() {
+        *out_api_version = description.api_version;
+    }
+    if !out_name.is_null() {
+        *out_name = static_cstr(description.name);
+    }
+    if !out_capabilities.is_null() {
+        *out_capabilities = 0;
+    }
+    m64p_error_M64ERR_SUCCESS
+}
+
+/// The version of the core that started the plugin as a mupen64plus version number, or 0 if it
+/// isn't known.
+static CORE_VERSION: AtomicI32 = AtomicI32::new(0);
+
+/// Records the version of the core, from the `PluginGetVersion` in the library handle given to
+/// `PluginStartup`.
+///
+/// # Safety
+/// `core_lib` must be null or a handle to a loaded library.
+pub unsafe fn record_core_version(core_lib: m64p_dynlib_handle) {
+    #[cfg(unix)]
+    use libloading::os::unix::Library;
+    #[cfg(windows)]
+    use libloading::os::windows::Library;
+
+    if core_lib.is_null() {
+        return;
+    }
+
+    // Borrow the handle without closing it
+    let lib = Library::from_raw(core_lib as _);
+    let version = match lib.get::<ptr_PluginGetVersion>(b"PluginGetVersion\0").ok().and_then(|f| *f) {
+        Some(get_version) => {
+            let (mut plugin_type, mut version) = (0, 0);
+            let null = std::ptr::null_mut();
+            let ret = get_version(&mut plugin_type, &mut version, null, null as _, null);
+            if ret == m64p_error_M64ERR_SUCCESS && plugin_type == m64p_plugin_type_M64PLUGIN_CORE {
+                version
+            } else {
+                0
+            }
+        }
+        None => 0,
+    };
+    let _ = lib.into_raw();
+
+    CORE_VERSION.store(version, Ordering::Relaxed);
+}
+
+/// The version of the core that started the plugin, if it is known. Parts of the plugin API,
+/// such as the fields at the end of `GFX_INFO`, depend on it.
+pub fn core_version() -> Option<Version> {
+    match CORE_VERSION.load(Ordering::Relaxed) {
+        0 => None,
+        version => Some(super::mupen_to_version(version)),
+    }
+}
+
+/// Implements `PluginStartup`: starts the plugin and sends its `log` output to the core.
+///
+/// # Safety
+/// `core_lib` must be null or a handle to a loaded library.
+pub unsafe fn startup<P: ?Sized>(
+    slot: &Slot<P>,
+    plugin: Box<P>,
+    core_lib: m64p_dynlib_handle,
+    context: *mut c_void,
+    debug_callback: DebugCb,
+) -> m64p_error {
+    if slot.is_running() {
+        return m64p_error_M64ERR_ALREADY_INIT;
+    }
+
+    install_logger(context, debug_callback);
+    record_core_version(core_lib);
+    slot.start(plugin);
+    m64p_error_M64ERR_SUCCESS
+}
+
+/// Implements `PluginShutdown`.
+pub fn shutdown<P: ?Sized>(slot: &Slot<P>) -> m64p_error {
+    if !slot.is_running() {
+        return m64p_error_M64ERR_NOT_INIT;
+    }
+
+    slot.stop();
+    m64p_error_M64ERR_SUCCESS
+}
+
+/// Forwards `log` records to the debug callback given to `PluginStartup`.
+struct CoreLogger {
+    /// The callback and its context pointer (as a usize, so it can be shared between threads).
+    callback: Mutex<Option<(unsafe extern "C" fn(*mut c_void, c_int, *const c_char), usize)>>,
+}
+
+static LOGGER: CoreLogger = CoreLogger {
+    callback: Mutex::new(None),
+};
+
+impl log::Log for CoreLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let callback = *self.callback.lock().unwrap();
+
+        if let Some((callback, context)) = callback {
+            let level = match record.level() {
+                log::Level::Error => m64p_msg_level_M64MSG_ERROR,
+                log::Level::Warn => m64p_msg_level_M64MSG_WARNING,
+                log::Level::Info => m64p_msg_level_M64MSG_INFO,
+                log::Level::Debug | log::Level::Trace => m64p_msg_level_M64MSG_VERBOSE,
+            };
+            let message = CString::new(record.args().to_string().replace('\0', "")).unwrap();
+
+            unsafe { callback(context as *mut c_void, level as c_int, message.as_ptr()) };
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger, unless the program already has one (e.g. a plugin built into the
+/// frontend).
+fn install_logger(context: *mut c_void, debug_callback: DebugCb) {
+    if let Some(callback) = debug_callback {
+        *LOGGER.callback.lock().unwrap() = Some((callback, context as usize));
+
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(log::LevelFilter::Trace);
+        }
+    }
+}
+
+/// Exports the functions every plugin has: `PluginGetVersion`, `PluginStartup` and
+/// `PluginShutdown`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __export_plugin_common {
+    ($plugin:ty, $plugin_type:expr, $api_version:expr, $slot:expr) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn PluginGetVersion(
+            plugin_type: *mut $crate::__sys::m64p_plugin_type,
+            plugin_version: *mut ::std::os::raw::c_int,
+            api_version: *mut ::std::os::raw::c_int,
+            plugin_name: *mut *const ::std::os::raw::c_char,
+            capabilities: *mut ::std::os::raw::c_int,
+        ) -> $crate::__sys::m64p_error {
+            $crate::plugin::export::get_version(
+                &$crate::plugin::export::Description::of::<$plugin>($plugin_type, $api_version),
+                plugin_type,
+                plugin_version,
+                api_version,
+                plugin_name,
+                capabilities,
+            )
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn PluginStartup(
+            core_lib: $crate::__sys::m64p_dynlib_handle,
+            context: *mut ::std::os::raw::c_void,
+            debug_callback: $crate::__sys::DebugCb,
+        ) -> $crate::__sys::m64p_error {
+            let plugin: $plugin = ::std::default::Default::default();
+            $crate::plugin::export::startup(&$slot, Box::new(plugin), core_lib, context, debug_callback)
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn PluginShutdown() -> $crate::__sys::m64p_error {
+            $crate::plugin::export::shutdown(&$slot)
+        }
+    };
+}
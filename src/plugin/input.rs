@@ -0,0 +1,353 @@
+//! Writing input plugins in Rust.
+//!
+//! Implement [`InputPlugin`] and [`PluginInfo`](super::PluginInfo) for a `Default` type, then
+//! export it from a `cdylib` crate with [`export_input_plugin!`](crate::export_input_plugin):
+//!
+//! ```ignore
+//! use mupen64plus::plugin::input::{Buttons, ControllerConfig, ControllerState, InputPlugin};
+//! use mupen64plus::plugin::{PluginInfo, Version};
+//!
+//! #[derive(Default)]
+//! struct HoldA;
+//!
+//! impl PluginInfo for HoldA {
+//!     const NAME: &'static str = "Hold A";
+//!     const VERSION: Version = Version::new(1, 0, 0);
+//! }
+//!
+//! impl InputPlugin for HoldA {
+//!     fn initiate_controllers(&mut self) -> [ControllerConfig; 4] {
+//!         [ControllerConfig::PRESENT, Default::default(), Default::default(), Default::default()]
+//!     }
+//!
+//!     fn get_keys(&mut self, _controller: usize) -> ControllerState {
+//!         ControllerState { buttons: Buttons::A, ..Default::default() }
+//!     }
+//! }
+//!
+//! mupen64plus::export_input_plugin!(HoldA);
+//! ```
+//!
+//! The resulting library is loaded with `Plugin::load_from_path` like any other input plugin.
+
+use super::export::Slot;
+use bitflags::bitflags;
+use mupen64plus_sys::*;
+use semver::Version;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub mod scripted;
+
+/// The version of the input plugin API implemented by `export_input_plugin!`.
+pub const INPUT_API_VERSION: Version = super::mupen_to_version(0x020100);
+
+bitflags! {
+    /// The buttons in a `BUTTONS` value.
+    #[derive(Default)]
+    pub struct Buttons: u16 {
+        const R_DPAD = 0x0001;
+        const L_DPAD = 0x0002;
+        const D_DPAD = 0x0004;
+        const U_DPAD = 0x0008;
+        const START = 0x0010;
+        const Z = 0x0020;
+        const B = 0x0040;
+        const A = 0x0080;
+        const R_CBUTTON = 0x0100;
+        const L_CBUTTON = 0x0200;
+        const D_CBUTTON = 0x0400;
+        const U_CBUTTON = 0x0800;
+        const R_TRIG = 0x1000;
+        const L_TRIG = 0x2000;
+    }
+}
+
+/// The state of a controller's buttons and analog stick.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Hash)]
+pub struct ControllerState {
+    pub buttons: Buttons,
+    /// Stick position, positive to the right.
+    pub x: i8,
+    /// Stick position, positive upwards.
+    pub y: i8,
+}
+
+impl From<u32> for ControllerState {
+    /// Decodes the value of a `BUTTONS` union.
+    fn from(value: u32) -> Self {
+        ControllerState {
+            buttons: Buttons::from_bits_truncate(value as u16),
+            x: (value >> 16) as i8,
+            y: (value >> 24) as i8,
+        }
+    }
+}
+
+impl From<ControllerState> for u32 {
+    /// Encodes the value of a `BUTTONS` union.
+    fn from(state: ControllerState) -> Self {
+        state.buttons.bits() as u32 | (state.x as u8 as u32) << 16 | (state.y as u8 as u32) << 24
+    }
+}
+
+/// The accessory plugged into a controller.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub enum Pak {
+    #[default]
+    None,
+    MemPak,
+    RumblePak,
+    TransferPak,
+    /// The plugin handles the pak itself via `controller_command` and `read_controller`.
+    Raw,
+    BioPak,
+}
+
+impl From<Pak> for std::os::raw::c_int {
+    fn from(pak: Pak) -> Self {
+        (match pak {
+            Pak::None => PLUGIN_NONE,
+            Pak::MemPak => PLUGIN_MEMPAK,
+            Pak::RumblePak => PLUGIN_RUMBLE_PAK,
+            Pak::TransferPak => PLUGIN_TRANSFER_PAK,
+            Pak::Raw => PLUGIN_RAW,
+            Pak::BioPak => PLUGIN_BIO_PAK,
+        }) as std::os::raw::c_int
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, Default)]
+pub enum ControllerType {
+    #[default]
+    Standard,
+    /// The Voice Recognition Unit.
+    Vru,
+}
+
+/// How a controller port is set up, as returned from `InputPlugin::initiate_controllers`. The
+/// default is an empty port.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Hash)]
+pub struct ControllerConfig {
+    pub present: bool,
+    /// Send the raw PIF commands for this controller to `controller_command` and
+    /// `read_controller` instead of calling `get_keys`.
+    pub raw_data: bool,
+    pub pak: Pak,
+    pub controller_type: ControllerType,
+}
+
+impl ControllerConfig {
+    /// A standard controller with nothing plugged in.
+    pub const PRESENT: ControllerConfig = ControllerConfig {
+        present: true,
+        raw_data: false,
+        pak: Pak::None,
+        controller_type: ControllerType::Standard,
+    };
+}
+
+/// An input plugin. Every method but `initiate_controllers` and `get_keys` has a default that
+/// does nothing.
+pub trait InputPlugin: Send + 'static {
+    /// Called when emulation starts, to set up the four controller ports.
+    fn initiate_controllers(&mut self) -> [ControllerConfig; 4];
+
+    /// Returns the state of a controller (0 to 3) that isn't in raw mode. Called once per input
+    /// poll by the game.
+    fn get_keys(&mut self, controller: usize) -> ControllerState;
+
+    /// Handles a raw PIF command for a controller with `raw_data` set, before the PIF processes
+    /// it. `command` is the transmit and receive length bytes, the transmitted bytes, then room
+    /// for the response. `controller` is -1 once all commands have been sent.
+    fn controller_command(&mut self, _controller: i32, _command: &mut [u8]) {}
+
+    /// Like `controller_command`, but called when the game reads the response.
+    fn read_controller(&mut self, _controller: i32, _command: &mut [u8]) {}
+
+    fn rom_open(&mut self) {}
+
+    fn rom_closed(&mut self) {}
+
+    /// A key was pressed in the emulator window. `keysym` and `keymod` are SDL values.
+    fn sdl_key_down(&mut self, _keymod: i32, _keysym: i32) {}
+
+    fn sdl_key_up(&mut self, _keymod: i32, _keysym: i32) {}
+}
+
+/// The running input plugin.
+pub static SLOT: Slot<dyn InputPlugin> = Slot::new();
+
+/// Set when the game reads a controller, for telling lag frames apart.
+static POLLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the game has read a controller since the last call.
+pub(crate) fn take_polled() -> bool {
+    POLLED.swap(false, Ordering::Relaxed)
+}
+
+/// Implementations of the input plugin API, called by the functions `export_input_plugin!`
+/// exports.
+#[doc(hidden)]
+pub mod ffi {
+    use super::*;
+    use std::os::raw::{c_int, c_uchar};
+
+    /// The size of PIF RAM, which commands point into. The last byte is the PIF's control byte.
+    const PIF_RAM_SIZE: usize = 64;
+
+    /// The length of a PIF command: the two length bytes, then the transmitted and received bytes.
+    ///
+    /// The core doesn't say where in PIF RAM the command is, so a command whose lengths would
+    /// reach past the control byte from the start of PIF RAM is ignored; the core doesn't set
+    /// up channels past the end of PIF RAM either.
+    unsafe fn pif_command<'a>(command: *mut c_uchar) -> Option<&'a mut [u8]> {
+        if command.is_null() {
+            return None;
+        }
+        let tx = (*command & 0x3F) as usize;
+        let rx = (*command.add(1) & 0x3F) as usize;
+        if 2 + tx + rx > PIF_RAM_SIZE - 1 {
+            log::warn!("input: ignoring PIF command longer than PIF RAM ({} + {} bytes)", tx, rx);
+            return None;
+        }
+        Some(std::slice::from_raw_parts_mut(command, 2 + tx + rx))
+    }
+
+    pub unsafe fn initiate_controllers(info: CONTROL_INFO) {
+        let configs = SLOT.with(None, |p| Some(p.initiate_controllers()));
+
+        if let (Some(configs), false) = (configs, info.Controls.is_null()) {
+            for (i, config) in configs.iter().enumerate() {
+                let control = &mut *info.Controls.add(i);
+                control.Present = config.present as c_int;
+                control.RawData = config.raw_data as c_int;
+                control.Plugin = config.pak.into();
+                control.Type = match config.controller_type {
+                    ControllerType::Standard => CONT_TYPE_STANDARD,
+                    ControllerType::Vru => CONT_TYPE_VRU,
+                } as c_int;
+            }
+        }
+    }
+
+    pub unsafe fn get_keys(controller: c_int, keys: *mut BUTTONS) {
+        POLLED.store(true, Ordering::Relaxed);
+        let state = SLOT.with(ControllerState::default(), |p| p.get_keys(controller as usize));
+        if !keys.is_null() {
+            (*keys).Value = state.into();
+        }
+    }
+
+    pub unsafe fn controller_command(controller: c_int, command: *mut c_uchar) {
+        if let Some(command) = pif_command(command) {
+            SLOT.with((), |p| p.controller_command(controller, command));
+        }
+    }
+
+    pub unsafe fn read_controller(controller: c_int, command: *mut c_uchar) {
+        if controller >= 0 {
+            POLLED.store(true, Ordering::Relaxed);
+        }
+        if let Some(command) = pif_command(command) {
+            SLOT.with((), |p| p.read_controller(controller, command));
+        }
+    }
+
+    pub fn rom_open() -> c_int {
+        SLOT.with((), |p| p.rom_open());
+        1
+    }
+
+    pub fn rom_closed() {
+        SLOT.with((), |p| p.rom_closed());
+    }
+
+    pub fn sdl_key_down(keymod: c_int, keysym: c_int) {
+        SLOT.with((), |p| p.sdl_key_down(keymod, keysym));
+    }
+
+    pub fn sdl_key_up(keymod: c_int, keysym: c_int) {
+        SLOT.with((), |p| p.sdl_key_up(keymod, keysym));
+    }
+}
+
+/// Exports the input plugin functions other than `RomOpen` and `RomClosed`, which are shared
+/// between plugin types.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __export_input_functions {
+    () => {
+        #[no_mangle]
+        pub unsafe extern "C" fn InitiateControllers(info: $crate::__sys::CONTROL_INFO) {
+            $crate::plugin::input::ffi::initiate_controllers(info)
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn GetKeys(controller: ::std::os::raw::c_int, keys: *mut $crate::__sys::BUTTONS) {
+            $crate::plugin::input::ffi::get_keys(controller, keys)
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn ControllerCommand(controller: ::std::os::raw::c_int, command: *mut ::std::os::raw::c_uchar) {
+            $crate::plugin::input::ffi::controller_command(controller, command)
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn ReadController(controller: ::std::os::raw::c_int, command: *mut ::std::os::raw::c_uchar) {
+            $crate::plugin::input::ffi::read_controller(controller, command)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn SDL_KeyDown(keymod: ::std::os::raw::c_int, keysym: ::std::os::raw::c_int) {
+            $crate::plugin::input::ffi::sdl_key_down(keymod, keysym)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn SDL_KeyUp(keymod: ::std::os::raw::c_int, keysym: ::std::os::raw::c_int) {
+            $crate::plugin::input::ffi::sdl_key_up(keymod, keysym)
+        }
+    };
+}
+
+/// Exports the input plugin API for a type implementing `InputPlugin`, `PluginInfo` and
+/// `Default`. Use this once, in a `cdylib` crate.
+#[macro_export]
+macro_rules! export_input_plugin {
+    ($plugin:ty) => {
+        $crate::__export_plugin_common!(
+            $plugin,
+            $crate::plugin::PluginType::Input,
+            $crate::plugin::version_to_mupen(&$crate::plugin::input::INPUT_API_VERSION),
+            $crate::plugin::input::SLOT
+        );
+
+        $crate::__export_input_functions!();
+
+        #[no_mangle]
+        pub extern "C" fn RomOpen() -> ::std::os::raw::c_int {
+            $crate::plugin::input::ffi::rom_open()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn RomClosed() {
+            $crate::plugin::input::ffi::rom_closed()
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buttons_value() {
+        let state = ControllerState {
+            buttons: Buttons::A | Buttons::START | Buttons::L_TRIG,
+            x: -128,
+            y: 80,
+        };
+        let value: u32 = state.into();
+        assert_eq!(value, 0x5080_2090);
+        assert_eq!(ControllerState::from(value), state);
+    }
+}
@@ -0,0 +1,225 @@
+//! An input plugin controlled from the frontend, for bots and automated tests.
+//!
+//! [`ScriptedInput`] is a handle shared between the plugin and the host. Attach a clone of it with
+//! `Plugin::builtin_input` (see the [`builtin`](crate::plugin::builtin) module), then set each
+//! controller's state directly or give it a closure that is called whenever the game reads it:
+//!
+//! ```ignore
+//! let input = ScriptedInput::new(1);
+//! mupen.attach_plugin(Plugin::builtin_input(input.clone())?)?;
+//!
+//! input.set_script(0, |poll| ControllerState {
+//!     buttons: if poll % 2 == 0 { Buttons::A } else { Buttons::empty() },
+//!     ..Default::default()
+//! });
+//! ```
+
+use super::{ControllerConfig, ControllerState, InputPlugin};
+use crate::plugin::PluginInfo;
+use semver::Version;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Returns a controller's state, given how many times the game has read it before.
+pub type Script = Box<dyn FnMut(u64) -> ControllerState + Send>;
+
+/// Called with the controller (0 to 3) and the state the game read.
+pub type PollCallback = Box<dyn FnMut(usize, ControllerState) + Send>;
+
+#[derive(Default)]
+struct Port {
+    present: bool,
+    state: ControllerState,
+    script: Option<Script>,
+    /// Bumped whenever the state or script is set, so a running script can tell it was replaced.
+    generation: u64,
+    polls: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    ports: [Port; 4],
+    on_poll: Option<PollCallback>,
+}
+
+/// An input plugin whose controllers are set from code. Clones share the same controllers.
+///
+/// Controllers are numbered 0 to 3; calls for other controllers are ignored.
+#[derive(Clone, Default)]
+pub struct ScriptedInput {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl PluginInfo for ScriptedInput {
+    const NAME: &'static str = "Scripted Input";
+    const VERSION: Version = Version::new(1, 0, 0);
+}
+
+impl ScriptedInput {
+    /// Creates the plugin with the first `controllers` ports connected.
+    pub fn new(controllers: usize) -> Self {
+        let input = ScriptedInput::default();
+        for port in input.lock().ports.iter_mut().take(controllers) {
+            port.present = true;
+        }
+        input
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Calls `f` with a controller's port, if there is one.
+    fn with_port<R>(&self, controller: usize, f: impl FnOnce(&mut Port) -> R) -> Option<R> {
+        self.lock().ports.get_mut(controller).map(f)
+    }
+
+    /// Connects or disconnects a controller. This takes effect when emulation starts.
+    pub fn set_connected(&self, controller: usize, connected: bool) {
+        self.with_port(controller, |port| port.present = connected);
+    }
+
+    /// Sets the state the game reads from a controller until it is changed, replacing any script.
+    pub fn set_state(&self, controller: usize, state: ControllerState) {
+        self.with_port(controller, |port| {
+            port.state = state;
+            port.script = None;
+            port.generation += 1;
+        });
+    }
+
+    /// Has the game read a controller's state from `script`.
+    pub fn set_script<F>(&self, controller: usize, script: F)
+    where
+        F: FnMut(u64) -> ControllerState + Send + 'static,
+    {
+        self.with_port(controller, |port| {
+            port.script = Some(Box::new(script));
+            port.generation += 1;
+        });
+    }
+
+    /// The state the game last read from a controller, or will read next if it was set with
+    /// `set_state`.
+    pub fn state(&self, controller: usize) -> ControllerState {
+        self.with_port(controller, |port| port.state).unwrap_or_default()
+    }
+
+    /// How many times the game has read a controller.
+    pub fn polls(&self, controller: usize) -> u64 {
+        self.with_port(controller, |port| port.polls).unwrap_or_default()
+    }
+
+    /// Calls `callback` each time the game reads a controller.
+    pub fn on_poll<F>(&self, callback: F)
+    where
+        F: FnMut(usize, ControllerState) + Send + 'static,
+    {
+        self.lock().on_poll = Some(Box::new(callback));
+    }
+}
+
+impl InputPlugin for ScriptedInput {
+    fn initiate_controllers(&mut self) -> [ControllerConfig; 4] {
+        let inner = self.lock();
+        let mut configs = [ControllerConfig::default(); 4];
+        for (config, port) in configs.iter_mut().zip(inner.ports.iter()) {
+            config.present = port.present;
+        }
+        configs
+    }
+
+    fn get_keys(&mut self, controller: usize) -> ControllerState {
+        if controller >= 4 {
+            return ControllerState::default();
+        }
+
+        // The script and callback are called with the lock released, so they can use the handle
+        let (script, polls, generation) = {
+            let mut inner = self.lock();
+            let port = &mut inner.ports[controller];
+            (port.script.take(), port.polls, port.generation)
+        };
+
+        let state = match script {
+            Some(mut script) => {
+                let state = script(polls);
+                let mut inner = self.lock();
+                let port = &mut inner.ports[controller];
+                // Unless the state or script was set while it ran
+                if port.generation == generation {
+                    port.state = state;
+                    port.script = Some(script);
+                }
+                state
+            }
+            None => self.state(controller),
+        };
+
+        let on_poll = {
+            let mut inner = self.lock();
+            inner.ports[controller].polls += 1;
+            inner.on_poll.take()
+        };
+        if let Some(mut on_poll) = on_poll {
+            on_poll(controller, state);
+            self.lock().on_poll.get_or_insert(on_poll);
+        }
+
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::input::Buttons;
+
+    #[test]
+    fn scripts_and_polls() {
+        let input = ScriptedInput::new(2);
+        let mut plugin = input.clone();
+
+        let configs = plugin.initiate_controllers();
+        assert!(configs[0].present && configs[1].present && !configs[2].present);
+
+        let a = ControllerState { buttons: Buttons::A, x: 10, y: -10 };
+        input.set_state(0, a);
+        input.set_script(1, |poll| ControllerState { x: poll as i8, ..Default::default() });
+
+        let polled = Arc::new(Mutex::new(Vec::new()));
+        let p = polled.clone();
+        input.on_poll(move |controller, state| p.lock().unwrap().push((controller, state.x)));
+
+        assert_eq!(plugin.get_keys(0), a);
+        assert_eq!(plugin.get_keys(1).x, 0);
+        assert_eq!(plugin.get_keys(1).x, 1);
+
+        assert_eq!(input.polls(0), 1);
+        assert_eq!(input.polls(1), 2);
+        assert_eq!(input.state(1).x, 1);
+        assert_eq!(*polled.lock().unwrap(), vec![(0, 10), (1, 0), (1, 1)]);
+
+        // Controllers that don't exist are ignored
+        input.set_state(4, a);
+        assert_eq!(input.state(4), ControllerState::default());
+        assert_eq!(input.polls(4), 0);
+    }
+
+    #[test]
+    fn script_replaced_while_running() {
+        let input = ScriptedInput::new(1);
+        let mut plugin = input.clone();
+
+        let a = ControllerState { buttons: Buttons::A, ..Default::default() };
+        let handle = input.clone();
+        input.set_script(0, move |_| {
+            handle.set_state(0, a);
+            ControllerState { x: 1, ..Default::default() }
+        });
+
+        assert_eq!(plugin.get_keys(0).x, 1);
+        // The script cleared itself, so the state it set is read from now on
+        assert_eq!(plugin.get_keys(0), a);
+        assert_eq!(plugin.get_keys(0), a);
+    }
+}
@@ -0,0 +1,58 @@
+//! Plugins that do nothing, for running games without a display, GPU or sound card. Attach them
+//! with `Mupen::attach_headless_plugins`, or individually as built-in plugins (see the
+//! [`builtin`](super::builtin) module).
+
+use super::audio::{AudioInfo, AudioPlugin};
+use super::input::{ControllerConfig, ControllerState, InputPlugin};
+use super::video::{GfxInfo, VideoPlugin};
+use super::PluginInfo;
+use semver::Version;
+
+/// A video plugin that draws nothing.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullVideo;
+
+impl PluginInfo for NullVideo {
+    const NAME: &'static str = "Null Video";
+    const VERSION: Version = Version::new(1, 0, 0);
+}
+
+impl VideoPlugin for NullVideo {
+    fn initiate_gfx(&mut self, _info: GfxInfo) -> bool {
+        true
+    }
+}
+
+/// An audio plugin that discards all audio.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullAudio;
+
+impl PluginInfo for NullAudio {
+    const NAME: &'static str = "Null Audio";
+    const VERSION: Version = Version::new(1, 0, 0);
+}
+
+impl AudioPlugin for NullAudio {
+    fn initiate_audio(&mut self, _info: AudioInfo) -> bool {
+        true
+    }
+}
+
+/// An input plugin with one controller connected and nothing ever pressed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullInput;
+
+impl PluginInfo for NullInput {
+    const NAME: &'static str = "Null Input";
+    const VERSION: Version = Version::new(1, 0, 0);
+}
+
+impl InputPlugin for NullInput {
+    fn initiate_controllers(&mut self) -> [ControllerConfig; 4] {
+        [ControllerConfig::PRESENT, Default::default(), Default::default(), Default::default()]
+    }
+
+    fn get_keys(&mut self, _controller: usize) -> ControllerState {
+        ControllerState::default()
+    }
+}
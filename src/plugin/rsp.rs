@@ -0,0 +1,302 @@
+//! Writing RSP plugins in Rust.
+//!
+//! Implement [`RspPlugin`] and [`PluginInfo`](super::PluginInfo) for a `Default` type, then
+//! export it from a `cdylib` crate with [`export_rsp_plugin!`](crate::export_rsp_plugin).
+//! The core calls `do_rsp_cycles` when the game starts the RSP; an HLE plugin looks at
+//! [`RspInfo::task_type`], runs the task or passes it on to the video or audio plugin, then calls
+//! [`RspInfo::task_done`].
+
+use super::export::Slot;
+use super::{memory, memory_mut, Register, RDRAM_SIZE, SP_MEM_SIZE};
+use mupen64plus_sys::*;
+use semver::Version;
+
+/// The version of the RSP plugin API implemented by `export_rsp_plugin!`.
+pub const RSP_API_VERSION: Version = super::mupen_to_version(0x020000);
+
+/// Where the `OSTask` structure for the current task is in DMEM.
+pub const TASK_ADDR: usize = 0xFC0;
+
+const SP_STATUS_HALT: u32 = 0x0001;
+const SP_STATUS_BROKE: u32 = 0x0002;
+const SP_STATUS_INTR_BREAK: u32 = 0x0040;
+const SP_STATUS_TASKDONE: u32 = 0x0200;
+const MI_INTR_SP: u32 = 0x01;
+const MI_INTR_DP: u32 = 0x20;
+
+/// The kind of task in DMEM, from the `type` field of its `OSTask`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum TaskType {
+    Graphics,
+    Audio,
+    Other(u32),
+}
+
+impl From<u32> for TaskType {
+    fn from(value: u32) -> Self {
+        match value {
+            1 => TaskType::Graphics,
+            2 => TaskType::Audio,
+            other => TaskType::Other(other),
+        }
+    }
+}
+
+/// The memory, registers and callbacks the core shares with the RSP plugin (`RSP_INFO`). It stays
+/// valid until the plugin is shut down.
+///
+/// Like in [`AudioInfo`](super::audio::AudioInfo), memory is stored as native-endian 32-bit
+/// words.
+#[derive(Debug)]
+pub struct RspInfo {
+    rdram: *mut u8,
+    dmem: *mut u8,
+    imem: *mut u8,
+    pub mi_intr: Register,
+    pub sp_mem_addr: Register,
+    pub sp_dram_addr: Register,
+    pub sp_rd_len: Register,
+    pub sp_wr_len: Register,
+    pub sp_status: Register,
+    pub sp_dma_full: Register,
+    pub sp_dma_busy: Register,
+    pub sp_pc: Register,
+    pub sp_semaphore: Register,
+    pub dpc_start: Register,
+    pub dpc_end: Register,
+    pub dpc_current: Register,
+    pub dpc_status: Register,
+    pub dpc_clock: Register,
+    pub dpc_bufbusy: Register,
+    pub dpc_pipebusy: Register,
+    pub dpc_tmem: Register,
+    check_interrupts: Option<unsafe extern "C" fn()>,
+    process_dlist_list: Option<unsafe extern "C" fn()>,
+    process_alist_list: Option<unsafe extern "C" fn()>,
+    process_rdp_list: Option<unsafe extern "C" fn()>,
+    show_cfb: Option<unsafe extern "C" fn()>,
+}
+
+unsafe impl Send for RspInfo {}
+
+/// Calls one of the core's callbacks, if it gave us one.
+fn call(callback: Option<unsafe extern "C" fn()>) {
+    if let Some(f) = callback {
+        unsafe { f() };
+    }
+}
+
+impl RspInfo {
+    /// # Safety
+    /// The pointers in `info` must be valid for as long as the `RspInfo` is used.
+    pub unsafe fn from_ffi(info: &RSP_INFO) -> Self {
+        RspInfo {
+            rdram: info.RDRAM,
+            dmem: info.DMEM,
+            imem: info.IMEM,
+            mi_intr: Register::from_ptr(info.MI_INTR_REG),
+            sp_mem_addr: Register::from_ptr(info.SP_MEM_ADDR_REG),
+            sp_dram_addr: Register::from_ptr(info.SP_DRAM_ADDR_REG),
+            sp_rd_len: Register::from_ptr(info.SP_RD_LEN_REG),
+            sp_wr_len: Register::from_ptr(info.SP_WR_LEN_REG),
+            sp_status: Register::from_ptr(info.SP_STATUS_REG),
+            sp_dma_full: Register::from_ptr(info.SP_DMA_FULL_REG),
+            sp_dma_busy: Register::from_ptr(info.SP_DMA_BUSY_REG),
+            sp_pc: Register::from_ptr(info.SP_PC_REG),
+            sp_semaphore: Register::from_ptr(info.SP_SEMAPHORE_REG),
+            dpc_start: Register::from_ptr(info.DPC_START_REG),
+            dpc_end: Register::from_ptr(info.DPC_END_REG),
+            dpc_current: Register::from_ptr(info.DPC_CURRENT_REG),
+            dpc_status: Register::from_ptr(info.DPC_STATUS_REG),
+            dpc_clock: Register::from_ptr(info.DPC_CLOCK_REG),
+            dpc_bufbusy: Register::from_ptr(info.DPC_BUFBUSY_REG),
+            dpc_pipebusy: Register::from_ptr(info.DPC_PIPEBUSY_REG),
+            dpc_tmem: Register::from_ptr(info.DPC_TMEM_REG),
+            check_interrupts: info.CheckInterrupts,
+            process_dlist_list: info.ProcessDlistList,
+            process_alist_list: info.ProcessAlistList,
+            process_rdp_list: info.ProcessRdpList,
+            show_cfb: info.ShowCFB,
+        }
+    }
+
+    pub fn rdram(&self) -> &[u8] {
+        unsafe { memory(self.rdram, RDRAM_SIZE) }
+    }
+
+    pub fn rdram_mut(&mut self) -> &mut [u8] {
+        unsafe { memory_mut(self.rdram, RDRAM_SIZE) }
+    }
+
+    pub fn dmem(&self) -> &[u8] {
+        unsafe { memory(self.dmem, SP_MEM_SIZE) }
+    }
+
+    pub fn dmem_mut(&mut self) -> &mut [u8] {
+        unsafe { memory_mut(self.dmem, SP_MEM_SIZE) }
+    }
+
+    pub fn imem(&self) -> &[u8] {
+        unsafe { memory(self.imem, SP_MEM_SIZE) }
+    }
+
+    pub fn imem_mut(&mut self) -> &mut [u8] {
+        unsafe { memory_mut(self.imem, SP_MEM_SIZE) }
+    }
+
+    /// The type of the task in DMEM.
+    pub fn task_type(&self) -> TaskType {
+        match self.dmem().get(TASK_ADDR..TASK_ADDR + 4) {
+            Some(word) => u32::from_ne_bytes([word[0], word[1], word[2], word[3]]).into(),
+            None => TaskType::Other(0),
+        }
+    }
+
+    /// Tells the core to check for interrupts raised through `mi_intr`.
+    pub fn check_interrupts(&self) {
+        call(self.check_interrupts);
+    }
+
+    /// Passes the graphics task in DMEM to the video plugin.
+    pub fn process_dlist_list(&self) {
+        call(self.process_dlist_list);
+    }
+
+    /// Passes the audio task in DMEM to the audio plugin.
+    pub fn process_alist_list(&self) {
+        call(self.process_alist_list);
+    }
+
+    /// Has the video plugin run the RDP commands between `dpc_current` and `dpc_end`.
+    pub fn process_rdp_list(&self) {
+        call(self.process_rdp_list);
+    }
+
+    /// Has the video plugin show the color frame buffer, for tasks that draw to it directly.
+    pub fn show_cfb(&self) {
+        call(self.show_cfb);
+    }
+
+    /// Halts the RSP at the end of a task the way the microcode would, raising the SP interrupt
+    /// if the game asked for it. For graphics tasks this also raises the DP interrupt, as the RDP
+    /// has finished too.
+    pub fn task_done(&self, task_type: TaskType) {
+        let mut interrupts = 0;
+
+        if task_type == TaskType::Graphics {
+            interrupts |= MI_INTR_DP;
+        }
+
+        let status = self.sp_status.get() | SP_STATUS_TASKDONE | SP_STATUS_BROKE | SP_STATUS_HALT;
+        self.sp_status.set(status);
+        if status & SP_STATUS_INTR_BREAK != 0 {
+            interrupts |= MI_INTR_SP;
+        }
+
+        if interrupts != 0 {
+            self.mi_intr.set(self.mi_intr.get() | interrupts);
+            self.check_interrupts();
+        }
+    }
+}
+
+/// An RSP plugin. Every method but `initiate_rsp` and `do_rsp_cycles` has a default that does
+/// nothing.
+pub trait RspPlugin: Send + 'static {
+    /// Called when emulation starts.
+    fn initiate_rsp(&mut self, info: RspInfo);
+
+    /// Runs the RSP, which the game has just started, for up to `cycles` cycles. Returns how many
+    /// were used.
+    fn do_rsp_cycles(&mut self, cycles: u32) -> u32;
+
+    fn rom_closed(&mut self) {}
+}
+
+/// The running RSP plugin.
+pub static SLOT: Slot<dyn RspPlugin> = Slot::new();
+
+/// Implementations of the RSP plugin API, called by the functions `export_rsp_plugin!` exports.
+#[doc(hidden)]
+pub mod ffi {
+    use super::*;
+    use std::os::raw::c_uint;
+
+    pub unsafe fn initiate_rsp(info: RSP_INFO, cycle_count: *mut c_uint) {
+        let info = RspInfo::from_ffi(&info);
+        SLOT.with((), |p| p.initiate_rsp(info));
+        if !cycle_count.is_null() {
+            *cycle_count = 0;
+        }
+    }
+
+    pub fn do_rsp_cycles(cycles: c_uint) -> c_uint {
+        SLOT.with(cycles, |p| p.do_rsp_cycles(cycles))
+    }
+
+    pub fn rom_closed() {
+        SLOT.with((), |p| p.rom_closed());
+    }
+}
+
+/// Exports the rsp plugin functions other than `RomOpen` and `RomClosed`, which are shared
+/// between plugin types.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __export_rsp_functions {
+    () => {
+        #[no_mangle]
+        pub unsafe extern "C" fn InitiateRSP(info: $crate::__sys::RSP_INFO, cycle_count: *mut ::std::os::raw::c_uint) {
+            $crate::plugin::rsp::ffi::initiate_rsp(info, cycle_count)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn DoRspCycles(cycles: ::std::os::raw::c_uint) -> ::std::os::raw::c_uint {
+            $crate::plugin::rsp::ffi::do_rsp_cycles(cycles)
+        }
+    };
+}
+
+/// Exports the RSP plugin API for a type implementing `RspPlugin`, `PluginInfo` and `Default`.
+/// Use this once, in a `cdylib` crate.
+#[macro_export]
+macro_rules! export_rsp_plugin {
+    ($plugin:ty) => {
+        $crate::__export_plugin_common!(
+            $plugin,
+            $crate::plugin::PluginType::Rsp,
+            $crate::plugin::version_to_mupen(&$crate::plugin::rsp::RSP_API_VERSION),
+            $crate::plugin::rsp::SLOT
+        );
+
+        $crate::__export_rsp_functions!();
+
+        #[no_mangle]
+        pub extern "C" fn RomClosed() {
+            $crate::plugin::rsp::ffi::rom_closed()
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn task_done() {
+        let mut dmem = vec![0u8; SP_MEM_SIZE];
+        dmem[TASK_ADDR..TASK_ADDR + 4].copy_from_slice(&1u32.to_ne_bytes());
+        let (mut sp_status, mut mi_intr) = (SP_STATUS_INTR_BREAK, 0u32);
+
+        let mut info: RSP_INFO = unsafe { std::mem::zeroed() };
+        info.DMEM = dmem.as_mut_ptr();
+        info.SP_STATUS_REG = &mut sp_status;
+        info.MI_INTR_REG = &mut mi_intr;
+        let info = unsafe { RspInfo::from_ffi(&info) };
+
+        assert_eq!(info.task_type(), TaskType::Graphics);
+        info.task_done(TaskType::Graphics);
+        assert_eq!(info.sp_status.get(), 0x0243);
+        assert_eq!(info.mi_intr.get(), MI_INTR_SP | MI_INTR_DP);
+    }
+}
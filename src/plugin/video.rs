@@ -0,0 +1,491 @@
+//! Writing video plugins in Rust.
+//!
+//! Implement [`VideoPlugin`] and [`PluginInfo`](super::PluginInfo) for a `Default` type, then
+//! export it from a `cdylib` crate with [`export_video_plugin!`](crate::export_video_plugin).
+//! The core calls `process_dlist` for each display list the RSP hands over, and `update_screen`
+//! once per VI interrupt; [`GfxInfo`] gives access to the memory and registers they work on.
+
+use super::export::Slot;
+use super::{memory, memory_mut, Register, RDRAM_SIZE, SP_MEM_SIZE};
+use mupen64plus_sys::*;
+use semver::Version;
+
+/// The version of the video plugin API implemented by `export_video_plugin!`.
+pub const VIDEO_API_VERSION: Version = super::mupen_to_version(0x020200);
+
+/// The first core version whose `GFX_INFO` has the `version` field and the fields after it.
+const GFX_INFO_VERSION_CORE: Version = Version::new(2, 5, 1);
+
+/// Size of the ROM header.
+pub const HEADER_SIZE: usize = 0x40;
+
+/// The memory and registers the core shares with the video plugin (`GFX_INFO`). It stays valid
+/// until the plugin is shut down.
+///
+/// Like in [`AudioInfo`](super::audio::AudioInfo), memory is stored as native-endian 32-bit
+/// words.
+#[derive(Debug)]
+pub struct GfxInfo {
+    header: *mut u8,
+    rdram: *mut u8,
+    rdram_size: usize,
+    dmem: *mut u8,
+    imem: *mut u8,
+    pub mi_intr: Register,
+    pub dpc_start: Register,
+    pub dpc_end: Register,
+    pub dpc_current: Register,
+    pub dpc_status: Register,
+    pub dpc_clock: Register,
+    pub dpc_bufbusy: Register,
+    pub dpc_pipebusy: Register,
+    pub dpc_tmem: Register,
+    pub vi_status: Register,
+    pub vi_origin: Register,
+    pub vi_width: Register,
+    pub vi_intr: Register,
+    pub vi_v_current_line: Register,
+    pub vi_timing: Register,
+    pub vi_v_sync: Register,
+    pub vi_h_sync: Register,
+    pub vi_leap: Register,
+    pub vi_h_start: Register,
+    pub vi_v_start: Register,
+    pub vi_v_burst: Register,
+    pub vi_x_scale: Register,
+    pub vi_y_scale: Register,
+    /// Only provided by newer cores; reads as 0 otherwise.
+    pub sp_status: Register,
+    check_interrupts: Option<unsafe extern "C" fn()>,
+}
+
+unsafe impl Send for GfxInfo {}
+
+impl GfxInfo {
+    /// # Safety
+    /// The pointers in `info` must be valid for as long as the `GfxInfo` is used.
+    pub unsafe fn from_ffi(info: &GFX_INFO) -> Self {
+        // Cores before 2.5.1 pass a shorter struct, without the version or anything after it
+        let has_version = matches!(super::export::core_version(), Some(v) if v >= GFX_INFO_VERSION_CORE);
+        let (sp_status, rdram_size) = if has_version && info.version >= 2 {
+            (info.SP_STATUS_REG, info.RDRAM_SIZE)
+        } else {
+            (std::ptr::null_mut(), std::ptr::null())
+        };
+
+        GfxInfo {
+            header: info.HEADER,
+            rdram: info.RDRAM,
+            rdram_size: if rdram_size.is_null() { RDRAM_SIZE } else { *rdram_size as usize },
+            dmem: info.DMEM,
+            imem: info.IMEM,
+            mi_intr: Register::from_ptr(info.MI_INTR_REG),
+            dpc_start: Register::from_ptr(info.DPC_START_REG),
+            dpc_end: Register::from_ptr(info.DPC_END_REG),
+            dpc_current: Register::from_ptr(info.DPC_CURRENT_REG),
+            dpc_status: Register::from_ptr(info.DPC_STATUS_REG),
+            dpc_clock: Register::from_ptr(info.DPC_CLOCK_REG),
+            dpc_bufbusy: Register::from_ptr(info.DPC_BUFBUSY_REG),
+            dpc_pipebusy: Register::from_ptr(info.DPC_PIPEBUSY_REG),
+            dpc_tmem: Register::from_ptr(info.DPC_TMEM_REG),
+            vi_status: Register::from_ptr(info.VI_STATUS_REG),
+            vi_origin: Register::from_ptr(info.VI_ORIGIN_REG),
+            vi_width: Register::from_ptr(info.VI_WIDTH_REG),
+            vi_intr: Register::from_ptr(info.VI_INTR_REG),
+            vi_v_current_line: Register::from_ptr(info.VI_V_CURRENT_LINE_REG),
+            vi_timing: Register::from_ptr(info.VI_TIMING_REG),
+            vi_v_sync: Register::from_ptr(info.VI_V_SYNC_REG),
+            vi_h_sync: Register::from_ptr(info.VI_H_SYNC_REG),
+            vi_leap: Register::from_ptr(info.VI_LEAP_REG),
+            vi_h_start: Register::from_ptr(info.VI_H_START_REG),
+            vi_v_start: Register::from_ptr(info.VI_V_START_REG),
+            vi_v_burst: Register::from_ptr(info.VI_V_BURST_REG),
+            vi_x_scale: Register::from_ptr(info.VI_X_SCALE_REG),
+            vi_y_scale: Register::from_ptr(info.VI_Y_SCALE_REG),
+            sp_status: Register::from_ptr(sp_status),
+            check_interrupts: info.CheckInterrupts,
+        }
+    }
+
+    /// The first 64 bytes of the ROM.
+    pub fn header(&self) -> &[u8] {
+        unsafe { memory(self.header, HEADER_SIZE) }
+    }
+
+    /// RDRAM, which is 4 or 8 MiB depending on whether the Expansion Pak is emulated.
+    pub fn rdram(&self) -> &[u8] {
+        unsafe { memory(self.rdram, self.rdram_size) }
+    }
+
+    pub fn rdram_mut(&mut self) -> &mut [u8] {
+        unsafe { memory_mut(self.rdram, self.rdram_size) }
+    }
+
+    pub fn dmem(&self) -> &[u8] {
+        unsafe { memory(self.dmem, SP_MEM_SIZE) }
+    }
+
+    pub fn dmem_mut(&mut self) -> &mut [u8] {
+        unsafe { memory_mut(self.dmem, SP_MEM_SIZE) }
+    }
+
+    pub fn imem(&self) -> &[u8] {
+        unsafe { memory(self.imem, SP_MEM_SIZE) }
+    }
+
+    pub fn imem_mut(&mut self) -> &mut [u8] {
+        unsafe { memory_mut(self.imem, SP_MEM_SIZE) }
+    }
+
+    /// Tells the core to check for interrupts raised through `mi_intr`.
+    pub fn check_interrupts(&self) {
+        if let Some(f) = self.check_interrupts {
+            unsafe { f() };
+        }
+    }
+}
+
+/// A frame buffer in RDRAM that the plugin renders to, as reported by `frame_buffer_info`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Hash)]
+pub struct FrameBuffer {
+    pub addr: u32,
+    /// Bytes per pixel.
+    pub size: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The most frame buffers `FBGetFrameBufferInfo` can report.
+const MAX_FRAME_BUFFERS: usize = 6;
+
+/// A video plugin. Every method but `initiate_gfx` has a default that does nothing.
+pub trait VideoPlugin: Send + 'static {
+    /// Called when emulation starts. Returning false stops it from starting.
+    fn initiate_gfx(&mut self, info: GfxInfo) -> bool;
+
+    /// Runs the display list task in DMEM.
+    fn process_dlist(&mut self) {}
+
+    /// Runs the RDP commands between `dpc_current` and `dpc_end`.
+    fn process_rdp_list(&mut self) {}
+
+    /// Called on each vertical interrupt. Returns whether the screen was redrawn, which is passed
+    /// on to the frontend's rendering callback.
+    fn update_screen(&mut self) -> bool {
+        false
+    }
+
+    /// Shows the color frame buffer in RDRAM, when the RSP isn't producing display lists.
+    fn show_cfb(&mut self) {}
+
+    fn vi_status_changed(&mut self) {}
+
+    fn vi_width_changed(&mut self) {}
+
+    /// Toggles between windowed and fullscreen.
+    fn change_window(&mut self) {}
+
+    fn move_screen(&mut self, _x: i32, _y: i32) {}
+
+    /// The frontend resized the window.
+    fn resize_video_output(&mut self, _width: i32, _height: i32) {}
+
+    /// The size of the image `read_screen` writes.
+    fn screen_size(&mut self) -> (u32, u32) {
+        (0, 0)
+    }
+
+    /// Copies the front or back buffer to `dest` as 24-bit RGB, bottom row first. `dest` is the
+    /// size given by `screen_size`.
+    fn read_screen(&mut self, _front: bool, _dest: &mut [u8]) {}
+
+    /// The CPU is about to read from `addr` in RDRAM, so anything rendered there should be copied
+    /// back.
+    fn fb_read(&mut self, _addr: u32) {}
+
+    /// The CPU wrote `size` bytes at `addr` in RDRAM.
+    fn fb_write(&mut self, _addr: u32, _size: u32) {}
+
+    /// The frame buffers the plugin is rendering to, at most 6.
+    fn frame_buffer_info(&mut self) -> Vec<FrameBuffer> {
+        Vec::new()
+    }
+
+    fn rom_open(&mut self) {}
+
+    fn rom_closed(&mut self) {}
+}
+
+/// The running video plugin.
+pub static SLOT: Slot<dyn VideoPlugin> = Slot::new();
+
+/// Implementations of the video plugin API, called by the functions `export_video_plugin!`
+/// exports.
+#[doc(hidden)]
+pub mod ffi {
+    use super::*;
+    use std::os::raw::{c_int, c_uint, c_void};
+    use std::sync::Mutex;
+
+    /// The callback given to `SetRenderingCallback`.
+    static RENDERING_CALLBACK: Mutex<Option<unsafe extern "C" fn(c_int)>> = Mutex::new(None);
+
+    pub unsafe fn initiate_gfx(info: GFX_INFO) -> c_int {
+        let info = GfxInfo::from_ffi(&info);
+        SLOT.with(false, |p| p.initiate_gfx(info)) as c_int
+    }
+
+    pub fn process_dlist() {
+        SLOT.with((), |p| p.process_dlist());
+    }
+
+    pub fn process_rdp_list() {
+        SLOT.with((), |p| p.process_rdp_list());
+    }
+
+    pub fn update_screen() {
+        let redrawn = SLOT.with(false, |p| p.update_screen());
+
+        // Called with the plugin unlocked, since the frontend may take a screenshot from it
+        let callback = *RENDERING_CALLBACK.lock().unwrap();
+        if let Some(callback) = callback {
+            unsafe { callback(redrawn as c_int) };
+        }
+    }
+
+    pub fn show_cfb() {
+        SLOT.with((), |p| p.show_cfb());
+    }
+
+    pub fn vi_status_changed() {
+        SLOT.with((), |p| p.vi_status_changed());
+    }
+
+    pub fn vi_width_changed() {
+        SLOT.with((), |p| p.vi_width_changed());
+    }
+
+    pub fn change_window() {
+        SLOT.with((), |p| p.change_window());
+    }
+
+    pub fn move_screen(x: c_int, y: c_int) {
+        SLOT.with((), |p| p.move_screen(x, y));
+    }
+
+    pub fn resize_video_output(width: c_int, height: c_int) {
+        SLOT.with((), |p| p.resize_video_output(width, height));
+    }
+
+    /// With a null `dest`, only reports the size.
+    pub unsafe fn read_screen2(dest: *mut c_void, width: *mut c_int, height: *mut c_int, front: c_int) {
+        SLOT.with((), |p| {
+            let (w, h) = p.screen_size();
+            if !width.is_null() {
+                *width = w as c_int;
+            }
+            if !height.is_null() {
+                *height = h as c_int;
+            }
+
+            if !dest.is_null() {
+                let dest = std::slice::from_raw_parts_mut(dest as *mut u8, w as usize * h as usize * 3);
+                p.read_screen(front != 0, dest);
+            }
+        });
+    }
+
+    pub fn set_rendering_callback(callback: Option<unsafe extern "C" fn(c_int)>) {
+        *RENDERING_CALLBACK.lock().unwrap() = callback;
+    }
+
+    pub fn fb_read(addr: c_uint) {
+        SLOT.with((), |p| p.fb_read(addr));
+    }
+
+    pub fn fb_write(addr: c_uint, size: c_uint) {
+        SLOT.with((), |p| p.fb_write(addr, size));
+    }
+
+    /// `info` points to an array of 6 `FrameBufferInfo`s; unused entries are zeroed.
+    pub unsafe fn fb_get_frame_buffer_info(info: *mut c_void) {
+        if info.is_null() {
+            return;
+        }
+
+        let info = std::slice::from_raw_parts_mut(info as *mut FrameBufferInfo, MAX_FRAME_BUFFERS);
+        let buffers = SLOT.with(Vec::new(), |p| p.frame_buffer_info());
+        for (i, out) in info.iter_mut().enumerate() {
+            let buffer = buffers.get(i).copied().unwrap_or_default();
+            *out = FrameBufferInfo {
+                addr: buffer.addr,
+                size: buffer.size,
+                width: buffer.width,
+                height: buffer.height,
+            };
+        }
+    }
+
+    pub fn rom_open() -> c_int {
+        SLOT.with((), |p| p.rom_open());
+        1
+    }
+
+    pub fn rom_closed() {
+        SLOT.with((), |p| p.rom_closed());
+    }
+}
+
+/// Exports the video plugin functions other than `RomOpen` and `RomClosed`, which are shared
+/// between plugin types.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __export_video_functions {
+    () => {
+        #[no_mangle]
+        pub unsafe extern "C" fn InitiateGFX(info: $crate::__sys::GFX_INFO) -> ::std::os::raw::c_int {
+            $crate::plugin::video::ffi::initiate_gfx(info)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn ProcessDList() {
+            $crate::plugin::video::ffi::process_dlist()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn ProcessRDPList() {
+            $crate::plugin::video::ffi::process_rdp_list()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn UpdateScreen() {
+            $crate::plugin::video::ffi::update_screen()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn ShowCFB() {
+            $crate::plugin::video::ffi::show_cfb()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn ViStatusChanged() {
+            $crate::plugin::video::ffi::vi_status_changed()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn ViWidthChanged() {
+            $crate::plugin::video::ffi::vi_width_changed()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn ChangeWindow() {
+            $crate::plugin::video::ffi::change_window()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn MoveScreen(x: ::std::os::raw::c_int, y: ::std::os::raw::c_int) {
+            $crate::plugin::video::ffi::move_screen(x, y)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn ResizeVideoOutput(width: ::std::os::raw::c_int, height: ::std::os::raw::c_int) {
+            $crate::plugin::video::ffi::resize_video_output(width, height)
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn ReadScreen2(
+            dest: *mut ::std::os::raw::c_void,
+            width: *mut ::std::os::raw::c_int,
+            height: *mut ::std::os::raw::c_int,
+            front: ::std::os::raw::c_int,
+        ) {
+            $crate::plugin::video::ffi::read_screen2(dest, width, height, front)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn SetRenderingCallback(callback: Option<unsafe extern "C" fn(::std::os::raw::c_int)>) {
+            $crate::plugin::video::ffi::set_rendering_callback(callback)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn FBRead(addr: ::std::os::raw::c_uint) {
+            $crate::plugin::video::ffi::fb_read(addr)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn FBWrite(addr: ::std::os::raw::c_uint, size: ::std::os::raw::c_uint) {
+            $crate::plugin::video::ffi::fb_write(addr, size)
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn FBGetFrameBufferInfo(info: *mut ::std::os::raw::c_void) {
+            $crate::plugin::video::ffi::fb_get_frame_buffer_info(info)
+        }
+    };
+}
+
+/// Exports the video plugin API for a type implementing `VideoPlugin`, `PluginInfo` and
+/// `Default`. Use this once, in a `cdylib` crate.
+#[macro_export]
+macro_rules! export_video_plugin {
+    ($plugin:ty) => {
+        $crate::__export_plugin_common!(
+            $plugin,
+            $crate::plugin::PluginType::Gfx,
+            $crate::plugin::version_to_mupen(&$crate::plugin::video::VIDEO_API_VERSION),
+            $crate::plugin::video::SLOT
+        );
+
+        $crate::__export_video_functions!();
+
+        #[no_mangle]
+        pub extern "C" fn RomOpen() -> ::std::os::raw::c_int {
+            $crate::plugin::video::ffi::rom_open()
+        }
+
+        #[no_mangle]
+        pub extern "C" fn RomClosed() {
+            $crate::plugin::video::ffi::rom_closed()
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::raw::c_int;
+
+    struct Gradient;
+
+    impl VideoPlugin for Gradient {
+        fn initiate_gfx(&mut self, _info: GfxInfo) -> bool {
+            true
+        }
+
+        fn screen_size(&mut self) -> (u32, u32) {
+            (2, 3)
+        }
+
+        fn read_screen(&mut self, _front: bool, dest: &mut [u8]) {
+            for (i, byte) in dest.iter_mut().enumerate() {
+                *byte = i as u8;
+            }
+        }
+    }
+
+    #[test]
+    fn read_screen2() {
+        SLOT.start(Box::new(Gradient));
+
+        let (mut width, mut height): (c_int, c_int) = (0, 0);
+        unsafe { ffi::read_screen2(std::ptr::null_mut(), &mut width, &mut height, 1) };
+        assert_eq!((width, height), (2, 3));
+
+        let mut image = vec![0xFFu8; 2 * 3 * 3 + 1];
+        unsafe { ffi::read_screen2(image.as_mut_ptr() as *mut _, &mut width, &mut height, 1) };
+        assert_eq!(image[17], 17);
+        assert_eq!(image[18], 0xFF);
+
+        SLOT.stop();
+    }
+}
@@ -0,0 +1,382 @@
+//! N64 ROM images: byte-order detection and conversion, header parsing, CIC detection and
+//! the CRC1/CRC2 checksums in the header.
+//!
+//! A [`Rom`] always stores its data in big-endian (z64) order, whatever order it was read in.
+//! [`Rom::load`] also unpacks ROMs from `.zip` and `.gz` archives.
+
+use std::fmt;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use thiserror::Error;
+
+/// Offset of the IPL3 boot code.
+const BOOT_CODE_START: usize = 0x40;
+/// Offset of the game code; the region covered by the checksum starts here.
+const CHECKSUM_START: usize = 0x1000;
+const CHECKSUM_LENGTH: usize = 0x10_0000;
+
+#[derive(Error, Debug)]
+pub enum RomError {
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("not an N64 ROM (unknown magic {0:02X?})")]
+    BadMagic([u8; 4]),
+    #[error("ROM size {0:#X} is too small or not a multiple of 4")]
+    BadSize(usize),
+    #[error("unknown CIC (IPL3 CRC32 {0:08X})")]
+    UnknownCic(u32),
+    #[error("zip: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("no N64 ROM found in archive")]
+    NotInArchive,
+}
+
+/// The order bytes are stored in a ROM dump, named after the usual file extensions.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum ByteOrder {
+    /// `.z64`: big-endian, the native order of the cartridge.
+    Z64,
+    /// `.v64`: every pair of bytes swapped.
+    V64,
+    /// `.n64`: little-endian, every 4 bytes reversed.
+    N64,
+}
+
+impl ByteOrder {
+    /// Detects the byte order from the first 4 bytes of a ROM.
+    pub fn detect(rom: &[u8]) -> Option<ByteOrder> {
+        match rom.get(0..4)? {
+            [0x80, 0x37, 0x12, 0x40] => Some(ByteOrder::Z64),
+            [0x37, 0x80, 0x40, 0x12] => Some(ByteOrder::V64),
+            [0x40, 0x12, 0x37, 0x80] => Some(ByteOrder::N64),
+            _ => None,
+        }
+    }
+}
+
+/// Converts `data` between byte orders in place. Trailing bytes that don't make up a full word
+/// are left alone.
+pub fn convert(data: &mut [u8], from: ByteOrder, to: ByteOrder) {
+    // Every conversion is its own inverse, so go via z64
+    for order in [from, to].iter() {
+        match order {
+            ByteOrder::Z64 => {}
+            ByteOrder::V64 => data.chunks_exact_mut(2).for_each(|c| c.swap(0, 1)),
+            ByteOrder::N64 => data.chunks_exact_mut(4).for_each(|c| c.reverse()),
+        }
+    }
+}
+
+/// The lockout chip on the cartridge, which determines the boot code and checksum seed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Cic {
+    Cic6101,
+    Cic6102,
+    Cic6103,
+    Cic6105,
+    Cic6106,
+    /// The 6101 variant used by Star Fox 64 / Lylat Wars.
+    Cic7102,
+}
+
+impl Cic {
+    /// Identifies a CIC from the CRC32 of the IPL3 boot code (`0x40..0x1000`).
+    pub fn from_ipl3_crc(crc: u32) -> Option<Cic> {
+        match crc {
+            0x6170A4A1 => Some(Cic::Cic6101),
+            0x90BB6CB5 => Some(Cic::Cic6102),
+            0x0B050EE0 => Some(Cic::Cic6103),
+            0x98BC2C86 => Some(Cic::Cic6105),
+            0xACC8580A => Some(Cic::Cic6106),
+            0x009E9EA3 => Some(Cic::Cic7102),
+            _ => None,
+        }
+    }
+
+    fn checksum_seed(self) -> u32 {
+        match self {
+            Cic::Cic6101 | Cic::Cic6102 | Cic::Cic7102 => 0xF8CA4DDC,
+            Cic::Cic6103 => 0xA3886759,
+            Cic::Cic6105 => 0xDF26F436,
+            Cic::Cic6106 => 0x1FEA617A,
+        }
+    }
+}
+
+/// The first 0x40 bytes of a ROM.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Header {
+    pub pi_bsd_dom1: u32,
+    pub clock_rate: u32,
+    pub entry_point: u32,
+    pub release: u32,
+    pub crc1: u32,
+    pub crc2: u32,
+    /// Internal name, padded with spaces in the ROM (trimmed here).
+    pub name: String,
+    /// `N` for cartridges, `D` for 64DD disks, and so on.
+    pub media_format: u8,
+    pub cartridge_id: [u8; 2],
+    /// Region byte, e.g. `E` (USA), `J` (Japan) or `P` (Europe).
+    pub country_code: u8,
+    pub version: u8,
+}
+
+impl Header {
+    pub fn parse(z64: &[u8; 0x40]) -> Header {
+        let word = |offset: usize| {
+            u32::from_be_bytes([z64[offset], z64[offset + 1], z64[offset + 2], z64[offset + 3]])
+        };
+
+        Header {
+            pi_bsd_dom1: word(0x00),
+            clock_rate: word(0x04),
+            entry_point: word(0x08),
+            release: word(0x0C),
+            crc1: word(0x10),
+            crc2: word(0x14),
+            name: String::from_utf8_lossy(&z64[0x20..0x34])
+                .trim_end_matches(&[' ', '\0'][..])
+                .to_owned(),
+            media_format: z64[0x3B],
+            cartridge_id: [z64[0x3C], z64[0x3D]],
+            country_code: z64[0x3E],
+            version: z64[0x3F],
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Rom {
+    data: Vec<u8>,
+    original_order: ByteOrder,
+}
+
+impl fmt::Debug for Rom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Rom")
+            .field("name", &self.header().name)
+            .field("size", &self.data.len())
+            .field("original_order", &self.original_order)
+            .finish()
+    }
+}
+
+impl Rom {
+    /// Takes a ROM image in any byte order, converting it to z64.
+    pub fn from_bytes(mut data: Vec<u8>) -> Result<Rom, RomError> {
+        if data.len() < CHECKSUM_START || data.len() & 3 != 0 {
+            return Err(RomError::BadSize(data.len()));
+        }
+
+        let order = ByteOrder::detect(&data)
+            .ok_or_else(|| RomError::BadMagic([data[0], data[1], data[2], data[3]]))?;
+        convert(&mut data, order, ByteOrder::Z64);
+
+        Ok(Rom {
+            data,
+            original_order: order,
+        })
+    }
+
+    /// Reads a ROM file. Zip and gzip archives are detected by their magic and unpacked; a zip
+    /// may hold several files, in which case the first valid N64 image is used.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Rom, RomError> {
+        let data = std::fs::read(path)?;
+
+        match data.get(0..4) {
+            Some([b'P', b'K', 0x03, 0x04]) => Self::from_zip(&data),
+            Some([0x1F, 0x8B, _, _]) => Self::from_gzip(&data),
+            _ => Self::from_bytes(data),
+        }
+    }
+
+    /// Takes the first file in a zip archive that is a valid ROM.
+    pub fn from_zip(zip: &[u8]) -> Result<Rom, RomError> {
+        let mut archive = zip::ZipArchive::new(Cursor::new(zip))?;
+
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            if !file.is_file() {
+                continue;
+            }
+
+            let mut data = Vec::with_capacity(file.size() as usize);
+            file.read_to_end(&mut data)?;
+
+            match Self::from_bytes(data) {
+                Ok(rom) => return Ok(rom),
+                Err(e) => log::debug!("skipping {:?} in zip: {}", file.name(), e),
+            }
+        }
+
+        Err(RomError::NotInArchive)
+    }
+
+    pub fn from_gzip(gz: &[u8]) -> Result<Rom, RomError> {
+        let mut data = Vec::new();
+        flate2::read::GzDecoder::new(gz).read_to_end(&mut data)?;
+        Self::from_bytes(data)
+    }
+
+    /// The byte order the ROM was in before it was converted to z64.
+    pub fn original_order(&self) -> ByteOrder {
+        self.original_order
+    }
+
+    /// The ROM in z64 order.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The ROM in z64 order, for patching. Call `fix_crc` afterwards.
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// A copy of the ROM in the given byte order.
+    pub fn to_order(&self, order: ByteOrder) -> Vec<u8> {
+        let mut data = self.data.clone();
+        convert(&mut data, ByteOrder::Z64, order);
+        data
+    }
+
+    pub fn header(&self) -> Header {
+        let mut header = [0; 0x40];
+        header.copy_from_slice(&self.data[..0x40]);
+        Header::parse(&header)
+    }
+
+    /// CRC32 of the IPL3 boot code, used to tell CICs apart.
+    pub fn ipl3_crc(&self) -> u32 {
+        crc32fast::hash(&self.data[BOOT_CODE_START..CHECKSUM_START])
+    }
+
+    pub fn cic(&self) -> Result<Cic, RomError> {
+        let crc = self.ipl3_crc();
+        Cic::from_ipl3_crc(crc).ok_or(RomError::UnknownCic(crc))
+    }
+
+    /// Computes CRC1 and CRC2 the way the boot code does. ROMs smaller than the checksummed
+    /// region are treated as padded with zeroes.
+    pub fn calculate_crc(&self) -> Result<(u32, u32), RomError> {
+        let cic = self.cic()?;
+        let seed = cic.checksum_seed();
+        let word = |offset: usize| match self.data.get(offset..offset + 4) {
+            Some(b) => u32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+            None => 0,
+        };
+
+        let (mut t1, mut t2, mut t3, mut t4, mut t5, mut t6) = (seed, seed, seed, seed, seed, seed);
+
+        for i in (CHECKSUM_START..CHECKSUM_START + CHECKSUM_LENGTH).step_by(4) {
+            let d = word(i);
+
+            if t6.wrapping_add(d) < t6 {
+                t4 = t4.wrapping_add(1);
+            }
+            t6 = t6.wrapping_add(d);
+            t3 ^= d;
+            let r = d.rotate_left(d & 0x1F);
+            t5 = t5.wrapping_add(r);
+            if t2 > d {
+                t2 ^= r;
+            } else {
+                t2 ^= t6 ^ d;
+            }
+
+            if cic == Cic::Cic6105 {
+                t1 = t1.wrapping_add(word(BOOT_CODE_START + 0x0710 + (i & 0xFF)) ^ d);
+            } else {
+                t1 = t1.wrapping_add(t5 ^ d);
+            }
+        }
+
+        Ok(match cic {
+            Cic::Cic6103 => ((t6 ^ t4).wrapping_add(t3), (t5 ^ t2).wrapping_add(t1)),
+            Cic::Cic6106 => (
+                t6.wrapping_mul(t4).wrapping_add(t3),
+                t5.wrapping_mul(t2).wrapping_add(t1),
+            ),
+            _ => (t6 ^ t4 ^ t3, t5 ^ t2 ^ t1),
+        })
+    }
+
+    /// Returns true if the CRCs in the header match the ROM's contents.
+    pub fn verify_crc(&self) -> Result<bool, RomError> {
+        let header = self.header();
+        Ok(self.calculate_crc()? == (header.crc1, header.crc2))
+    }
+
+    /// Recomputes the CRCs and writes them to the header. Returns true if they changed.
+    pub fn fix_crc(&mut self) -> Result<bool, RomError> {
+        let (crc1, crc2) = self.calculate_crc()?;
+        let header = self.header();
+
+        self.data[0x10..0x14].copy_from_slice(&crc1.to_be_bytes());
+        self.data[0x14..0x18].copy_from_slice(&crc2.to_be_bytes());
+
+        Ok((header.crc1, header.crc2) != (crc1, crc2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_rom() -> Vec<u8> {
+        std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/examples/m64p_test_rom.v64")).unwrap()
+    }
+
+    #[test]
+    fn byte_order() {
+        let v64 = test_rom();
+        let rom = Rom::from_bytes(v64.clone()).unwrap();
+        assert_eq!(rom.original_order(), ByteOrder::V64);
+        assert_eq!(ByteOrder::detect(rom.as_bytes()), Some(ByteOrder::Z64));
+        assert_eq!(rom.to_order(ByteOrder::V64), v64);
+
+        let n64 = rom.to_order(ByteOrder::N64);
+        assert_eq!(ByteOrder::detect(&n64), Some(ByteOrder::N64));
+        assert_eq!(Rom::from_bytes(n64).unwrap(), Rom { original_order: ByteOrder::N64, ..rom });
+    }
+
+    #[test]
+    fn header_and_crc() {
+        let mut rom = Rom::from_bytes(test_rom()).unwrap();
+        let header = rom.header();
+        assert_eq!(header.name, "Mupen64Plus");
+        assert_eq!(rom.cic().unwrap(), Cic::Cic6102);
+        assert!(rom.verify_crc().unwrap());
+
+        rom.as_bytes_mut()[0x2000] ^= 0xFF;
+        assert!(!rom.verify_crc().unwrap());
+        assert!(rom.fix_crc().unwrap());
+        assert!(rom.verify_crc().unwrap());
+    }
+
+    #[test]
+    fn archives() {
+        use std::io::Write;
+
+        let rom = Rom::from_bytes(test_rom()).unwrap();
+
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default();
+        zip.add_directory("roms/", options).unwrap();
+        zip.start_file("roms/readme.txt", options).unwrap();
+        zip.write_all(b"not a rom").unwrap();
+        zip.start_file("roms/test.v64", options).unwrap();
+        zip.write_all(&test_rom()).unwrap();
+        let zip = zip.finish().unwrap().into_inner();
+        assert_eq!(Rom::from_zip(&zip).unwrap(), rom);
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&test_rom()).unwrap();
+        assert_eq!(Rom::from_gzip(&gz.finish().unwrap()).unwrap(), rom);
+    }
+}
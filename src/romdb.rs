@@ -0,0 +1,296 @@
+//! Offline reading of the ROM database, `mupen64plus.ini`.
+//!
+//! This is the same database the core consults when a ROM is opened, so lookups here tell you
+//! which settings the core will pick for a game without having to load it.
+
+use mupen64plus_sys::*;
+use std::collections::HashMap;
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum RomDbError {
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("line {line}: {message}")]
+    Parse { line: usize, message: String },
+}
+
+/// The kind of save memory on the cartridge. The variants mirror `m64p_rom_save_type`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum SaveType {
+    Eeprom4k,
+    Eeprom16k,
+    Sram,
+    FlashRam,
+    ControllerPack,
+    None,
+}
+
+impl SaveType {
+    /// Parses the spelling used by `mupen64plus.ini`, e.g. `Eeprom 4KB`.
+    pub fn from_ini(s: &str) -> Option<SaveType> {
+        match s {
+            "Eeprom 4KB" => Some(SaveType::Eeprom4k),
+            "Eeprom 16KB" => Some(SaveType::Eeprom16k),
+            "SRAM" => Some(SaveType::Sram),
+            "Flash RAM" => Some(SaveType::FlashRam),
+            "Controller Pack" => Some(SaveType::ControllerPack),
+            "None" => Some(SaveType::None),
+            _ => None,
+        }
+    }
+}
+
+impl From<m64p_rom_save_type> for SaveType {
+    fn from(save_type: m64p_rom_save_type) -> Self {
+        #[allow(non_upper_case_globals)]
+        match save_type {
+            m64p_rom_save_type_SAVETYPE_EEPROM_4KB => SaveType::Eeprom4k,
+            m64p_rom_save_type_SAVETYPE_EEPROM_16KB => SaveType::Eeprom16k,
+            m64p_rom_save_type_SAVETYPE_SRAM => SaveType::Sram,
+            m64p_rom_save_type_SAVETYPE_FLASH_RAM => SaveType::FlashRam,
+            m64p_rom_save_type_SAVETYPE_CONTROLLER_PACK => SaveType::ControllerPack,
+            _ => SaveType::None,
+        }
+    }
+}
+
+impl From<SaveType> for m64p_rom_save_type {
+    fn from(save_type: SaveType) -> Self {
+        match save_type {
+            SaveType::Eeprom4k => m64p_rom_save_type_SAVETYPE_EEPROM_4KB,
+            SaveType::Eeprom16k => m64p_rom_save_type_SAVETYPE_EEPROM_16KB,
+            SaveType::Sram => m64p_rom_save_type_SAVETYPE_SRAM,
+            SaveType::FlashRam => m64p_rom_save_type_SAVETYPE_FLASH_RAM,
+            SaveType::ControllerPack => m64p_rom_save_type_SAVETYPE_CONTROLLER_PACK,
+            SaveType::None => m64p_rom_save_type_SAVETYPE_NONE,
+        }
+    }
+}
+
+/// A single game in the ROM database. Fields that are `None` weren't given by the entry (or the
+/// entry it references), so the core uses its defaults for them.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct RomDbEntry {
+    /// MD5 of the ROM in big-endian (z64) order, as uppercase hex.
+    pub md5: String,
+    pub good_name: String,
+    /// CRC1 and CRC2 from the ROM header.
+    pub crc: Option<(u32, u32)>,
+    /// The entry this one inherits its settings from.
+    pub ref_md5: Option<String>,
+    /// Emulation status, from 0 (doesn't work) to 5 (perfect).
+    pub status: Option<u8>,
+    pub save_type: Option<SaveType>,
+    pub players: Option<u8>,
+    pub rumble: Option<bool>,
+    pub transferpak: Option<bool>,
+    pub mempak: Option<bool>,
+    pub biopak: Option<bool>,
+    pub count_per_op: Option<u32>,
+    pub disable_extra_mem: Option<bool>,
+    pub si_dma_duration: Option<u32>,
+    pub ai_dma_modifier: Option<u32>,
+    /// Built-in fixes (`Cheat0`, `Cheat1`, ...), as comma-separated GameShark codes.
+    pub cheats: Vec<String>,
+}
+
+impl RomDbEntry {
+    /// Fills in any settings this entry doesn't have from `parent`.
+    fn inherit(&mut self, parent: &RomDbEntry) {
+        macro_rules! inherit {
+            ($($field:ident),*) => {
+                $(
+                    if self.$field.is_none() {
+                        self.$field = parent.$field;
+                    }
+                )*
+            };
+        }
+
+        inherit!(
+            status, save_type, players, rumble, transferpak, mempak, biopak,
+            count_per_op, disable_extra_mem, si_dma_duration, ai_dma_modifier
+        );
+
+        if self.cheats.is_empty() {
+            self.cheats = parent.cheats.clone();
+        }
+    }
+}
+
+/// The parsed ROM database, indexed by MD5 and by header CRC.
+#[derive(Debug, Clone, Default)]
+pub struct RomDb {
+    entries: Vec<RomDbEntry>,
+    by_md5: HashMap<String, usize>,
+    by_crc: HashMap<(u32, u32), Vec<usize>>,
+}
+
+impl RomDb {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, RomDbError> {
+        let text = std::fs::read(path)?;
+        Self::parse(&String::from_utf8_lossy(&text))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, RomDbError> {
+        let mut entries: Vec<RomDbEntry> = Vec::new();
+
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            let err = |message: String| RomDbError::Parse { line: idx + 1, message };
+
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            if let Some(rest) = line.strip_prefix('[') {
+                let md5 = rest
+                    .strip_suffix(']')
+                    .ok_or_else(|| err("malformed section header".into()))?;
+                entries.push(RomDbEntry {
+                    md5: md5.trim().to_ascii_uppercase(),
+                    ..Default::default()
+                });
+                continue;
+            }
+
+            let entry = entries
+                .last_mut()
+                .ok_or_else(|| err("key outside of an entry".into()))?;
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| err("expected `key=value`".into()))?;
+            let value = value.trim();
+
+            let yes_no = || match value {
+                "Yes" => Ok(true),
+                "No" => Ok(false),
+                _ => Err(err(format!("invalid {} {:?}", key, value))),
+            };
+            let number = || value.parse().map_err(|_| err(format!("invalid {} {:?}", key, value)));
+
+            match key.trim() {
+                "GoodName" => entry.good_name = value.to_owned(),
+                "CRC" => {
+                    let crc = value
+                        .split_once(' ')
+                        .and_then(|(a, b)| {
+                            Some((u32::from_str_radix(a, 16).ok()?, u32::from_str_radix(b.trim(), 16).ok()?))
+                        })
+                        .ok_or_else(|| err(format!("invalid CRC {:?}", value)))?;
+                    entry.crc = Some(crc);
+                }
+                "RefMD5" => entry.ref_md5 = Some(value.to_ascii_uppercase()),
+                "Status" => entry.status = Some(number()? as u8),
+                "SaveType" => {
+                    let save_type = SaveType::from_ini(value)
+                        .ok_or_else(|| err(format!("invalid SaveType {:?}", value)))?;
+                    entry.save_type = Some(save_type);
+                }
+                "Players" => entry.players = Some(number()? as u8),
+                "Rumble" => entry.rumble = Some(yes_no()?),
+                "Transferpak" => entry.transferpak = Some(yes_no()?),
+                "Mempak" => entry.mempak = Some(yes_no()?),
+                "Biopak" => entry.biopak = Some(yes_no()?),
+                "CountPerOp" => entry.count_per_op = Some(number()?),
+                "DisableExtraMem" => entry.disable_extra_mem = Some(number()? != 0),
+                "SiDmaDuration" => entry.si_dma_duration = Some(number()?),
+                "AiDmaModifier" => entry.ai_dma_modifier = Some(number()?),
+                key if key.starts_with("Cheat") => entry.cheats.push(value.to_owned()),
+                key => log::debug!("romdb: ignoring unknown key {:?} on line {}", key, idx + 1),
+            }
+        }
+
+        let mut db = RomDb {
+            by_md5: entries.iter().enumerate().map(|(i, e)| (e.md5.clone(), i)).collect(),
+            entries,
+            by_crc: HashMap::new(),
+        };
+
+        for i in 0..db.entries.len() {
+            let resolved = db.resolve(i);
+            db.entries[i] = resolved;
+
+            if let Some(crc) = db.entries[i].crc {
+                db.by_crc.entry(crc).or_default().push(i);
+            }
+        }
+
+        Ok(db)
+    }
+
+    /// Returns a copy of the entry at `idx` with its `RefMD5` chain applied.
+    fn resolve(&self, idx: usize) -> RomDbEntry {
+        let mut entry = self.entries[idx].clone();
+        let mut parent = entry.ref_md5.clone();
+
+        // Bounded so that a reference cycle can't loop forever
+        for _ in 0..self.entries.len() {
+            match parent.as_ref().and_then(|md5| self.by_md5.get(md5)) {
+                Some(&p) => {
+                    entry.inherit(&self.entries[p]);
+                    parent = self.entries[p].ref_md5.clone();
+                }
+                None => break,
+            }
+        }
+
+        entry
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &RomDbEntry> {
+        self.entries.iter()
+    }
+
+    /// Looks up a ROM by the hex MD5 of its z64 image. Case-insensitive.
+    pub fn get_by_md5(&self, md5: &str) -> Option<&RomDbEntry> {
+        self.by_md5
+            .get(&md5.to_ascii_uppercase())
+            .map(|&i| &self.entries[i])
+    }
+
+    /// Looks up ROMs by header CRC. Several dumps of a game (e.g. hacks) may share a CRC.
+    pub fn get_by_crc(&self, crc1: u32, crc2: u32) -> impl Iterator<Item = &RomDbEntry> {
+        self.by_crc
+            .get(&(crc1, crc2))
+            .into_iter()
+            .flatten()
+            .map(move |&i| &self.entries[i])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shipped_database() {
+        let db = RomDb::load(concat!(env!("CARGO_MANIFEST_DIR"), "/libs/mupen64plus.ini")).unwrap();
+        assert!(db.len() > 3000);
+
+        let parent = db.get_by_md5("9D58996A8AA91263B5CD45C385F45FE4").unwrap();
+        assert_eq!(parent.good_name, "007 - The World is Not Enough (U) [!]");
+        assert_eq!(parent.save_type, Some(SaveType::None));
+        assert_eq!(parent.players, Some(4));
+
+        // [t1] inherits everything but its name and CRC from the parent
+        let child = db.get_by_md5("0846fffda3081821ea0dcbb7d4deaaa3").unwrap();
+        assert_eq!(child.good_name, "007 - The World is Not Enough (U) [t1]");
+        assert_eq!(child.crc, Some((0x5B6AC01B, 0x8D1A562A)));
+        assert_eq!(child.mempak, Some(true));
+        assert_eq!(child.rumble, Some(true));
+        assert_eq!(child.players, Some(4));
+
+        assert_eq!(db.get_by_crc(0x5B6AC01B, 0x8D1A562A).count(), 2);
+    }
+}
@@ -0,0 +1,249 @@
+//! Game saves in the formats used by mupen64plus and other emulators.
+//!
+//! Mupen64Plus keeps one file per kind of save memory (`.eep`, `.sra`, `.fla`, `.mpk`); see
+//! [`Mupen::read_saves`](crate::core::Mupen::read_saves) for finding them. [`SaveData`] holds all
+//! of a game's saves and converts them to and from:
+//!
+//! - Project64, which also uses one file per kind, but stores SRAM and FlashRAM as little-endian
+//!   32-bit words and keeps one 32KB Controller Pak per file.
+//! - RetroArch's `.srm`, which puts every kind in one file.
+
+use std::path::Path;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SaveError {
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{kind:?} save of {size:#X} bytes is the wrong size")]
+    BadSize { kind: SaveKind, size: usize },
+    #[error(".srm of {0:#X} bytes is the wrong size")]
+    BadSrmSize(usize),
+}
+
+/// A kind of save memory, each of which is kept in its own file by mupen64plus.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum SaveKind {
+    /// `.eep`: 4Kbit or 16Kbit EEPROM, always saved as 16Kbit.
+    Eeprom,
+    /// `.sra`: 256Kbit SRAM.
+    Sram,
+    /// `.fla`: 1Mbit FlashRAM.
+    FlashRam,
+    /// `.mpk`: the Controller Paks of all four controllers.
+    ControllerPak,
+}
+
+/// Size of a single Controller Pak.
+pub const CONTROLLER_PAK_SIZE: usize = 0x8000;
+
+impl SaveKind {
+    pub const ALL: [SaveKind; 4] = [SaveKind::Eeprom, SaveKind::Sram, SaveKind::FlashRam, SaveKind::ControllerPak];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            SaveKind::Eeprom => "eep",
+            SaveKind::Sram => "sra",
+            SaveKind::FlashRam => "fla",
+            SaveKind::ControllerPak => "mpk",
+        }
+    }
+
+    pub fn from_extension(ext: &str) -> Option<SaveKind> {
+        SaveKind::ALL.iter().copied().find(|k| k.extension().eq_ignore_ascii_case(ext))
+    }
+
+    /// Size of the mupen64plus save file.
+    pub fn size(self) -> usize {
+        match self {
+            SaveKind::Eeprom => 0x800,
+            SaveKind::Sram => 0x8000,
+            SaveKind::FlashRam => 0x2_0000,
+            SaveKind::ControllerPak => 4 * CONTROLLER_PAK_SIZE,
+        }
+    }
+
+    /// The value of erased memory, used to pad short saves.
+    fn fill(self) -> u8 {
+        match self {
+            SaveKind::Eeprom | SaveKind::FlashRam => 0xFF,
+            SaveKind::Sram | SaveKind::ControllerPak => 0x00,
+        }
+    }
+
+    /// Offset of this kind in a RetroArch `.srm`.
+    fn srm_offset(self) -> usize {
+        match self {
+            SaveKind::Eeprom => 0,
+            SaveKind::ControllerPak => 0x800,
+            SaveKind::Sram => 0x2_0800,
+            SaveKind::FlashRam => 0x2_8800,
+        }
+    }
+}
+
+/// Size of a RetroArch `.srm`.
+pub const SRM_SIZE: usize = 0x4_8800;
+
+/// All of a game's saves, in mupen64plus format. Kinds the game doesn't have are `None`.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct SaveData {
+    pub eeprom: Option<Vec<u8>>,
+    pub sram: Option<Vec<u8>>,
+    pub flashram: Option<Vec<u8>>,
+    pub controller_pak: Option<Vec<u8>>,
+}
+
+impl SaveData {
+    pub fn get(&self, kind: SaveKind) -> Option<&[u8]> {
+        match kind {
+            SaveKind::Eeprom => self.eeprom.as_deref(),
+            SaveKind::Sram => self.sram.as_deref(),
+            SaveKind::FlashRam => self.flashram.as_deref(),
+            SaveKind::ControllerPak => self.controller_pak.as_deref(),
+        }
+    }
+
+    fn slot(&mut self, kind: SaveKind) -> &mut Option<Vec<u8>> {
+        match kind {
+            SaveKind::Eeprom => &mut self.eeprom,
+            SaveKind::Sram => &mut self.sram,
+            SaveKind::FlashRam => &mut self.flashram,
+            SaveKind::ControllerPak => &mut self.controller_pak,
+        }
+    }
+
+    /// Sets a save in mupen64plus format. Short saves, such as a 4Kbit EEPROM or a single
+    /// Controller Pak, are padded to the size of the mupen64plus file.
+    pub fn set(&mut self, kind: SaveKind, mut data: Vec<u8>) -> Result<(), SaveError> {
+        if data.len() > kind.size() || data.is_empty() {
+            return Err(SaveError::BadSize { kind, size: data.len() });
+        }
+
+        data.resize(kind.size(), kind.fill());
+        *self.slot(kind) = Some(data);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, kind: SaveKind) -> Option<Vec<u8>> {
+        self.slot(kind).take()
+    }
+
+    /// Reads the save files in `dir` named `name.eep`, `name.sra` and so on, in mupen64plus format.
+    /// Missing files are left as `None`.
+    pub fn load<P: AsRef<Path>>(dir: P, name: &str) -> Result<SaveData, SaveError> {
+        let mut saves = SaveData::default();
+
+        for &kind in SaveKind::ALL.iter() {
+            let path = dir.as_ref().join(format!("{}.{}", name, kind.extension()));
+            match std::fs::read(&path) {
+                Ok(data) => saves.set(kind, data)?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(saves)
+    }
+
+    /// Writes every save that is present to `dir`, named like `load` expects.
+    pub fn save<P: AsRef<Path>>(&self, dir: P, name: &str) -> Result<(), SaveError> {
+        std::fs::create_dir_all(dir.as_ref())?;
+
+        for &kind in SaveKind::ALL.iter() {
+            if let Some(data) = self.get(kind) {
+                std::fs::write(dir.as_ref().join(format!("{}.{}", name, kind.extension())), data)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a RetroArch `.srm`. Kinds whose area is entirely erased are left as `None`.
+    pub fn from_srm(srm: &[u8]) -> Result<SaveData, SaveError> {
+        if srm.len() != SRM_SIZE {
+            return Err(SaveError::BadSrmSize(srm.len()));
+        }
+
+        let mut saves = SaveData::default();
+        for &kind in SaveKind::ALL.iter() {
+            let data = &srm[kind.srm_offset()..kind.srm_offset() + kind.size()];
+            if data.iter().any(|&b| b != kind.fill()) {
+                saves.set(kind, data.to_vec())?;
+            }
+        }
+
+        Ok(saves)
+    }
+
+    /// Combines the saves into a RetroArch `.srm`, leaving missing kinds erased. Saves not set
+    /// with `set` are padded or truncated to their area.
+    pub fn to_srm(&self) -> Vec<u8> {
+        let mut srm = vec![0; SRM_SIZE];
+
+        for &kind in SaveKind::ALL.iter() {
+            let area = &mut srm[kind.srm_offset()..kind.srm_offset() + kind.size()];
+            let data = self.get(kind).unwrap_or_default();
+            let len = data.len().min(kind.size());
+            area[..len].copy_from_slice(&data[..len]);
+            area[len..].iter_mut().for_each(|b| *b = kind.fill());
+        }
+
+        srm
+    }
+
+    /// Sets a save from a Project64 file. Project64 keeps one Controller Pak per file, which is
+    /// used as controller 1's.
+    pub fn set_pj64(&mut self, kind: SaveKind, mut data: Vec<u8>) -> Result<(), SaveError> {
+        swap_pj64(kind, &mut data);
+        self.set(kind, data)
+    }
+
+    /// A save in Project64 format. For Controller Paks, this is controller 1's.
+    pub fn to_pj64(&self, kind: SaveKind) -> Option<Vec<u8>> {
+        let mut data = self.get(kind)?.to_vec();
+        if kind == SaveKind::ControllerPak {
+            data.truncate(CONTROLLER_PAK_SIZE);
+        }
+        swap_pj64(kind, &mut data);
+        Some(data)
+    }
+}
+
+/// Converts between Project64 and mupen64plus byte order, which differ for SRAM and FlashRAM.
+/// The conversion is its own inverse.
+pub fn swap_pj64(kind: SaveKind, data: &mut [u8]) {
+    if let SaveKind::Sram | SaveKind::FlashRam = kind {
+        data.chunks_exact_mut(4).for_each(|w| w.reverse());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        let mut saves = SaveData::default();
+        saves.set_pj64(SaveKind::Sram, vec![1, 2, 3, 4]).unwrap();
+        saves.set(SaveKind::Eeprom, vec![0xAB; 0x200]).unwrap();
+        assert!(saves.set(SaveKind::Eeprom, vec![0; 0x801]).is_err());
+
+        let sram = saves.get(SaveKind::Sram).unwrap();
+        assert_eq!(sram.len(), 0x8000);
+        assert_eq!(&sram[..4], &[4, 3, 2, 1]);
+        assert_eq!(&saves.to_pj64(SaveKind::Sram).unwrap()[..4], &[1, 2, 3, 4]);
+        assert_eq!(saves.get(SaveKind::Eeprom).unwrap()[0x200], 0xFF);
+
+        let srm = saves.to_srm();
+        assert_eq!(srm.len(), SRM_SIZE);
+        assert_eq!(srm[0x2_0800..0x2_0804], [4, 3, 2, 1]);
+        assert_eq!(SaveData::from_srm(&srm).unwrap(), saves);
+
+        // Assigned directly, without set's padding
+        saves.eeprom = Some(vec![0xAB; 0x200]);
+        let srm = saves.to_srm();
+        assert_eq!(srm[0x1FF..0x201], [0xAB, 0xFF]);
+        assert_eq!(SaveData::from_srm(&srm).unwrap().get(SaveKind::Eeprom), Some(&srm[..0x800]));
+    }
+}